@@ -0,0 +1,398 @@
+//! Checkpoints of long runs and recovery of the outputs of interrupted runs
+//!
+//! The checkpoint lists each repeat handed to the output writers along with the
+//! number of spanning reads written for each sample. Writers buffer their
+//! output, so an interrupted run may have lost the most recent records. A
+//! repeat is therefore only considered complete if its VCF record and all of
+//! its spanning reads can be read back from the partial outputs.
+
+use crate::locus::Locus;
+use crate::workflows::CLIP_RADIUS;
+use crate::writers::{BamWriter, VcfWriter};
+use itertools::Itertools;
+use rust_htslib::bam::{self, record::Aux, Read as BamRead};
+use rust_htslib::bcf::{self, Read as BcfRead};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, String>;
+
+const PARTIAL_SUFFIX: &str = "partial";
+
+pub struct CheckpointWriter {
+    writer: BufWriter<File>,
+}
+
+impl CheckpointWriter {
+    pub fn new(path: &str) -> Result<Self> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(CheckpointWriter {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Entries are flushed right away so that they survive the run being killed
+    pub fn add(&mut self, trid: &str, read_counts: &[usize]) -> Result<()> {
+        writeln!(self.writer, "{}\t{}", trid, read_counts.iter().join(","))
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Reads the repeats listed in a checkpoint; an unterminated last line is
+/// ignored as it may have been cut short
+fn read_checkpoint(path: &str) -> Result<HashMap<String, Vec<usize>>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut entries = HashMap::new();
+    for line in content.split_inclusive('\n') {
+        let Some(line) = line.strip_suffix('\n') else {
+            continue;
+        };
+        let error = || format!("Invalid checkpoint line: {}", line);
+        let (trid, counts) = line.split_once('\t').ok_or_else(error)?;
+        let counts = counts
+            .split(',')
+            .map(|count| count.parse::<usize>().map_err(|_| error()))
+            .collect::<Result<Vec<_>>>()?;
+        entries.insert(trid.to_string(), counts);
+    }
+    Ok(entries)
+}
+
+fn get_trid(record: &bcf::Record) -> Option<String> {
+    let trid = record.info(b"TRID").string().ok()??;
+    Some(String::from_utf8_lossy(trid[0]).to_string())
+}
+
+fn get_read_trid(record: &bam::Record) -> Option<String> {
+    match record.aux(b"TR") {
+        Ok(Aux::String(trid)) => Some(trid.to_string()),
+        _ => None,
+    }
+}
+
+/// Collects repeat IDs of all VCF records up to the point of truncation
+fn scan_vcf(path: &str) -> Option<HashSet<String>> {
+    let mut reader = bcf::Reader::from_path(path).ok()?;
+    let mut trids = HashSet::new();
+    let mut record = reader.empty_record();
+    while let Some(Ok(())) = reader.read(&mut record) {
+        if let Some(trid) = get_trid(&record) {
+            trids.insert(trid);
+        }
+    }
+    Some(trids)
+}
+
+/// Counts reads of each repeat up to the point of truncation
+fn scan_bam(path: &str) -> Option<HashMap<String, usize>> {
+    let mut reader = bam::Reader::from_path(path).ok()?;
+    let mut counts = HashMap::new();
+    let mut record = bam::Record::new();
+    while let Some(Ok(())) = reader.read(&mut record) {
+        if let Some(trid) = get_read_trid(&record) {
+            *counts.entry(trid).or_insert(0) += 1;
+        }
+    }
+    Some(counts)
+}
+
+/// Reads of a partial BAM file that are consumed in coordinate order
+struct PartialBam {
+    reader: bam::Reader,
+    next: Option<bam::Record>,
+}
+
+impl PartialBam {
+    fn new(path: &str) -> Result<Self> {
+        let reader = bam::Reader::from_path(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut bam = PartialBam { reader, next: None };
+        bam.advance();
+        Ok(bam)
+    }
+
+    fn advance(&mut self) {
+        let mut record = bam::Record::new();
+        self.next = match self.reader.read(&mut record) {
+            Some(Ok(())) => Some(record),
+            _ => None,
+        };
+    }
+}
+
+/// Outputs of an interrupted run, which are copied to the new outputs for the
+/// repeats that were completed
+pub struct Recovery {
+    completed: HashMap<String, Vec<usize>>,
+    vcf: bcf::Reader,
+    bams: Vec<PartialBam>,
+    partial_paths: Vec<String>,
+}
+
+impl Recovery {
+    /// Moves the outputs of an interrupted run aside; returns None if the run
+    /// left no checkpoint or no completed repeats behind
+    pub fn new(
+        checkpoint_path: &str,
+        vcf_path: &str,
+        bam_paths: &[String],
+    ) -> Result<Option<Self>> {
+        let output_paths = [vcf_path.to_string()]
+            .into_iter()
+            .chain(bam_paths.iter().cloned())
+            .collect_vec();
+        let missing = [checkpoint_path]
+            .into_iter()
+            .chain(output_paths.iter().map(|p| p.as_str()))
+            .find(|path| !Path::new(path).exists());
+        if let Some(path) = missing {
+            log::warn!("Nothing to resume: {} does not exist", path);
+            return Ok(None);
+        }
+
+        let checkpoint = read_checkpoint(checkpoint_path)?;
+        let mut partial_paths = Vec::new();
+        for path in &output_paths {
+            let partial_path = format!("{}.{}", path, PARTIAL_SUFFIX);
+            fs::rename(path, &partial_path).map_err(|e| format!("{}: {}", path, e))?;
+            partial_paths.push(partial_path);
+        }
+
+        // Outputs killed before their headers were written cannot be opened
+        let recorded_trids = scan_vcf(&partial_paths[0]);
+        let read_counts = partial_paths[1..]
+            .iter()
+            .map(|path| scan_bam(path))
+            .collect::<Option<Vec<_>>>();
+        let completed: HashMap<String, Vec<usize>> = match (recorded_trids, read_counts) {
+            (Some(recorded_trids), Some(read_counts)) => checkpoint
+                .into_iter()
+                .filter(|(trid, counts)| {
+                    recorded_trids.contains(trid)
+                        && counts.len() == read_counts.len()
+                        && counts
+                            .iter()
+                            .zip(&read_counts)
+                            .all(|(count, found)| found.get(trid).unwrap_or(&0) == count)
+                })
+                .collect(),
+            _ => HashMap::new(),
+        };
+
+        if completed.is_empty() {
+            log::warn!("No completed repeats to resume from; starting over");
+            for path in &partial_paths {
+                fs::remove_file(path).map_err(|e| format!("{}: {}", path, e))?;
+            }
+            return Ok(None);
+        }
+        log::info!("Resuming with {} completed repeats", completed.len());
+
+        let vcf = bcf::Reader::from_path(&partial_paths[0]).map_err(|e| e.to_string())?;
+        let bams = partial_paths[1..]
+            .iter()
+            .map(|path| PartialBam::new(path))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Some(Recovery {
+            completed,
+            vcf,
+            bams,
+            partial_paths,
+        }))
+    }
+
+    pub fn completed(&self) -> HashSet<String> {
+        self.completed.keys().cloned().collect()
+    }
+
+    /// Returns the read counts of a completed repeat
+    pub fn get_read_counts(&self, trid: &str) -> Option<&Vec<usize>> {
+        self.completed.get(trid)
+    }
+
+    /// Passes reads of completed repeats that may precede the reads of the
+    /// given locus to the BAM writers
+    pub fn restore_reads(&mut self, locus: &Locus, bam_writers: &mut [BamWriter]) {
+        for (bam, bam_writer) in self.bams.iter_mut().zip(bam_writers.iter_mut()) {
+            let tid = bam.reader.header().tid(locus.region.contig.as_bytes());
+            let boundary = (tid.map_or(-1, |t| t as i32), locus.region.start as i64);
+            while let Some(record) = bam.next.take() {
+                if (record.tid(), record.pos() + CLIP_RADIUS as i64) >= boundary {
+                    bam.next = Some(record);
+                    break;
+                }
+                Self::restore_read(&self.completed, record, bam_writer);
+                bam.advance();
+            }
+        }
+    }
+
+    fn restore_read(
+        completed: &HashMap<String, Vec<usize>>,
+        record: bam::Record,
+        bam_writer: &mut BamWriter,
+    ) {
+        if get_read_trid(&record).is_some_and(|trid| completed.contains_key(&trid)) {
+            bam_writer.insert(record);
+        }
+    }
+
+    /// Copies the VCF record of a completed repeat
+    pub fn restore_record(&mut self, trid: &str, vcf_writer: &mut VcfWriter) -> Result<()> {
+        let mut record = self.vcf.empty_record();
+        while let Some(Ok(())) = self.vcf.read(&mut record) {
+            if get_trid(&record).as_deref() == Some(trid) {
+                return vcf_writer.write_record(&mut record);
+            }
+        }
+        Err(format!(
+            "Record of {} not found in the interrupted output; rerun without --resume",
+            trid
+        ))
+    }
+
+    /// Passes all remaining reads of completed repeats to the BAM writers
+    pub fn finish(mut self, bam_writers: &mut [BamWriter]) -> Vec<String> {
+        for (bam, bam_writer) in self.bams.iter_mut().zip(bam_writers.iter_mut()) {
+            while let Some(record) = bam.next.take() {
+                Self::restore_read(&self.completed, record, bam_writer);
+                bam.advance();
+            }
+        }
+        self.partial_paths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_htslib::bam::header::HeaderRecord;
+
+    const VCF_HEADER: &str = "##fileformat=VCFv4.2
+##INFO=<ID=TRID,Number=1,Type=String,Description=\"Tandem repeat ID\">
+##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">
+##contig=<ID=chr1,length=100000>
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tsample
+";
+
+    const SAM_HEADER: &str = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:100000\n";
+
+    fn make_vcf_line(pos: usize, trid: &str) -> String {
+        format!("chr1\t{}\t.\tCAG\t.\t.\t.\tTRID={}\tGT\t0/0\n", pos, trid)
+    }
+
+    fn make_sam_line(name: &str, pos: usize, trid: &str) -> String {
+        format!(
+            "{}\t0\tchr1\t{}\t60\t4M\t*\t0\t0\tACGT\t*\tTR:Z:{}\n",
+            name, pos, trid
+        )
+    }
+
+    /// Writes the outputs of a run that was killed while writing TR2 and TR3;
+    /// uncompressed outputs are cut short in the middle of a line
+    fn make_interrupted_run(dir: &Path, checkpoint: &str) -> (String, String, String) {
+        fs::create_dir_all(dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let vcf = [
+            VCF_HEADER.to_string(),
+            make_vcf_line(1001, "TR1"),
+            make_vcf_line(2001, "TR2"),
+            "chr1\t3001\t.\tCA".to_string(),
+        ];
+        let sam = [
+            SAM_HEADER.to_string(),
+            make_sam_line("read1", 1001, "TR1"),
+            make_sam_line("read2", 1002, "TR1"),
+            make_sam_line("read3", 2001, "TR2"),
+            "read4\t0\tchr1\t20".to_string(),
+        ];
+        let (checkpoint_path, vcf_path, bam_path) =
+            (path("checkpoint"), path("vcf.gz"), path("bam"));
+        fs::write(&checkpoint_path, checkpoint).unwrap();
+        fs::write(&vcf_path, vcf.concat()).unwrap();
+        fs::write(&bam_path, sam.concat()).unwrap();
+        (checkpoint_path, vcf_path, bam_path)
+    }
+
+    fn make_bam_header() -> bam::Header {
+        let mut header = bam::Header::new();
+        let mut record = HeaderRecord::new(b"SQ");
+        record.push_tag(b"SN", "chr1");
+        record.push_tag(b"LN", 100000);
+        header.push_record(&record);
+        header
+    }
+
+    #[test]
+    fn resume_from_truncated_outputs() {
+        let dir = std::env::temp_dir().join(format!("trgt-{}-resume", std::process::id()));
+        let checkpoint = "TR1\t2\nTR2\t2\nTR3\t1\n";
+        let (checkpoint_path, vcf_path, bam_path) = make_interrupted_run(&dir, checkpoint);
+
+        let recovery =
+            Recovery::new(&checkpoint_path, &vcf_path, std::slice::from_ref(&bam_path)).unwrap();
+        let mut recovery = recovery.unwrap();
+        assert!(!Path::new(&vcf_path).exists());
+        assert!(Path::new(&format!("{}.partial", bam_path)).exists());
+
+        // TR2 lost one of its reads and TR3 lost its record
+        assert_eq!(recovery.completed(), HashSet::from(["TR1".to_string()]));
+        assert_eq!(recovery.get_read_counts("TR1"), Some(&vec![2]));
+        assert_eq!(recovery.get_read_counts("TR2"), None);
+
+        let header = make_bam_header();
+        let mut vcf_writer = VcfWriter::new(&vcf_path, &["sample"], &["XX"], &header).unwrap();
+        recovery.restore_record("TR1", &mut vcf_writer).unwrap();
+        assert!(recovery.restore_record("TR3", &mut vcf_writer).is_err());
+        drop(vcf_writer);
+        let mut bam_writers = vec![BamWriter::new(&bam_path, header, 50).unwrap()];
+        let partial_paths = recovery.finish(&mut bam_writers);
+        bam_writers.pop().unwrap().finish();
+
+        assert_eq!(
+            scan_vcf(&vcf_path),
+            Some(HashSet::from(["TR1".to_string()]))
+        );
+        assert_eq!(
+            scan_bam(&bam_path),
+            Some(HashMap::from([("TR1".to_string(), 2)]))
+        );
+        assert_eq!(partial_paths.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reject_checkpoint_with_mismatched_read_counts() {
+        let dir = std::env::temp_dir().join(format!("trgt-{}-mismatch", std::process::id()));
+        let checkpoint = "TR1\t3\nTR2\t2\n";
+        let (checkpoint_path, vcf_path, bam_path) = make_interrupted_run(&dir, checkpoint);
+
+        let recovery =
+            Recovery::new(&checkpoint_path, &vcf_path, std::slice::from_ref(&bam_path)).unwrap();
+        assert!(recovery.is_none());
+        assert!(!Path::new(&format!("{}.partial", vcf_path)).exists());
+        assert!(!Path::new(&format!("{}.partial", bam_path)).exists());
+
+        // Checkpoints listing a different number of samples are rejected too
+        let checkpoint = "TR1\t2,0\n";
+        let (checkpoint_path, vcf_path, bam_path) = make_interrupted_run(&dir, checkpoint);
+        let recovery = Recovery::new(&checkpoint_path, &vcf_path, &[bam_path]).unwrap();
+        assert!(recovery.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_checkpoint_ignores_unterminated_line() {
+        let path = std::env::temp_dir().join(format!("trgt-{}.checkpoint", std::process::id()));
+        fs::write(&path, "TR1\t3,0\nTR2\t12\nTR3\t1").unwrap();
+        let entries = read_checkpoint(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["TR1"], vec![3, 0]);
+        assert_eq!(entries["TR2"], vec![12]);
+    }
+}
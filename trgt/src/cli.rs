@@ -1,7 +1,8 @@
 use crate::locate::TrgtScoring;
 use crate::locus::Genotyper;
+use crate::preset::Preset;
 use chrono::Datelike;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use env_logger::fmt::Color;
 use lazy_static::lazy_static;
 use log::{Level, LevelFilter};
@@ -28,7 +29,29 @@ lazy_static! {
           help_template = "{name} {version}\n{author}{about-section}\n{usage-heading}\n    {usage}\n\n{all-args}{after-help}",
           )]
 #[command(arg_required_else_help(true))]
-pub struct CliParams {
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+
+    #[clap(short = 'v')]
+    #[clap(long = "verbose")]
+    #[clap(action = clap::ArgAction::Count)]
+    #[clap(global = true)]
+    pub verbosity: u8,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    #[clap(about = "Genotype tandem repeats from aligned reads")]
+    Genotype(Box<GenotypeArgs>),
+
+    #[clap(about = "Merge VCF files generated by TRGT")]
+    Merge(MergeArgs),
+}
+
+#[derive(Parser)]
+#[command(arg_required_else_help(true))]
+pub struct GenotypeArgs {
     #[clap(required = true)]
     #[clap(long = "genome")]
     #[clap(help = "Path to reference genome FASTA")]
@@ -38,10 +61,11 @@ pub struct CliParams {
 
     #[clap(required = true)]
     #[clap(long = "reads")]
-    #[clap(help = "BAM file with aligned HiFi reads")]
+    #[clap(help = "BAM or CRAM file(s) with aligned HiFi reads (BAM or FASTQ with --unaligned)")]
     #[clap(value_name = "READS")]
+    #[clap(num_args = 1..)]
     #[arg(value_parser = check_file_exists)]
-    pub reads_path: PathBuf,
+    pub reads_paths: Vec<PathBuf>,
 
     #[clap(required = true)]
     #[clap(long = "repeats")]
@@ -66,13 +90,15 @@ pub struct CliParams {
 
     #[clap(long = "karyotype")]
     #[clap(value_name = "KARYOTYPE")]
-    #[clap(help = "Sample karyotype (XX or XY or file name)")]
+    #[clap(
+        help = "Sample karyotype (XX, XY, auto, optionally suffixed with :hg38 or :hg19, or file name)"
+    )]
     #[clap(default_value = "XX")]
     pub karyotype: String,
 
     #[clap(long = "sample-name")]
     #[clap(value_name = "SAMPLE_NAME")]
-    #[clap(help = "Sample name")]
+    #[clap(help = "Sample name (single-sample runs only)")]
     #[clap(default_value = None)]
     #[arg(value_parser = check_sample_name_nonempty)]
     pub sample_name: Option<String>,
@@ -83,6 +109,65 @@ pub struct CliParams {
     #[clap(default_value = "250")]
     pub max_depth: usize,
 
+    #[clap(long = "region")]
+    #[clap(value_name = "REGION")]
+    #[clap(
+        help = "Only analyze repeats overlapping a region (chr, chr:start-end, or BED file); can be repeated"
+    )]
+    pub regions: Vec<String>,
+
+    #[clap(long = "loci-ids")]
+    #[clap(value_name = "IDS")]
+    #[clap(help = "Only analyze repeats with these comma-separated IDs")]
+    #[clap(value_delimiter = ',')]
+    pub loci_ids: Vec<String>,
+
+    #[clap(long = "loci-file")]
+    #[clap(value_name = "LOCI_FILE")]
+    #[clap(help = "Only analyze repeats with IDs listed in this file (one per line)")]
+    #[arg(value_parser = check_file_exists)]
+    pub loci_path: Option<PathBuf>,
+
+    #[clap(long = "checkpoint")]
+    #[clap(help = "Record completed repeats so that an interrupted run can be resumed")]
+    pub checkpoint: bool,
+
+    #[clap(long = "resume")]
+    #[clap(help = "Resume an interrupted run, skipping repeats that were completed")]
+    pub resume: bool,
+
+    #[clap(long = "index")]
+    #[clap(help = "Index the sorted VCF and BAM outputs")]
+    pub index: bool,
+
+    #[clap(long = "annotate-reads")]
+    #[clap(help = "Label motifs in the repeat sequence of each spanning read in the BAM output")]
+    pub annotate_reads: bool,
+
+    #[clap(long = "read-table")]
+    #[clap(help = "Write a table describing each spanning read")]
+    #[clap(conflicts_with = "resume")]
+    pub read_table: bool,
+
+    #[clap(long = "cpg-profiles")]
+    #[clap(help = "Report methylation of each CpG in the alleles and their flanks")]
+    pub cpg_profiles: bool,
+
+    #[clap(long = "stitch-supplementary")]
+    #[clap(help = "Join primary and supplementary alignments of reads spanning large expansions")]
+    pub stitch_supplementary: bool,
+
+    #[clap(long = "unaligned")]
+    #[clap(help = "Recruit unaligned reads (BAM or FASTQ) to repeats by flank k-mers")]
+    #[clap(conflicts_with_all = ["stitch_supplementary", "resume", "checkpoint"])]
+    pub unaligned: bool,
+
+    #[clap(long = "preset")]
+    #[clap(value_name = "PRESET")]
+    #[clap(help = "Sequencing platform that sets defaults of advanced options (hifi or ont)")]
+    #[clap(default_value = "hifi")]
+    pub preset: Preset,
+
     #[clap(help_heading("Advanced"))]
     #[clap(long = "genotyper")]
     #[clap(value_name = "GENOTYPER")]
@@ -94,18 +179,18 @@ pub struct CliParams {
     #[clap(long = "aln-scoring")]
     #[clap(value_name = "SCORING")]
     #[clap(
-        help = "Scoring function to align to flanks (non-negative values): MATCH,MISM,GAPO,GAPE,KMERLEN,BANDWIDTH"
+        help = "Scoring function to align to flanks (non-negative values): MATCH,MISM,GAPO,GAPE,KMERLEN,BANDWIDTH [default: 1,1,5,1,8,6 for hifi, 1,1,4,1,7,20 for ont]"
     )]
-    #[clap(default_value = "1,1,5,1,8,6")]
     #[arg(value_parser = scoring_from_string)]
-    pub aln_scoring: TrgtScoring,
+    pub aln_scoring: Option<TrgtScoring>,
 
     #[clap(help_heading("Advanced"))]
     #[clap(long = "min-flank-id-frac")]
     #[clap(value_name = "PERC")]
-    #[clap(help = "Minimum fraction of matches in a flank sequence to consider it 'found'")]
-    #[clap(default_value = "0.7")]
-    pub min_flank_id_frac: f32,
+    #[clap(
+        help = "Minimum fraction of matches in a flank sequence to consider it 'found' [default: 0.7 for hifi, 0.65 for ont]"
+    )]
+    pub min_flank_id_frac: Option<f32>,
 
     #[clap(help_heading("Advanced"))]
     #[clap(long = "flank-len")]
@@ -117,30 +202,45 @@ pub struct CliParams {
     #[clap(help_heading("Advanced"))]
     #[clap(long = "fixed-flanks")]
     #[clap(value_name = "FIXED_FLANKS")]
-    #[clap(help = "Keep flank length fixed")]
+    #[clap(help = "Locate repeats using flanks directly adjacent to them")]
     pub fixed_flanks: bool,
 
     #[clap(help_heading("Advanced"))]
     #[clap(long = "min-read-quality")]
     #[clap(value_name = "MIN_RQ")]
-    #[clap(help = "Minimum HiFi rq value required to use a read for genotyping")]
-    #[clap(default_value = "0.98")]
-    pub min_hifi_read_qual: f64,
+    #[clap(
+        help = "Minimum read accuracy (rq tag or, if missing and the preset is ont, mean base quality) required to use a read for genotyping [default: 0.98 for hifi, 0.9 for ont]"
+    )]
+    pub min_read_qual: Option<f64>,
+}
 
-    #[clap(help_heading("Advanced"))]
-    #[clap(short = 'v')]
-    #[clap(long = "verbose")]
-    #[clap(action = clap::ArgAction::Count)]
-    pub verbosity: u8,
+#[derive(Parser)]
+#[command(arg_required_else_help(true))]
+pub struct MergeArgs {
+    #[clap(required = true)]
+    #[clap(long = "vcf")]
+    #[clap(help = "VCF files generated by TRGT")]
+    #[clap(value_name = "VCF")]
+    #[clap(num_args = 1..)]
+    #[arg(value_parser = check_file_exists)]
+    pub vcf_paths: Vec<PathBuf>,
+
+    #[clap(required = true)]
+    #[clap(long = "output")]
+    #[clap(help = "Output path of the merged VCF file")]
+    #[clap(value_name = "OUTPUT")]
+    #[arg(value_parser = check_prefix_path)]
+    pub output_path: String,
 }
 
-pub fn get_cli_params() -> CliParams {
-    let args = CliParams::parse();
-    check_args(args)
+pub fn get_cli_params() -> Cli {
+    let args = Cli::parse();
+    init_logger(args.verbosity);
+    args
 }
 
-fn check_args(args: CliParams) -> CliParams {
-    let filter_level: LevelFilter = match args.verbosity {
+fn init_logger(verbosity: u8) {
+    let filter_level: LevelFilter = match verbosity {
         0 => LevelFilter::Warn,
         1 => LevelFilter::Info,
         _ => LevelFilter::Debug,
@@ -167,8 +267,6 @@ fn check_args(args: CliParams) -> CliParams {
         })
         .filter_level(filter_level)
         .init();
-
-    args
 }
 
 pub fn handle_error_and_exit(err: String) -> ! {
@@ -1,69 +1,91 @@
-use crate::cluster::consensus;
 use crate::cluster::math::median;
+use crate::consensus::{get_consensus, Consensus};
 use crate::genotype::{Gt, TrSize};
-use arrayvec::ArrayVec;
 use bio::alignment::distance::simd::bounded_levenshtein;
-use bio::alignment::{pairwise::*, Alignment};
 use itertools::Itertools;
 use kodama::{linkage, Method};
 
-pub fn central_read(num_seqs: usize, group: &[usize], dists: &[f64]) -> usize {
-    let group_size = group.len();
-    if group_size <= 2 {
-        return group[0];
-    }
-
-    let mut dist_sums = vec![0_f64; group_size];
-    for i in 0..(group_size - 1) {
-        for j in (i + 1)..group_size {
-            let index1 = group[i];
-            let index2 = group[j];
-
-            /* dist_sums has the condensed distance matrix, element 0 is
-             * dist(seq[0], seq[1]), element 1 is dist(seq[0], seq[2]), etc.
-             * This is the "inverse" that finds the matrix index based on the
-             * index of the two seqs being compared */
-            let mat_index = num_seqs * index1 - index1 * (index1 + 3) / 2 + index2 - 1;
-            dist_sums[i] += dists[mat_index];
-            dist_sums[j] += dists[mat_index];
-        }
-    }
-
-    dist_sums
-        .iter()
-        .enumerate()
-        .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
-        .map(|(index, _)| group[index])
-        .unwrap()
-}
-
-pub fn make_consensus(
-    num_seqs: usize,
-    trs: &[&str],
-    dists: &[f64],
-    group: &[usize],
-) -> (String, TrSize) {
+pub fn make_consensus(trs: &[&str], group: &[usize]) -> (Consensus, TrSize) {
     let seqs = group.iter().map(|&i| trs[i]).collect_vec();
-    let backbone = trs[central_read(num_seqs, group, dists)];
-    let aligns = align(backbone, &seqs);
-    let allele = consensus::repair_consensus(backbone, &seqs, &aligns);
-    let size = TrSize::new(allele.len(), get_ci(&seqs));
+    let allele = get_consensus(&seqs);
+    let size = TrSize::new(allele.seq.len(), get_ci(&seqs));
 
     (allele, size)
 }
 
-pub fn genotype(seqs: &Vec<&[u8]>, trs: &[&str]) -> (Gt, Vec<String>, Vec<i32>) {
+pub fn genotype(ploidy: usize, seqs: &Vec<&[u8]>, trs: &[&str]) -> (Gt, Vec<Consensus>, Vec<i32>) {
     let mut dists = get_dist_matrix(seqs);
     let mut groups = cluster(seqs.len(), &mut dists);
     groups.sort_by_key(|a| a.len());
 
     let group1 = groups.pop().unwrap();
-    let (allele1, size1) = make_consensus(seqs.len(), trs, &dists, &group1);
+    let mut allele_groups = vec![group1];
+    while allele_groups.len() < ploidy {
+        let group = groups.pop().unwrap_or_default();
+        if is_minor_group(&allele_groups[0], &group, trs) {
+            break;
+        }
+        allele_groups.push(group);
+    }
 
-    let group2 = groups.pop().unwrap_or_default();
+    if allele_groups.len() == 1 {
+        let (allele1, size1) = make_consensus(trs, &allele_groups[0]);
+        let gt = vec![size1; ploidy];
+        let alleles = vec![allele1; ploidy];
 
+        // distribute reads across alleles "randomly"
+        let classification = (0..seqs.len() as i32)
+            .map(|x| x % ploidy as i32)
+            .collect::<Vec<i32>>();
+        return (gt, alleles, classification);
+    }
+
+    let mut gt = Gt::new();
+    let mut alleles = Vec::new();
+    // Reads outside of the allele groups stay unassigned
+    let mut classifications = vec![-1; seqs.len()];
+    for (group_index, group) in allele_groups.iter().enumerate() {
+        let (allele, size) = make_consensus(trs, group);
+        gt.push(size);
+        alleles.push(allele);
+        for seq_index in group {
+            classifications[*seq_index] = group_index as i32;
+        }
+    }
+
+    // Remaining copies are attributed to the largest group, whose reads are
+    // distributed across its copies
+    let copies = (allele_groups.len()..ploidy).collect_vec();
+    for (read_index, seq_index) in allele_groups[0].iter().enumerate() {
+        let copy = read_index % (copies.len() + 1);
+        if copy != 0 {
+            classifications[*seq_index] = copies[copy - 1] as i32;
+        }
+    }
+    for _ in copies {
+        gt.push(gt[0].clone());
+        alleles.push(alleles[0].clone());
+    }
+
+    // Shorter alleles come first
+    let order = (0..alleles.len())
+        .sorted_by_key(|index| alleles[*index].seq.len())
+        .collect_vec();
+    let gt = order.iter().map(|index| gt[*index].clone()).collect();
+    let alleles = order.iter().map(|index| alleles[*index].clone()).collect();
+    for classification in classifications.iter_mut() {
+        if let Some(position) = order.iter().position(|i| *i as i32 == *classification) {
+            *classification = position as i32;
+        }
+    }
+    (gt, alleles, classifications)
+}
+
+/// Checks if a group of reads is too small or too similar to the largest
+/// group to represent a separate allele
+fn is_minor_group(group1: &[usize], group2: &[usize], trs: &[&str]) -> bool {
     const MAX_GROUP_RATIO: usize = 10;
-    let is_homozygous = group2.is_empty() || group2.len() * MAX_GROUP_RATIO <= group1.len() || {
+    group2.is_empty() || group2.len() * MAX_GROUP_RATIO <= group1.len() || {
         // reject group2 if estimated consensus size difference is negligible
         // and group 1 has significantly more reads.
         let group1_len =
@@ -76,33 +98,7 @@ pub fn genotype(seqs: &Vec<&[u8]>, trs: &[&str]) -> (Gt, Vec<String>, Vec<i32>)
         const MIN_GROUP_SIZE_DIFF: f32 = 100.0;
         const MAX_SIMILAR_GROUP_FRAC: f64 = 0.80;
         delta <= MIN_GROUP_SIZE_DIFF && group1_frac >= MAX_SIMILAR_GROUP_FRAC
-    };
-
-    if is_homozygous {
-        let gt = ArrayVec::from([size1.clone(), size1]);
-        let alleles = vec![allele1.clone(), allele1];
-
-        // distribute reads across alleles "randomly"
-        let classification = (0..seqs.len() as i32).map(|x| x % 2).collect::<Vec<i32>>();
-        return (gt, alleles, classification);
-    }
-
-    let (allele2, size2) = make_consensus(seqs.len(), trs, &dists, &group2);
-    let mut classifications = vec![2; seqs.len()];
-    for seq_index in group1 {
-        classifications[seq_index] = 0;
     }
-    for seq_index in group2 {
-        classifications[seq_index] = 1;
-    }
-
-    let (gt, alleles) = if allele1.len() > allele2.len() {
-        classifications = classifications.iter().map(|x| 1 - x).collect();
-        (ArrayVec::from([size2, size1]), vec![allele2, allele1])
-    } else {
-        (ArrayVec::from([size1, size2]), vec![allele1, allele2])
-    };
-    (gt, alleles, classifications)
 }
 
 pub fn cluster(num_seqs: usize, dists: &mut Vec<f64>) -> Vec<Vec<usize>> {
@@ -189,9 +185,26 @@ fn get_dist_matrix(seqs: &Vec<&[u8]>) -> Vec<f64> {
     dists
 }
 
-fn align(backbone: &str, seqs: &[&str]) -> Vec<Alignment> {
-    let mut aligner = Aligner::new(-5, -1, |a, b| if a == b { 1i32 } else { -1i32 });
-    seqs.iter()
-        .map(|seq| aligner.global(seq.as_bytes(), backbone.as_bytes()))
-        .collect_vec()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_outside_allele_groups_are_unassigned() {
+        let (short, long, other) = ("CAG".repeat(10), "CAG".repeat(40), "AT".repeat(50));
+        let trs = [
+            vec![short.as_str(); 10],
+            vec![long.as_str(); 10],
+            vec![other.as_str(); 3],
+        ]
+        .concat();
+        let seqs = trs.iter().map(|tr| tr.as_bytes()).collect_vec();
+        let (gt, alleles, classification) = genotype(2, &seqs, &trs);
+
+        assert_eq!(gt.len(), 2);
+        assert_eq!(alleles[0].seq, short);
+        assert_eq!(alleles[1].seq, long);
+        let expected = [vec![0; 10], vec![1; 10], vec![-1; 3]].concat();
+        assert_eq!(classification, expected);
+    }
 }
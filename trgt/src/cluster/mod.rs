@@ -1,5 +1,4 @@
 pub mod cluster;
-pub mod consensus;
 pub mod math;
 
 pub use cluster::cluster;
@@ -0,0 +1,282 @@
+//! Consensus of allele sequences by partial order alignment
+
+use bio::alignment::pairwise::Scoring;
+use bio::alignment::poa::{Aligner, POAGraph};
+use itertools::Itertools;
+
+/// Sequences are aligned within a band when the full alignment matrix would
+/// have more cells
+const MAX_UNBANDED_CELLS: usize = 4_000_000;
+/// The band is widened by the length difference between the sequence and the
+/// backbone of the graph
+const MIN_BANDWIDTH: usize = 100;
+
+/// Bases added around every sequence so that all of them enter and leave the
+/// graph through the same nodes
+const SEQ_START: u8 = b'^';
+const SEQ_END: u8 = b'$';
+
+/// Bases supported by a smaller fraction of sequences are low-confidence
+pub const MIN_BASE_SUPPORT: f64 = 0.5;
+
+/// Consensus sequence and the fraction of sequences that agree with each of
+/// its bases; empty sequences count towards the total but support no base
+#[derive(Debug, Clone, PartialEq)]
+pub struct Consensus {
+    pub seq: String,
+    pub support: Vec<f64>,
+}
+
+/// Positions of the bases supported by less than the minimum fraction of
+/// sequences
+pub fn get_low_support_positions(support: &[f64]) -> Vec<usize> {
+    support
+        .iter()
+        .positions(|frac| *frac < MIN_BASE_SUPPORT)
+        .collect()
+}
+
+/// Builds the consensus by adding sequences to a partial order graph from the
+/// most to the least frequent and taking the heaviest path through it; empty
+/// sequences win if they make up at least half of all sequences
+pub fn get_consensus(seqs: &[&str]) -> Consensus {
+    let num_seqs = seqs.len();
+    let median_len = seqs.iter().map(|s| s.len()).sorted().nth(num_seqs / 2);
+    let unique_seqs = seqs
+        .iter()
+        .filter(|seq| !seq.is_empty())
+        .counts()
+        .into_iter()
+        .sorted_by_key(|(seq, count)| {
+            let median_dist = seq.len().abs_diff(median_len.unwrap_or(0));
+            (std::cmp::Reverse(*count), median_dist, **seq)
+        })
+        .collect_vec();
+
+    let num_nonempty = unique_seqs.iter().map(|(_, count)| count).sum::<usize>();
+    if unique_seqs.is_empty() || 2 * num_nonempty <= num_seqs {
+        return Consensus {
+            seq: String::new(),
+            support: Vec::new(),
+        };
+    }
+
+    let padded_seqs = unique_seqs
+        .iter()
+        .map(|(seq, count)| {
+            let padded = [&[SEQ_START], seq.as_bytes(), &[SEQ_END]].concat();
+            (padded, *count)
+        })
+        .collect_vec();
+
+    let scoring = Scoring::new(-2, 0, |a: u8, b: u8| if a == b { 1i32 } else { -1i32 });
+    let (backbone, backbone_count) = &padded_seqs[0];
+    let mut aligner = Aligner::new(scoring, backbone);
+    add_copies(&mut aligner, backbone, backbone.len(), backbone_count - 1);
+    for (seq, count) in &padded_seqs[1..] {
+        align(&mut aligner, seq, backbone.len()).add_to_graph();
+        add_copies(&mut aligner, seq, backbone.len(), count - 1);
+    }
+
+    // Since every sequence enters each of its nodes through an edge, the
+    // weights of incoming edges add up to the number of sequences at a node
+    let graph = aligner.graph();
+    let mut coverage = vec![0; graph.node_count()];
+    for edge in graph.raw_edges() {
+        coverage[edge.target().index()] += edge.weight as usize;
+    }
+
+    let nodes = graph.raw_nodes();
+    let path = get_heaviest_path(graph, num_nonempty)
+        .into_iter()
+        .filter(|node| ![SEQ_START, SEQ_END].contains(&nodes[*node].weight))
+        .collect_vec();
+    Consensus {
+        seq: path
+            .iter()
+            .map(|node| nodes[*node].weight as char)
+            .collect(),
+        support: path
+            .iter()
+            .map(|node| coverage[*node] as f64 / num_seqs as f64)
+            .collect(),
+    }
+}
+
+/// Consensus of the given length; if the consensus of all sequences has a
+/// different length, each base is instead the most common one at its position
+/// in the sequences of that length
+pub fn get_consensus_of_len(seqs: &[&str], len: usize) -> Consensus {
+    let consensus = get_consensus(seqs);
+    let same_len = seqs.iter().filter(|s| s.len() == len).collect_vec();
+    if consensus.seq.len() == len || same_len.is_empty() {
+        return consensus;
+    }
+
+    let (seq, support) = (0..len)
+        .map(|pos| {
+            let (base, count) = same_len
+                .iter()
+                .map(|s| s.as_bytes()[pos])
+                .counts()
+                .into_iter()
+                .max_by_key(|(base, count)| (*count, std::cmp::Reverse(*base)))
+                .unwrap();
+            (base as char, count as f64 / seqs.len() as f64)
+        })
+        .unzip();
+    Consensus { seq, support }
+}
+
+fn align<'a, F>(aligner: &'a mut Aligner<F>, seq: &[u8], backbone_len: usize) -> &'a mut Aligner<F>
+where
+    F: bio::alignment::pairwise::MatchFunc,
+{
+    let num_cells = aligner.graph().node_count() * seq.len();
+    if num_cells <= MAX_UNBANDED_CELLS {
+        aligner.global(seq)
+    } else {
+        let bandwidth = MIN_BANDWIDTH + seq.len().abs_diff(backbone_len);
+        aligner.global_banded(seq, bandwidth)
+    }
+}
+
+/// Adds more copies of a sequence that is already in the graph; since the
+/// sequence aligns to its own path without mismatches, the copies only
+/// increase the weights of the edges along it
+fn add_copies<F>(aligner: &mut Aligner<F>, seq: &[u8], backbone_len: usize, count: usize)
+where
+    F: bio::alignment::pairwise::MatchFunc,
+{
+    if count == 0 {
+        return;
+    }
+    let alignment = align(aligner, seq, backbone_len).alignment();
+    for _ in 0..count {
+        aligner.add_alignment(&alignment);
+    }
+}
+
+/// Finds the path that follows the heaviest edges
+fn get_heaviest_path(graph: &POAGraph, num_seqs: usize) -> Vec<usize> {
+    let num_nodes = graph.node_count();
+    let mut preds = vec![Vec::new(); num_nodes];
+    let mut succs = vec![Vec::new(); num_nodes];
+    for edge in graph.raw_edges() {
+        let (source, target) = (edge.source().index(), edge.target().index());
+        let weight = edge.weight as usize;
+        preds[target].push((source, weight));
+        succs[source].push(target);
+    }
+
+    // Nodes are visited in topological order; each node keeps its heaviest
+    // incoming edge. Edges shared by less than half of the sequences lower the
+    // score of the path, so that it ends where most sequences end instead of
+    // following a minority of longer sequences
+    let mut scores = vec![0; num_nodes];
+    let mut best_preds = vec![None; num_nodes];
+    let mut num_unvisited_preds = preds.iter().map(|p| p.len()).collect_vec();
+    let mut queue = (0..num_nodes)
+        .filter(|node| num_unvisited_preds[*node] == 0)
+        .collect_vec();
+    while let Some(node) = queue.pop() {
+        let best = preds[node]
+            .iter()
+            .map(|(pred, weight)| (*weight, scores[*pred], *pred))
+            .max();
+        if let Some((weight, score, pred)) = best {
+            scores[node] = score + 2 * weight as i64 - num_seqs as i64;
+            best_preds[node] = Some(pred);
+        }
+        for succ in &succs[node] {
+            num_unvisited_preds[*succ] -= 1;
+            if num_unvisited_preds[*succ] == 0 {
+                queue.push(*succ);
+            }
+        }
+    }
+
+    let mut node = (0..num_nodes).max_by_key(|node| (scores[*node], *node));
+    let mut path = Vec::new();
+    while let Some(current) = node {
+        path.push(current);
+        node = best_preds[current];
+    }
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consensus_of_noisy_sequences() {
+        let seqs = [
+            "CAGCAGCAGCTGCAGCAG",
+            "CAGCAGCAGCAGCAG",
+            "CAGCAGCAGCTGCAGCAG",
+            "CAGCAGCAGCTGCAGAG",
+            "CAGCATCAGCTGCAGCAG",
+        ];
+        let consensus = get_consensus(&seqs);
+        assert_eq!(consensus.seq, "CAGCAGCAGCTGCAGCAG");
+        assert_eq!(consensus.support.len(), consensus.seq.len());
+        assert_eq!(consensus.support[0], 1.0);
+        assert!(consensus.support[5] < 1.0);
+        assert!(get_low_support_positions(&consensus.support).is_empty());
+    }
+
+    #[test]
+    fn consensus_of_mosaic_sequences() {
+        let seqs = ["ATATATAT", "ATATATAT", "ATAGGTAT", "ATACCTAT"];
+        let consensus = get_consensus(&seqs);
+        assert_eq!(consensus.seq, "ATATATAT");
+        assert!(get_low_support_positions(&consensus.support).is_empty());
+
+        let seqs = ["ATAGGTAT", "ATATATAT", "ATACCTAT"];
+        let consensus = get_consensus(&seqs);
+        assert_eq!(consensus.seq.len(), 8);
+        assert_eq!(get_low_support_positions(&consensus.support), vec![3, 4]);
+    }
+
+    #[test]
+    fn consensus_ends_where_most_sequences_end() {
+        let seqs = [
+            "CAGCAGCAGCAG",
+            "CAGCAGCAGCAG",
+            "CAGCAGCAGCAG",
+            "CAGCAGCAGCAGCAG",
+            "CAGCAGCAGCAGCAGCAG",
+        ];
+        let consensus = get_consensus(&seqs);
+        assert_eq!(consensus.seq, "CAGCAGCAGCAG");
+        assert!(get_low_support_positions(&consensus.support).is_empty());
+    }
+
+    #[test]
+    fn consensus_of_mostly_empty_sequences() {
+        let consensus = get_consensus(&["", "", "CAG"]);
+        assert_eq!(consensus.seq, "");
+        let consensus = get_consensus(&["", "CAG", "CAG"]);
+        assert_eq!(consensus.seq, "CAG");
+        assert_eq!(consensus.support, vec![2.0 / 3.0; 3]);
+    }
+
+    #[test]
+    fn consensus_of_given_length() {
+        let seqs = [
+            "CAGCAGCAG",
+            "CAGCAGCAG",
+            "CAGCAGCAG",
+            "CAGCAGCAGCAG",
+            "CAGCAGCAGCTG",
+        ];
+        assert_eq!(get_consensus_of_len(&seqs, 9), get_consensus(&seqs));
+
+        let consensus = get_consensus_of_len(&seqs, 12);
+        assert_eq!(consensus.seq, "CAGCAGCAGCAG");
+        assert_eq!(consensus.support[0], 0.4);
+        assert_eq!(consensus.support[10], 0.2);
+    }
+}
@@ -89,7 +89,10 @@ impl Reader {
         end: usize,
     ) -> Result<String> {
         let bytes = self.fetch_seq(name, begin, end)?;
-        Ok(std::str::from_utf8(bytes).unwrap().to_owned())
+        let seq = std::str::from_utf8(bytes).unwrap().to_owned();
+        // The sequence is allocated by htslib and must be released once copied
+        unsafe { htslib::free(bytes.as_ptr() as *mut ffi::c_void) };
+        Ok(seq)
     }
 
     /// Fetches the number of sequences in the fai index
@@ -1,13 +1,13 @@
-use super::{Gt, TrSize};
+use super::{Gt, LenErrors, TrSize};
 use itertools::Itertools;
 use std::cmp::{max, min};
 
-pub fn genotype(sizes: &[usize], counts: &[usize]) -> Gt {
+pub fn genotype(sizes: &[usize], counts: &[usize], len_errors: &LenErrors) -> Gt {
     let mut gts_and_penalties = Vec::new();
     for short_index in 0..sizes.len() {
         for long_index in short_index..sizes.len() {
             let gt = (sizes[short_index], sizes[long_index]);
-            let penalty = calc_gt_penalty(&gt, sizes, counts);
+            let penalty = calc_gt_penalty(&gt, sizes, counts, len_errors);
             gts_and_penalties.push((gt, penalty));
         }
     }
@@ -40,6 +40,13 @@ pub fn genotype(sizes: &[usize], counts: &[usize]) -> Gt {
         }
     }
 
+    // Alleles closer than the length errors of reads cannot be told apart
+    if short_size != long_size && long_size - short_size <= len_errors.tolerance(long_size) {
+        let median = get_median_size(sizes, counts);
+        short_size = median;
+        long_size = median;
+    }
+
     let (short_ci, long_ci) = get_ci((short_size, long_size), sizes);
 
     let short_allele = TrSize {
@@ -55,7 +62,12 @@ pub fn genotype(sizes: &[usize], counts: &[usize]) -> Gt {
     Gt::from([short_allele, long_allele])
 }
 
-fn calc_gt_penalty(gt: &(usize, usize), sizes: &[usize], counts: &[usize]) -> f64 {
+fn calc_gt_penalty(
+    gt: &(usize, usize),
+    sizes: &[usize],
+    counts: &[usize],
+    len_errors: &LenErrors,
+) -> f64 {
     let (short_allele, long_allele) = gt;
 
     let mut penalty = 0.0;
@@ -71,25 +83,28 @@ fn calc_gt_penalty(gt: &(usize, usize), sizes: &[usize], counts: &[usize]) -> f6
     };
 
     for (size, count) in sizes.iter().zip(counts) {
-        let short_term = if *size != *short_allele {
-            10 + 2 * short_allele.abs_diff(*size)
-        } else {
-            0
-        };
-
-        let long_term = if *size != *long_allele {
-            10 + 2 * long_allele.abs_diff(*size)
-        } else {
-            0
-        };
-
-        let term = min(short_term, long_term) as f64 + max_frac * max(short_term, long_term) as f64;
+        let short_term = len_errors.penalty(*short_allele, *size);
+        let long_term = len_errors.penalty(*long_allele, *size);
+        let term = short_term.min(long_term) + max_frac * short_term.max(long_term);
         penalty += term * (*count as f64);
     }
 
     penalty
 }
 
+/// Size of the read in the middle when reads are ordered by size
+fn get_median_size(sizes: &[usize], counts: &[usize]) -> usize {
+    let total = counts.iter().sum::<usize>();
+    let mut seen = 0;
+    for (size, count) in sizes.iter().zip(counts).sorted() {
+        seen += count;
+        if 2 * seen >= total {
+            return *size;
+        }
+    }
+    *sizes.last().unwrap()
+}
+
 fn get_ci(gt: (usize, usize), sizes: &[usize]) -> ((usize, usize), (usize, usize)) {
     let (short_size, long_size) = gt;
     let mut short_ci = (short_size, short_size);
@@ -112,16 +127,46 @@ fn get_ci(gt: (usize, usize), sizes: &[usize]) -> ((usize, usize), (usize, usize
 #[cfg(test)]
 mod tests {
     use super::*;
-    use arrayvec::ArrayVec;
 
     #[test]
     fn clean_het_tr() {
         let sizes = vec![3, 4];
         let counts = vec![3, 3];
-        let gt = genotype(&sizes, &counts);
+        let gt = genotype(&sizes, &counts, &LenErrors::NONE);
 
         let short_allele = TrSize::new(3, (3, 3));
         let long_allele = TrSize::new(4, (4, 4));
-        assert_eq!(gt, ArrayVec::from([short_allele, long_allele]));
+        assert_eq!(gt, vec![short_allele, long_allele]);
+    }
+
+    #[test]
+    fn noisy_hom_tr() {
+        let sizes = vec![57, 58, 59, 60, 61, 62, 63];
+        let counts = vec![1, 2, 3, 3, 4, 1, 1];
+        let len_errors = LenErrors {
+            min_tolerance: 2,
+            tolerance_frac: 0.05,
+        };
+
+        let gt = genotype(&sizes, &counts, &len_errors);
+        assert_eq!(gt[0].size, 60);
+        assert_eq!(gt[1].size, 60);
+
+        let gt = genotype(&sizes, &counts, &LenErrors::NONE);
+        assert_ne!(gt[0].size, gt[1].size);
+    }
+
+    #[test]
+    fn noisy_het_tr() {
+        let sizes = vec![28, 29, 30, 31, 32, 58, 59, 60, 61, 62];
+        let counts = vec![1, 2, 4, 2, 1, 1, 3, 3, 2, 1];
+        let len_errors = LenErrors {
+            min_tolerance: 2,
+            tolerance_frac: 0.05,
+        };
+
+        let gt = genotype(&sizes, &counts, &len_errors);
+        assert_eq!(gt[0].size, 30);
+        assert!(gt[1].size.abs_diff(60) <= 1);
     }
 }
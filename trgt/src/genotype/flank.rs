@@ -1,39 +1,33 @@
 use super::Gt;
-use crate::cluster::consensus::repair_consensus;
-use crate::cluster::math::median;
+use crate::consensus::{get_consensus, Consensus};
 use crate::{genotype::TrSize, reads::HiFiRead};
-use bio::alignment::{pairwise::*, Alignment};
 use itertools::Itertools;
 use std::cmp::Ordering;
 
 type Profile = Vec<Option<bool>>;
 
-pub fn genotype(reads: &[HiFiRead], tr_seqs: &[&str]) -> Option<(Gt, Vec<String>, Vec<i32>)> {
+pub fn genotype(reads: &[HiFiRead], tr_seqs: &[&str]) -> Option<(Gt, Vec<Consensus>, Vec<i32>)> {
     let (trs_by_allele, mut allele_assignment) =
         get_trs_with_hp(reads, tr_seqs).or_else(|| get_trs_with_clustering(reads, tr_seqs))?;
     let mut gt = Gt::new();
     let mut alleles = Vec::new();
 
     for trs in trs_by_allele {
-        let (backbone, frequency) = simple_consensus(&trs)?;
-        const MIN_FREQ_TO_ALIGN: f64 = 0.5;
-        let allele = if frequency < MIN_FREQ_TO_ALIGN {
-            let aligns = align(&backbone, &trs);
-            repair_consensus(&backbone, &trs, &aligns)
-        } else {
-            backbone.to_string()
-        };
+        if trs.is_empty() {
+            return None;
+        }
+        let allele = get_consensus(&trs);
 
         let min_tr_len = trs.iter().map(|tr| tr.len()).min().unwrap();
         let max_tr_len = trs.iter().map(|tr| tr.len()).max().unwrap();
 
-        let size = TrSize::new(allele.len(), (min_tr_len, max_tr_len));
+        let size = TrSize::new(allele.seq.len(), (min_tr_len, max_tr_len));
         gt.push(size);
         alleles.push(allele);
     }
 
     // Smaller allele should always appear first
-    if alleles[0].len() > alleles[1].len() {
+    if alleles[0].seq.len() > alleles[1].seq.len() {
         gt.swap(0, 1);
         alleles.swap(0, 1);
         allele_assignment = allele_assignment.into_iter().map(|a| (a + 1) % 2).collect();
@@ -143,27 +137,6 @@ fn get_dist(read: &[Option<bool>], allele: &[bool]) -> usize {
         .sum()
 }
 
-/// Determine consensus for the input sequences
-///
-/// Return the most frequent sequence and its relative frequency.
-/// If multiple sequences have the same frequency,
-/// return the one whose length is closest to the median.
-///
-fn simple_consensus(seqs: &[&str]) -> Option<(String, f64)> {
-    let median_len = median(&seqs.iter().map(|s| s.len() as i32).collect_vec())? as usize;
-    let seq_to_count = seqs.iter().counts();
-    let top_group_size = *seq_to_count.values().max()?;
-    let consensus = seq_to_count
-        .into_iter()
-        .filter(|(_, c)| *c == top_group_size)
-        .map(|(s, _)| (s, s.len().abs_diff(median_len)))
-        .min_by_key(|(_, delta)| *delta)
-        .map(|(s, _)| s.to_string())?;
-
-    let top_group_frequency = (top_group_size as f64) / (seqs.len() as f64);
-    Some((consensus, top_group_frequency))
-}
-
 fn get_loglik(gt: &(Vec<bool>, Vec<bool>), profiles: &Vec<Profile>) -> f64 {
     let mut total_ll = 0.0;
     for profile in profiles {
@@ -285,13 +258,6 @@ fn call_snvs(region: (i32, i32), reads: &[HiFiRead], min_freq: f64) -> Vec<i32>
         .collect()
 }
 
-fn align(backbone: &str, seqs: &[&str]) -> Vec<Alignment> {
-    let mut aligner = Aligner::new(-5, -1, |a, b| if a == b { 1i32 } else { -1i32 });
-    seqs.iter()
-        .map(|seq| aligner.global(seq.as_bytes(), backbone.as_bytes()))
-        .collect_vec()
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -332,7 +298,10 @@ mod tests {
             end_offset,
             cigar: None,
             hp_tag: None,
+            ps_tag: None,
             mapq: 60,
+            is_reverse: false,
+            mods: Vec::new(),
         }
     }
 
@@ -367,7 +336,16 @@ mod tests {
             },
         ]);
 
-        let alleles = vec!["TATATATA".to_string(), "TATATATATA".to_string()];
+        let alleles = vec![
+            Consensus {
+                seq: "TATATATA".to_string(),
+                support: vec![1.0; 8],
+            },
+            Consensus {
+                seq: "TATATATATA".to_string(),
+                support: vec![1.0; 10],
+            },
+        ];
         let assignment = vec![0, 0, 1, 1, 1, 0];
 
         assert_eq!(result, Some((gt, alleles, assignment)));
@@ -1,22 +1,68 @@
-use arrayvec::ArrayVec;
 use std::str::FromStr;
 
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub enum Ploidy {
-    Zero,
-    One,
-    Two,
+/// Largest supported number of copies of a chromosome
+pub const MAX_PLOIDY: usize = 10;
+
+/// Number of copies of a chromosome
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Ploidy(usize);
+
+impl Ploidy {
+    pub const ZERO: Ploidy = Ploidy(0);
+    pub const ONE: Ploidy = Ploidy(1);
+    pub const TWO: Ploidy = Ploidy(2);
+
+    /// Ploidy with the given number of copies, capped at the supported maximum
+    pub fn capped(count: usize) -> Ploidy {
+        Ploidy(count.min(MAX_PLOIDY))
+    }
+
+    pub fn count(&self) -> usize {
+        self.0
+    }
 }
 
 impl FromStr for Ploidy {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "0" => Ok(Ploidy::Zero),
-            "1" => Ok(Ploidy::One),
-            "2" => Ok(Ploidy::Two),
-            _ => Err("must be set to 0, 1, or 2".to_string()),
+        match s.parse::<usize>() {
+            Ok(count) if count <= MAX_PLOIDY => Ok(Ploidy(count)),
+            _ => Err(format!("must be set to a number from 0 to {}", MAX_PLOIDY)),
+        }
+    }
+}
+
+/// Differences between repeat lengths in reads and the lengths of their
+/// alleles that can be explained by sequencing errors
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct LenErrors {
+    pub min_tolerance: usize,
+    pub tolerance_frac: f64,
+}
+
+impl LenErrors {
+    /// Reads are expected to match allele lengths exactly
+    pub const NONE: LenErrors = LenErrors {
+        min_tolerance: 0,
+        tolerance_frac: 0.0,
+    };
+
+    /// Largest length difference attributed to sequencing errors
+    pub fn tolerance(&self, allele_len: usize) -> usize {
+        let scaled = (self.tolerance_frac * allele_len as f64).round() as usize;
+        self.min_tolerance.max(scaled)
+    }
+
+    /// Penalty for a read length under an allele length; differences within
+    /// the tolerance cost less than any difference beyond it
+    pub fn penalty(&self, allele_len: usize, read_len: usize) -> f64 {
+        let tolerance = self.tolerance(allele_len);
+        let diff = allele_len.abs_diff(read_len);
+        if diff <= tolerance {
+            diff as f64 / (tolerance + 1) as f64
+        } else {
+            10.0 + 2.0 * (diff - tolerance) as f64
         }
     }
 }
@@ -33,4 +79,4 @@ impl TrSize {
     }
 }
 
-pub type Gt = ArrayVec<TrSize, 2>;
+pub type Gt = Vec<TrSize>;
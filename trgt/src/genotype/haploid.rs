@@ -1,9 +1,9 @@
-use super::{Gt, TrSize};
+use super::{Gt, LenErrors, TrSize};
 
-pub fn genotype(sizes: &[usize], counts: &[usize]) -> Gt {
+pub fn genotype(sizes: &[usize], counts: &[usize], len_errors: &LenErrors) -> Gt {
     let mut gts_and_penalties = Vec::new();
     for allele in sizes.iter() {
-        let penalty = calc_gt_penalty(*allele, sizes, counts);
+        let penalty = calc_gt_penalty(*allele, sizes, counts, len_errors);
         gts_and_penalties.push((allele, penalty));
     }
 
@@ -12,22 +12,19 @@ pub fn genotype(sizes: &[usize], counts: &[usize]) -> Gt {
     let size = gts_and_penalties.first().unwrap().0;
     let ci = (*sizes.iter().min().unwrap(), *sizes.iter().max().unwrap());
 
-    let allele = TrSize::new(*size, ci);
-    let mut gt = Gt::new();
-    gt.push(allele);
-    gt
+    vec![TrSize::new(*size, ci)]
 }
 
-fn calc_gt_penalty(allele: usize, sizes: &[usize], counts: &[usize]) -> f64 {
+fn calc_gt_penalty(
+    allele: usize,
+    sizes: &[usize],
+    counts: &[usize],
+    len_errors: &LenErrors,
+) -> f64 {
     let mut penalty = 0.0;
 
     for (size, count) in sizes.iter().zip(counts) {
-        let term = if *size != allele {
-            10.0 + 2.0 * allele.abs_diff(*size) as f64
-        } else {
-            0.0
-        };
-        penalty += term * (*count as f64);
+        penalty += len_errors.penalty(allele, *size) * (*count as f64);
     }
 
     penalty
@@ -41,35 +38,29 @@ mod tests {
     fn clean_tr() {
         let sizes = vec![3];
         let counts = vec![3];
-        let gt = genotype(&sizes, &counts);
+        let gt = genotype(&sizes, &counts, &LenErrors::NONE);
 
         let allele = TrSize::new(3, (3, 3));
-        let mut expected = Gt::new();
-        expected.push(allele);
-        assert_eq!(gt, expected);
+        assert_eq!(gt, vec![allele]);
     }
 
     #[test]
     fn mosaic_tr() {
         let sizes = vec![10, 20, 30, 40, 50, 60, 70, 80, 90, 100];
         let counts = vec![1, 1, 1, 1, 1, 1, 1, 1, 1, 1];
-        let gt = genotype(&sizes, &counts);
+        let gt = genotype(&sizes, &counts, &LenErrors::NONE);
 
         let allele = TrSize::new(50, (10, 100));
-        let mut expected = Gt::new();
-        expected.push(allele);
-        assert_eq!(gt, expected);
+        assert_eq!(gt, vec![allele]);
     }
 
     #[test]
     fn tr_with_outliers() {
         let sizes = vec![10, 50];
         let counts = vec![4, 2];
-        let gt = genotype(&sizes, &counts);
+        let gt = genotype(&sizes, &counts, &LenErrors::NONE);
 
         let allele = TrSize::new(10, (10, 50));
-        let mut expected = Gt::new();
-        expected.push(allele);
-        assert_eq!(gt, expected);
+        assert_eq!(gt, vec![allele]);
     }
 }
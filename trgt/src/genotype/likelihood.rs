@@ -0,0 +1,132 @@
+use super::LenErrors;
+use itertools::Itertools;
+
+/// Fraction of reads with repeat lengths unrelated to the allele length
+const OUTLIER_FRAC: f64 = 0.01;
+
+/// Maximum reported genotype quality
+const MAX_GQ: i32 = 99;
+
+/// Likelihoods are omitted for samples with more possible genotypes
+pub const MAX_PL_GENOTYPES: usize = 1000;
+
+/// Lists genotypes as sorted allele indexes in the order used by the PL field
+pub fn get_genotypes(num_alleles: usize, ploidy: usize) -> Vec<Vec<usize>> {
+    (0..num_alleles)
+        .combinations_with_replacement(ploidy)
+        .sorted_by_key(|gt| gt.iter().rev().copied().collect_vec())
+        .collect()
+}
+
+/// Number of genotypes listed by get_genotypes, saturating on overflow
+pub fn count_genotypes(num_alleles: usize, ploidy: usize) -> usize {
+    let mut count: u128 = 1;
+    for k in 1..=ploidy as u128 {
+        count = count * (num_alleles as u128 + k - 1) / k;
+        if count > usize::MAX as u128 {
+            return usize::MAX;
+        }
+    }
+    count as usize
+}
+
+/// Computes log10-likelihoods of each genotype given the repeat lengths
+/// observed in reads, assuming that reads are sampled uniformly from alleles
+pub fn get_log_likelihoods(
+    allele_lens: &[usize],
+    genotypes: &[Vec<usize>],
+    read_lens: &[usize],
+    len_errors: &LenErrors,
+) -> Vec<f64> {
+    let max_len = allele_lens.iter().chain(read_lens).max().unwrap_or(&0);
+    let outlier_prob = OUTLIER_FRAC / (2 * max_len + 1) as f64;
+    genotypes
+        .iter()
+        .map(|gt| {
+            read_lens
+                .iter()
+                .map(|read_len| {
+                    let prob = gt
+                        .iter()
+                        .map(|index| get_len_prob(allele_lens[*index], *read_len, len_errors))
+                        .sum::<f64>()
+                        / gt.len() as f64;
+                    ((1.0 - OUTLIER_FRAC) * prob + outlier_prob).log10()
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// Probability of observing a repeat of a given length in a read from an
+/// allele; errors follow a discrete Laplace distribution that widens with the
+/// allele length and the tolerance to length errors
+fn get_len_prob(allele_len: usize, read_len: usize, len_errors: &LenErrors) -> f64 {
+    let scale = 0.5 + allele_len as f64 / 100.0 + len_errors.tolerance(allele_len) as f64;
+    let decay = (-1.0 / scale).exp();
+    (1.0 - decay) / (1.0 + decay) * decay.powi(allele_len.abs_diff(read_len) as i32)
+}
+
+/// Phred-scaled likelihoods normalized to the most likely genotype
+pub fn get_pl(log_likelihoods: &[f64]) -> Vec<i32> {
+    let max = log_likelihoods
+        .iter()
+        .copied()
+        .fold(f64::NEG_INFINITY, f64::max);
+    log_likelihoods
+        .iter()
+        .map(|ll| (-10.0 * (ll - max)).round() as i32)
+        .collect()
+}
+
+/// Phred-scaled probability that the called genotype is wrong, assuming a
+/// flat prior over genotypes
+pub fn get_gq(log_likelihoods: &[f64], called_index: usize) -> i32 {
+    let max = log_likelihoods
+        .iter()
+        .copied()
+        .fold(f64::NEG_INFINITY, f64::max);
+    let probs = log_likelihoods
+        .iter()
+        .map(|ll| 10.0_f64.powf(ll - max))
+        .collect_vec();
+    let total = probs.iter().sum::<f64>();
+    let error_prob = (total - probs[called_index]) / total;
+    if error_prob <= 0.0 {
+        return MAX_GQ;
+    }
+    ((-10.0 * error_prob.log10()).round() as i32).clamp(0, MAX_GQ)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn genotypes_follow_vcf_order() {
+        let expected = vec![
+            vec![0, 0],
+            vec![0, 1],
+            vec![1, 1],
+            vec![0, 2],
+            vec![1, 2],
+            vec![2, 2],
+        ];
+        assert_eq!(get_genotypes(3, 2), expected);
+        assert_eq!(get_genotypes(2, 1), vec![vec![0], vec![1]]);
+        assert_eq!(count_genotypes(3, 2), 6);
+        assert_eq!(count_genotypes(30, 10), 635745396);
+    }
+
+    #[test]
+    fn het_reads_favor_het_genotype() {
+        let genotypes = get_genotypes(2, 2);
+        let read_lens = [30, 30, 30, 30, 30, 33, 33, 33, 33, 33];
+        let lls = get_log_likelihoods(&[30, 33], &genotypes, &read_lens, &LenErrors::NONE);
+        let pl = get_pl(&lls);
+        assert_eq!(pl[1], 0);
+        assert!(pl[0] > 40 && pl[2] > 40);
+        assert!(get_gq(&lls, 1) > 40);
+        assert_eq!(get_gq(&lls, 0), 0);
+    }
+}
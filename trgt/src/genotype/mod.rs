@@ -1,12 +1,17 @@
-mod consensus;
 mod diploid;
 mod flank;
 mod genotype;
 mod gt;
 mod haploid;
+mod likelihood;
+mod polyploid;
 
 pub use flank::genotype as flank_genotype;
 pub use genotype::genotype;
 pub use gt::Gt;
+pub use gt::LenErrors;
 pub use gt::Ploidy;
 pub use gt::TrSize;
+pub use likelihood::{
+    count_genotypes, get_genotypes, get_gq, get_log_likelihoods, get_pl, MAX_PL_GENOTYPES,
+};
@@ -0,0 +1,135 @@
+use super::likelihood::{get_genotypes, get_log_likelihoods};
+use super::{Gt, LenErrors, TrSize};
+use itertools::Itertools;
+use std::cmp::{max, min};
+
+/// Only the best-supported sizes are considered as alleles to keep the number
+/// of candidate genotypes manageable at high ploidy
+const MAX_CANDIDATE_SIZES: usize = 8;
+
+/// Lengths within this range may come from a single allele with stutter
+const MAX_STUTTER_RANGE: usize = 6;
+/// Excess of the most common length over its expected fraction when a single
+/// copy differs from it
+const MIN_HOMOZYGOUS_EXCESS: f64 = 0.1;
+
+/// Picks the most likely combination of allele sizes; unlike the penalty of
+/// the diploid genotyper, the likelihood accounts for allele dosage
+pub fn genotype(sizes: &[usize], counts: &[usize], ploidy: usize, len_errors: &LenErrors) -> Gt {
+    let candidates = sizes
+        .iter()
+        .zip(counts)
+        .sorted_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)))
+        .take(MAX_CANDIDATE_SIZES)
+        .map(|(size, _count)| *size)
+        .sorted()
+        .collect_vec();
+    let read_lens = sizes
+        .iter()
+        .zip(counts)
+        .flat_map(|(size, count)| std::iter::repeat_n(*size, *count))
+        .collect_vec();
+
+    let genotypes = get_genotypes(candidates.len(), ploidy);
+    let lls = get_log_likelihoods(&candidates, &genotypes, &read_lens, len_errors);
+    let (best_index, _) = lls
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap())
+        .unwrap();
+    let mut allele_sizes = genotypes[best_index]
+        .iter()
+        .map(|index| candidates[*index])
+        .collect_vec();
+
+    // Reclassify the repeat as homozygous if all lengths are close and the
+    // most common one is more frequent than if any copy differed from it;
+    // for two copies, this takes over 60% of reads
+    if allele_sizes.iter().any(|s| *s != allele_sizes[0]) && sizes.len() >= 2 {
+        let coverage = counts.iter().sum::<usize>();
+        let (top_len, top_count) = sizes
+            .iter()
+            .zip(counts)
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .unwrap();
+        let top_frac = *top_count as f64 / coverage as f64;
+        let range = sizes.iter().max().unwrap() - sizes.iter().min().unwrap();
+        let max_het_frac = (ploidy - 1) as f64 / ploidy as f64;
+        if top_frac > max_het_frac + MIN_HOMOZYGOUS_EXCESS && range <= MAX_STUTTER_RANGE {
+            allele_sizes = vec![*top_len; ploidy];
+        }
+    }
+
+    // Alleles closer than the length errors of reads cannot be told apart
+    let (min_size, max_size) = (allele_sizes[0], allele_sizes[ploidy - 1]);
+    if min_size != max_size && max_size - min_size <= len_errors.tolerance(max_size) {
+        allele_sizes = vec![read_lens[read_lens.len() / 2]; ploidy];
+    }
+
+    get_ci(&allele_sizes, sizes)
+        .into_iter()
+        .zip(allele_sizes)
+        .map(|(ci, size)| TrSize::new(size, ci))
+        .collect()
+}
+
+/// Assigns each size to the closest allele; copies of an allele share its range
+fn get_ci(gt: &[usize], sizes: &[usize]) -> Vec<(usize, usize)> {
+    let mut cis = gt.iter().map(|size| (*size, *size)).collect_vec();
+    for size in sizes {
+        let index = gt
+            .iter()
+            .position_min_by_key(|allele| allele.abs_diff(*size))
+            .unwrap();
+        cis[index] = (min(cis[index].0, *size), max(cis[index].1, *size));
+    }
+    gt.iter()
+        .map(|size| cis[gt.iter().position(|s| s == size).unwrap()])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triploid_tr_with_copy_gain() {
+        let sizes = vec![30, 31, 45, 60];
+        let counts = vec![10, 1, 10, 10];
+        let gt = genotype(&sizes, &counts, 3, &LenErrors::NONE);
+
+        let expected = vec![
+            TrSize::new(30, (30, 31)),
+            TrSize::new(45, (45, 45)),
+            TrSize::new(60, (60, 60)),
+        ];
+        assert_eq!(gt, expected);
+    }
+
+    #[test]
+    fn stutter_depends_on_ploidy() {
+        let sizes = vec![30, 33];
+        let counts = vec![16, 4];
+
+        let gt = genotype(&sizes, &counts, 2, &LenErrors::NONE);
+        assert!(gt.iter().all(|a| a.size == 30));
+
+        let gt = genotype(&sizes, &counts, 4, &LenErrors::NONE);
+        assert_eq!(
+            gt.iter().map(|a| a.size).collect_vec(),
+            vec![30, 30, 30, 33]
+        );
+    }
+
+    #[test]
+    fn tetraploid_tr_with_two_alleles() {
+        let sizes = vec![30, 60];
+        let counts = vec![20, 20];
+        let gt = genotype(&sizes, &counts, 4, &LenErrors::NONE);
+
+        assert_eq!(
+            gt.iter().map(|a| a.size).collect_vec(),
+            vec![30, 30, 60, 60]
+        );
+    }
+}
@@ -1,71 +1,182 @@
 use crate::genotype::Ploidy;
-use std::collections::HashMap;
+use crate::utils::GenomicRegion;
+use crate::Sample;
+use rust_htslib::bam::{self, record::Aux, Read};
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::Path;
+
+/// Pseudoautosomal regions as 0-based half-open intervals on chrX and chrY
+const PARS_HG38: [(&str, u32, u32); 4] = [
+    ("X", 10000, 2781479),
+    ("X", 155701382, 156030895),
+    ("Y", 10000, 2781479),
+    ("Y", 56887902, 57217415),
+];
+
+const PARS_HG19: [(&str, u32, u32); 4] = [
+    ("X", 60000, 2699520),
+    ("X", 154931043, 155260560),
+    ("Y", 10000, 2649520),
+    ("Y", 59034049, 59363566),
+];
+
+/// Number of windows sampled per chromosome to estimate coverage of CRAM files
+const NUM_SAMPLED_WINDOWS: u64 = 20;
+const SAMPLED_WINDOW_LEN: u64 = 100_000;
+const MIN_SAMPLED_MAPQ: u8 = 20;
+
+/// Minimum chrY to autosome coverage ratio of a sample with a Y chromosome;
+/// much of chrY is unassembled or unmappable, so the ratio is only used to
+/// detect its presence
+const MIN_Y_COVERAGE_RATIO: f64 = 0.1;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Karyotype {
+    name: String,
     ploidy: PloidyInfo,
+    intervals: Vec<PloidyInterval>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 enum PloidyInfo {
-    PresetXX,
-    PresetXY,
+    SexChroms { x: Ploidy, y: Ploidy },
     Custom(HashMap<String, Ploidy>),
 }
 
+/// Ploidy of an interval that overrides the ploidy of its chromosome
+#[derive(Debug, PartialEq, Clone)]
+struct PloidyInterval {
+    contig: String,
+    start: u32,
+    end: u32,
+    ploidy: Ploidy,
+}
+
 impl Karyotype {
-    pub fn new(encoding: &str) -> Result<Self, String> {
-        let ploidy = match encoding {
-            "XX" => PloidyInfo::PresetXX,
-            "XY" => PloidyInfo::PresetXY,
+    /// Creates the karyotype of a sample from a preset, a file, or, if set to
+    /// `auto`, from the coverage of sex chromosomes in the reads of the sample.
+    /// Presets can be suffixed with a genome build to account for the
+    /// pseudoautosomal regions.
+    pub fn new(encoding: &str, sample: &Sample, genome_path: &Path) -> Result<Self, String> {
+        let (preset, pars) = match encoding.split_once(':') {
+            Some((preset @ ("XX" | "XY" | "auto"), build)) => match build {
+                "hg38" => (preset, Some(&PARS_HG38)),
+                "hg19" => (preset, Some(&PARS_HG19)),
+                _ => return Err(format!("Unknown genome build in karyotype: {}", build)),
+            },
+            _ => (encoding, None),
+        };
+        let (x, y) = match preset {
+            "XX" => (Ploidy::TWO, Ploidy::ZERO),
+            "XY" => (Ploidy::ONE, Ploidy::ONE),
+            "auto" => infer_sex_chroms(sample, genome_path)?,
             _ => return Self::from_file(encoding),
         };
-        Ok(Self { ploidy })
+
+        let intervals = match pars {
+            Some(pars) => get_par_intervals(pars, Ploidy::capped(x.count() + y.count())),
+            None => Vec::new(),
+        };
+        Ok(Self {
+            name: get_karyotype_name(x, y),
+            ploidy: PloidyInfo::SexChroms { x, y },
+            intervals,
+        })
     }
 
     #[cfg(test)]
     pub fn new_for_test(ploidies: HashMap<String, Ploidy>) -> Self {
         Self {
+            name: "test".to_string(),
             ploidy: PloidyInfo::Custom(ploidies),
+            intervals: Vec::new(),
         }
     }
 
+    /// Sex chromosome karyotype (such as `XY`) or the path of the karyotype file
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Parses a file whose lines either give the ploidy of a whole chromosome
+    /// (`chrom ploidy`) or of a BED-style interval (`chrom start end ploidy`)
     fn from_file(path: &str) -> Result<Self, String> {
         let contents = fs::read_to_string(path).map_err(|e| format!("File {}: {}", path, e))?;
 
-        let ploidies = contents
-            .lines()
-            .enumerate()
-            .map(|(line_number, line)| {
-                let mut parts = line.split_whitespace();
-                let chrom = parts
-                    .next()
-                    .ok_or(format!("Missing chromosome at line {}", line_number).to_string())?;
-                let ploidy_str = parts
-                    .next()
-                    .ok_or(format!("Missing ploidy at line {}", line_number).to_string())?;
-                let ploidy = ploidy_str.parse().map_err(|e: String| {
-                    format!("Invalid ploidy at line {}, {}", line_number, e)
-                })?;
-                Ok((chrom.to_string(), ploidy))
-            })
-            .collect::<Result<HashMap<_, _>, String>>()?;
+        let mut ploidies = HashMap::new();
+        let mut intervals = Vec::new();
+        for (line_number, line) in contents.lines().enumerate() {
+            let parts = line.split_whitespace().collect::<Vec<_>>();
+            let parse_ploidy = |ploidy_str: &str| {
+                ploidy_str
+                    .parse::<Ploidy>()
+                    .map_err(|e: String| format!("Invalid ploidy at line {}, {}", line_number, e))
+            };
+            match parts[..] {
+                [] => continue,
+                [_] => return Err(format!("Missing ploidy at line {}", line_number)),
+                [chrom, ploidy_str] => {
+                    ploidies.insert(chrom.to_string(), parse_ploidy(ploidy_str)?);
+                }
+                [chrom, start, end, ploidy_str] => {
+                    let parse_pos = |pos: &str| {
+                        pos.parse::<u32>()
+                            .map_err(|_| format!("Invalid interval at line {}", line_number))
+                    };
+                    let (start, end) = (parse_pos(start)?, parse_pos(end)?);
+                    if start >= end {
+                        return Err(format!("Invalid interval at line {}", line_number));
+                    }
+                    intervals.push(PloidyInterval {
+                        contig: chrom.to_string(),
+                        start,
+                        end,
+                        ploidy: parse_ploidy(ploidy_str)?,
+                    });
+                }
+                _ => {
+                    return Err(format!(
+                        "Unexpected number of fields at line {}",
+                        line_number
+                    ))
+                }
+            }
+        }
 
         Ok(Self {
+            name: path.to_string(),
             ploidy: PloidyInfo::Custom(ploidies),
+            intervals,
         })
     }
 
-    pub fn get_ploidy(&self, chrom: &str) -> Result<Ploidy, String> {
+    /// Ploidy of the interval overlapping the region the most, provided that it
+    /// covers at least half of the region, and otherwise of its chromosome
+    pub fn get_ploidy(&self, region: &GenomicRegion) -> Result<Ploidy, String> {
+        let best_interval = self
+            .intervals
+            .iter()
+            .filter(|interval| interval.contig == region.contig)
+            .map(|interval| {
+                let overlap = region
+                    .end
+                    .min(interval.end)
+                    .saturating_sub(region.start.max(interval.start));
+                (overlap, interval)
+            })
+            .filter(|(overlap, _)| *overlap > 0 && 2 * overlap >= region.end - region.start)
+            .max_by_key(|(overlap, _)| *overlap);
+        if let Some((_, interval)) = best_interval {
+            return Ok(interval.ploidy);
+        }
+
+        let chrom = region.contig.as_str();
         match &self.ploidy {
-            PloidyInfo::PresetXX => match chrom {
-                "Y" | "chrY" => Ok(Ploidy::Zero),
-                _ => Ok(Ploidy::Two),
-            },
-            PloidyInfo::PresetXY => match chrom {
-                "X" | "chrX" | "Y" | "chrY" => Ok(Ploidy::One),
-                _ => Ok(Ploidy::Two),
+            PloidyInfo::SexChroms { x, y } => match chrom {
+                "X" | "chrX" => Ok(*x),
+                "Y" | "chrY" => Ok(*y),
+                _ => Ok(Ploidy::TWO),
             },
             PloidyInfo::Custom(ploidies) => ploidies
                 .get(chrom)
@@ -75,22 +186,277 @@ impl Karyotype {
     }
 }
 
+/// PARs carry one copy per sex chromosome; reads from them are expected to
+/// align to chrX, so the chrY copies are not genotyped
+fn get_par_intervals(pars: &[(&str, u32, u32)], par_ploidy: Ploidy) -> Vec<PloidyInterval> {
+    let mut intervals = Vec::new();
+    for (chrom, start, end) in pars {
+        let ploidy = if *chrom == "X" {
+            par_ploidy
+        } else {
+            Ploidy::ZERO
+        };
+        for contig in [chrom.to_string(), format!("chr{}", chrom)] {
+            intervals.push(PloidyInterval {
+                contig,
+                start: *start,
+                end: *end,
+                ploidy,
+            });
+        }
+    }
+    intervals
+}
+
+fn get_karyotype_name(x: Ploidy, y: Ploidy) -> String {
+    if x == Ploidy::ONE && y == Ploidy::ZERO {
+        return "X0".to_string();
+    }
+    "X".repeat(x.count()) + &"Y".repeat(y.count())
+}
+
+/// Infers the number of copies of chrX and chrY of a sample
+fn infer_sex_chroms(sample: &Sample, genome_path: &Path) -> Result<(Ploidy, Ploidy), String> {
+    let (x_ratio, y_ratio) = get_sex_chrom_coverage(sample, genome_path)?;
+    let (x, y) = get_sex_chrom_counts(x_ratio, y_ratio);
+    log::info!(
+        "Inferred karyotype {} for {} (chrX and chrY to autosome coverage ratios {:.2} and {:.2})",
+        get_karyotype_name(x, y),
+        sample.name,
+        x_ratio,
+        y_ratio
+    );
+    Ok((x, y))
+}
+
+fn get_sex_chrom_counts(x_ratio: f64, y_ratio: f64) -> (Ploidy, Ploidy) {
+    let x = Ploidy::capped(((2.0 * x_ratio).round() as usize).max(1));
+    let y = if y_ratio < MIN_Y_COVERAGE_RATIO {
+        Ploidy::ZERO
+    } else {
+        Ploidy::ONE
+    };
+    (x, y)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ChromClass {
+    Autosome,
+    X,
+    Y,
+    /// Chromosomes that are not numbered, such as unplaced contigs or
+    /// chromosomes of genomes with other naming schemes
+    Other,
+}
+
+/// Classifies chromosomes by name; mitochondrial chromosomes are skipped
+fn get_chrom_class(chrom: &str) -> Option<ChromClass> {
+    let name = chrom.strip_prefix("chr").unwrap_or(chrom);
+    match name {
+        "X" => Some(ChromClass::X),
+        "Y" => Some(ChromClass::Y),
+        "M" | "MT" => None,
+        _ if !name.is_empty() && name.chars().all(|c| c.is_ascii_digit()) => {
+            Some(ChromClass::Autosome)
+        }
+        _ => Some(ChromClass::Other),
+    }
+}
+
+/// Computes the ratios of read coverage of chrX and chrY to that of autosomes,
+/// which are the numbered chromosomes or, if the genome has none, all other
+/// chromosomes besides the mitochondrial one. Read counts of BAM files come
+/// from index statistics unless reads of the sample have to be picked out by
+/// read group; otherwise, and for CRAM files whose indexes lack statistics,
+/// reads are counted in windows sampled along each chromosome.
+fn get_sex_chrom_coverage(sample: &Sample, genome_path: &Path) -> Result<(f64, f64), String> {
+    let reads_path = &sample.reads_path;
+    let error = |msg: String| {
+        format!(
+            "Unable to infer karyotype of {} from {}: {}",
+            sample.name,
+            reads_path.display(),
+            msg
+        )
+    };
+    let mut reader = crate::open_reads(reads_path, genome_path)?;
+    let header = reader.header().clone();
+    let is_bam = reads_path.extension().is_some_and(|ext| ext == "bam");
+    let index_stats = if is_bam && sample.read_groups.is_none() {
+        Some(reader.index_stats().map_err(|e| error(e.to_string()))?)
+    } else {
+        None
+    };
+
+    // Reads and bases covered for each class of chromosomes
+    let mut counts = [(0, 0); 4];
+    for tid in 0..header.target_count() {
+        let chrom = String::from_utf8_lossy(header.tid2name(tid));
+        let Some(class) = get_chrom_class(&chrom) else {
+            continue;
+        };
+        let chrom_len = header.target_len(tid).unwrap_or(0);
+        let (num_reads, num_bases) = match &index_stats {
+            Some(index_stats) => (index_stats[tid as usize].2, chrom_len),
+            None => {
+                let read_groups = sample.read_groups.as_ref();
+                count_sampled_reads(&mut reader, tid, chrom_len, read_groups).map_err(error)?
+            }
+        };
+        counts[class as usize].0 += num_reads;
+        counts[class as usize].1 += num_bases;
+    }
+
+    let mut autosome_counts = counts[ChromClass::Autosome as usize];
+    if autosome_counts.1 == 0 {
+        log::warn!(
+            "No numbered chromosomes found in {}; using all chromosomes other than chrX, chrY, and chrM as autosomes",
+            reads_path.display()
+        );
+        autosome_counts = counts[ChromClass::Other as usize];
+    }
+    let get_rate = |(num_reads, num_bases): (u64, u64)| match num_bases {
+        0 => None,
+        _ => Some(num_reads as f64 / num_bases as f64),
+    };
+    let autosome_rate = get_rate(autosome_counts)
+        .filter(|rate| *rate > 0.0)
+        .ok_or_else(|| error("no reads on autosomes".to_string()))?;
+    let x_rate = get_rate(counts[ChromClass::X as usize])
+        .ok_or_else(|| error("chrX is missing from the header".to_string()))?;
+    let y_rate = get_rate(counts[ChromClass::Y as usize]).unwrap_or(0.0);
+    Ok((x_rate / autosome_rate, y_rate / autosome_rate))
+}
+
+fn count_sampled_reads(
+    reader: &mut bam::IndexedReader,
+    tid: u32,
+    chrom_len: u64,
+    read_groups: Option<&HashSet<String>>,
+) -> Result<(u64, u64), String> {
+    let window_len = SAMPLED_WINDOW_LEN.min(chrom_len);
+    let mut num_reads = 0;
+    let mut num_bases = 0;
+    for index in 0..NUM_SAMPLED_WINDOWS {
+        let center = (2 * index + 1) * chrom_len / (2 * NUM_SAMPLED_WINDOWS);
+        let start = center
+            .saturating_sub(window_len / 2)
+            .min(chrom_len - window_len);
+        let end = start + window_len;
+        reader
+            .fetch((tid, start as i64, end as i64))
+            .map_err(|e| e.to_string())?;
+        // Reads are counted in the window where they start so that reads
+        // overlapping neighboring windows are not counted twice
+        for record in reader.records() {
+            let record = record.map_err(|e| e.to_string())?;
+            let is_counted = !record.is_secondary()
+                && !record.is_supplementary()
+                && !record.is_duplicate()
+                && record.mapq() >= MIN_SAMPLED_MAPQ
+                && record.pos() as u64 >= start
+                && read_groups.is_none_or(|read_groups| {
+                    matches!(record.aux(b"RG"), Ok(Aux::String(rg)) if read_groups.contains(rg))
+                });
+            if is_counted {
+                num_reads += 1;
+            }
+        }
+        num_bases += window_len;
+    }
+    Ok((num_reads, num_bases))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::genotype::Ploidy;
     use std::collections::HashMap;
 
+    fn region(encoding: &str) -> GenomicRegion {
+        GenomicRegion::new(encoding).unwrap()
+    }
+
     #[test]
     fn test_karyotype_custom() {
         let mut ploidies = HashMap::new();
-        ploidies.insert("chr1".to_string(), Ploidy::Two);
-        ploidies.insert("chr2".to_string(), Ploidy::One);
+        ploidies.insert("chr1".to_string(), Ploidy::TWO);
+        ploidies.insert("chr2".to_string(), Ploidy::ONE);
 
         let karyotype = Karyotype::new_for_test(ploidies);
 
-        assert_eq!(karyotype.get_ploidy("chr1").unwrap(), Ploidy::Two);
-        assert_eq!(karyotype.get_ploidy("chr2").unwrap(), Ploidy::One);
-        assert!(karyotype.get_ploidy("chrX").is_err());
+        assert_eq!(
+            karyotype.get_ploidy(&region("chr1:0-10")).unwrap(),
+            Ploidy::TWO
+        );
+        assert_eq!(
+            karyotype.get_ploidy(&region("chr2:0-10")).unwrap(),
+            Ploidy::ONE
+        );
+        assert!(karyotype.get_ploidy(&region("chrX:0-10")).is_err());
+    }
+
+    #[test]
+    fn test_karyotype_par_preset() {
+        let sample = Sample {
+            name: "sample".to_string(),
+            reads_path: Default::default(),
+            read_groups: None,
+        };
+        let karyotype = Karyotype::new("XY:hg38", &sample, Path::new("")).unwrap();
+
+        let par1 = region("chrX:100000-100050");
+        assert_eq!(karyotype.get_ploidy(&par1).unwrap(), Ploidy::TWO);
+        assert_eq!(
+            karyotype
+                .get_ploidy(&region("chrX:5000000-5000050"))
+                .unwrap(),
+            Ploidy::ONE
+        );
+        assert_eq!(
+            karyotype.get_ploidy(&region("Y:100000-100050")).unwrap(),
+            Ploidy::ZERO
+        );
+        assert_eq!(
+            karyotype
+                .get_ploidy(&region("chrY:3000000-3000050"))
+                .unwrap(),
+            Ploidy::ONE
+        );
+        // A repeat straddling the PAR boundary takes the ploidy of the larger part
+        assert_eq!(
+            karyotype
+                .get_ploidy(&region("chrX:2781469-2781499"))
+                .unwrap(),
+            Ploidy::ONE
+        );
+        assert_eq!(
+            karyotype.get_ploidy(&region("chr1:100000-100050")).unwrap(),
+            Ploidy::TWO
+        );
+    }
+
+    #[test]
+    fn test_sex_chrom_inference() {
+        let infer = |x_ratio, y_ratio| {
+            let (x, y) = get_sex_chrom_counts(x_ratio, y_ratio);
+            get_karyotype_name(x, y)
+        };
+        assert_eq!(infer(0.98, 0.01), "XX");
+        assert_eq!(infer(0.51, 0.22), "XY");
+        assert_eq!(infer(0.49, 0.02), "X0");
+        assert_eq!(infer(1.03, 0.19), "XXY");
+        assert_eq!(get_chrom_class("chr21"), Some(ChromClass::Autosome));
+        assert_eq!(get_chrom_class("X"), Some(ChromClass::X));
+        assert_eq!(get_chrom_class("chrUn_KI270302v1"), Some(ChromClass::Other));
+        assert_eq!(get_chrom_class("chrM"), None);
+    }
+
+    #[test]
+    fn test_ploidy_parsing() {
+        assert_eq!("4".parse::<Ploidy>().unwrap().count(), 4);
+        assert_eq!("0".parse::<Ploidy>().unwrap(), Ploidy::ZERO);
+        assert!("11".parse::<Ploidy>().is_err());
+        assert!("two".parse::<Ploidy>().is_err());
     }
 }
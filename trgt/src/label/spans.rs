@@ -19,3 +19,18 @@ pub struct Annotation {
     pub motif_counts: Vec<usize>,
     pub purity: f64,
 }
+
+impl Annotation {
+    /// Encodes motif spans as `motif(start-end)` separated by `_` or `.` if
+    /// the sequence is unlabeled
+    pub fn encode_spans(&self) -> String {
+        match &self.labels {
+            None => ".".to_string(),
+            Some(spans) => spans
+                .iter()
+                .map(|s| format!("{}({}-{})", s.motif_index, s.start, s.end))
+                .collect::<Vec<_>>()
+                .join("_"),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{reads::HiFiRead, workflows::Params};
+use crate::{locus::Locus, reads::HiFiRead, workflows::Params};
 use bio::alignment::{pairwise::*, AlignmentOperation};
 use itertools::Itertools;
 use std::str;
@@ -15,6 +15,27 @@ pub struct TrgtScoring {
 
 type Span = (usize, usize);
 
+/// Maximum length of the sequences aligned to place the repeat between flank
+/// offsets; constant offsets are used for longer sequences
+const MAX_OFFSET_ALN_LEN: usize = 1000;
+
+/// Placement of the repeat within a read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrSpan {
+    /// Both flanks were found around the repeat
+    Spanning(Span),
+    /// Only the left flank was found; the repeat starts at the given position
+    /// and extends past the end of the read
+    LeftFlanking(usize),
+    /// Only the right flank was found; the repeat ends at the given position
+    /// and extends past the start of the read
+    RightFlanking(usize),
+    /// The left flank was found after the right flank
+    Discordant,
+    /// Neither flank was found
+    Unplaced,
+}
+
 fn find_spans<F>(
     aligner: &mut banded::Aligner<F>,
     piece: &str,
@@ -47,9 +68,55 @@ where
         .collect()
 }
 
-pub fn find_tr_spans(lf: &str, rf: &str, reads: &[HiFiRead], params: &Params) -> Vec<Option<Span>> {
-    let lf_piece = &lf[lf.len() - params.search_flank_len..];
-    let rf_piece = &rf[..params.search_flank_len];
+/// Places the repeat in the part of a read between the flank pieces by
+/// aligning it to the reference bases between the pieces. Gaps are placed
+/// leftmost, so indels in the offsets stay in the offsets while bases inserted
+/// where the repeat starts are attributed to the repeat.
+fn align_offsets<F>(aligner: &mut Aligner<F>, locus: &Locus, seq: &[u8]) -> Span
+where
+    F: MatchFunc,
+{
+    let (lf_offset, rf_offset) = locus.flank_offsets;
+    let (lf, rf) = (locus.left_flank.as_bytes(), locus.right_flank.as_bytes());
+    let reference = [
+        &lf[lf.len() - lf_offset..],
+        locus.tr.as_bytes(),
+        &rf[..rf_offset],
+    ]
+    .concat();
+    let (tr_start, tr_end) = (lf_offset, lf_offset + locus.tr.len());
+
+    let align = aligner.global(&reference, seq);
+    let (mut ref_pos, mut seq_pos) = (0, 0);
+    let (mut start, mut end) = (None, None);
+    for op in &align.operations {
+        if ref_pos == tr_start {
+            start = start.or(Some(seq_pos));
+        }
+        if ref_pos == tr_end {
+            end = end.or(Some(seq_pos));
+        }
+        match op {
+            AlignmentOperation::Match | AlignmentOperation::Subst => {
+                ref_pos += 1;
+                seq_pos += 1;
+            }
+            AlignmentOperation::Ins => ref_pos += 1,
+            AlignmentOperation::Del => seq_pos += 1,
+            AlignmentOperation::Xclip(_) | AlignmentOperation::Yclip(_) => {}
+        }
+    }
+    let start = start.unwrap_or(seq_pos);
+    (start, end.unwrap_or(seq_pos).max(start))
+}
+
+/// Locates the repeat in reads using the parts of the flanks chosen for the
+/// locus; bases between these parts and the repeat are aligned to the
+/// reference in reads spanning the repeat and otherwise assumed to be present
+/// in reads as in the reference
+pub fn find_tr_spans(locus: &Locus, reads: &[HiFiRead], params: &Params) -> Vec<TrSpan> {
+    let (lf_offset, rf_offset) = locus.flank_offsets;
+    let (lf_piece, rf_piece) = locus.get_flank_pieces(params.search_flank_len);
 
     let scoring = Scoring {
         match_fn: |a: u8, b: u8| {
@@ -68,9 +135,11 @@ pub fn find_tr_spans(lf: &str, rf: &str, reads: &[HiFiRead], params: &Params) ->
         yclip_suffix: 0,
     };
 
+    // Unaligned and nanopore reads can be far longer than HiFi reads
+    let max_read_len = reads.iter().map(|r| r.bases.len()).max().unwrap_or(0);
     let mut aligner = banded::Aligner::with_capacity_and_scoring(
         params.search_flank_len + 10, // global length
-        20000,                        // local length: maximum HiFi read length
+        max_read_len,                 // local length
         scoring,
         params.aln_scoring.kmer_len,
         params.aln_scoring.bandwidth,
@@ -84,24 +153,120 @@ pub fn find_tr_spans(lf: &str, rf: &str, reads: &[HiFiRead], params: &Params) ->
     let lf_spans = find_spans(&mut aligner, lf_piece, &seqs, params);
     let rf_spans = find_spans(&mut aligner, rf_piece, &seqs, params);
 
+    // Offsets are low-complexity, so their length changes are scored as
+    // indels rather than as runs of mismatches
+    let offset_scoring = Scoring::from_scores(-1, -1, 1, -2);
+    let mut offset_aligner = Aligner::with_scoring(offset_scoring);
+    let ref_len = lf_offset + locus.tr.len() + rf_offset;
+
     lf_spans
         .iter()
         .zip(rf_spans.iter())
-        .map(|(lf_span, rf_span)| match (lf_span, rf_span) {
-            (None, None) => None,      // No left or right span
-            (Some(_lf), None) => None, // Left flanking
-            (None, Some(_rf)) => None, // Right flanking
-            (Some(lf), Some(rf)) => {
-                if lf.1 <= rf.0 {
-                    Some((lf.1, rf.0))
-                } else {
-                    None // Discordant flanks
+        .zip(&seqs)
+        .map(|((lf_span, rf_span), seq)| {
+            let mut tr_start = lf_span.map(|lf| (lf.1 + lf_offset).min(seq.len()));
+            let mut tr_end = rf_span.map(|rf| rf.0.saturating_sub(rf_offset));
+            // Reads with indels in the offsets need the offsets to be aligned
+            if let (Some(lf), Some(rf)) = (lf_span, rf_span) {
+                let is_short = ref_len.max(rf.0.saturating_sub(lf.1)) <= MAX_OFFSET_ALN_LEN;
+                if lf_offset + rf_offset > 0 && lf.1 <= rf.0 && is_short {
+                    let between = &seq.as_bytes()[lf.1..rf.0];
+                    let (start, end) = align_offsets(&mut offset_aligner, locus, between);
+                    tr_start = Some(lf.1 + start);
+                    tr_end = Some(lf.1 + end);
+                }
+            }
+            match (tr_start, tr_end) {
+                (None, None) => TrSpan::Unplaced,
+                (Some(start), None) => TrSpan::LeftFlanking(start),
+                (None, Some(end)) => TrSpan::RightFlanking(end),
+                (Some(start), Some(end)) => {
+                    if start <= end && lf_span.unwrap().1 <= rf_span.unwrap().0 {
+                        TrSpan::Spanning((start, end))
+                    } else {
+                        TrSpan::Discordant
+                    }
                 }
             }
         })
         .collect()
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genotype::{LenErrors, Ploidy};
+    use crate::locus::Genotyper;
+    use crate::utils::GenomicRegion;
+
+    #[test]
+    fn size_repeat_in_reads_with_indels_in_flank_offsets() {
+        let mut state = 11u64;
+        let mut make_seq = |len: usize| {
+            (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    b"ACGT"[(state >> 33) as usize % 4] as char
+                })
+                .collect::<String>()
+        };
+        let (left, right) = (make_seq(150), make_seq(150));
+        let locus = Locus {
+            id: "TR".to_string(),
+            left_flank: format!("{}{}", left, "T".repeat(20)),
+            tr: "CAG".repeat(10),
+            right_flank: format!("{}{}", "A".repeat(20), right),
+            flank_offsets: (20, 20),
+            region: GenomicRegion::new("chrA:170-200").unwrap(),
+            motifs: vec!["CAG".to_string()],
+            struc: "(CAG)n".to_string(),
+            ploidies: vec![Ploidy::TWO],
+            genotyper: Genotyper::Size,
+        };
+        let params = Params {
+            search_flank_len: 100,
+            min_read_qual: 0.0,
+            use_base_quals: false,
+            max_depth: 250,
+            aln_scoring: crate::preset::Preset::Hifi.aln_scoring(),
+            min_flank_id_frac: 0.7,
+            len_errors: LenErrors::NONE,
+            annotate_reads: false,
+            cpg_profiles: false,
+            stitch_supplementary: false,
+        };
+
+        let make_read = |lf_offset: usize, tr: usize, rf_offset: usize| {
+            let seq = format!(
+                "{}{}{}{}{}",
+                left,
+                "T".repeat(lf_offset),
+                "CAG".repeat(tr),
+                "A".repeat(rf_offset),
+                right
+            );
+            HiFiRead::from_seq("read".to_string(), seq.into_bytes(), false)
+        };
+        let reads = vec![
+            make_read(20, 10, 20),
+            make_read(23, 10, 18),
+            make_read(17, 10, 24),
+            make_read(20, 12, 20),
+        ];
+
+        let spans = find_tr_spans(&locus, &reads, &params);
+        assert_eq!(
+            spans,
+            [
+                TrSpan::Spanning((170, 200)),
+                TrSpan::Spanning((173, 203)),
+                TrSpan::Spanning((167, 197)),
+                TrSpan::Spanning((170, 206)),
+            ]
+        );
+    }
+}
+
 /*
 
 #[cfg(test)]
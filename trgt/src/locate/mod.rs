@@ -1,6 +1,3 @@
 mod locate;
-pub use locate::find_tr_spans;
 pub use locate::TrgtScoring;
-
-mod consensus;
-pub use consensus::get_consensus;
+pub use locate::{find_tr_spans, TrSpan};
@@ -2,10 +2,22 @@ use crate::faidx;
 use crate::genotype::Ploidy;
 use crate::karyotype::Karyotype;
 use crate::utils::GenomicRegion;
-use std::collections::HashMap;
+use crate::workflows::CLIP_RADIUS;
+use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
 use std::io::{BufRead, BufReader, Read as ioRead};
 use std::str::FromStr;
 
+/// Windows of the flank in which fewer than this fraction of k-mers are
+/// distinct are considered low-complexity
+const COMPLEXITY_WINDOW_LEN: usize = 24;
+const COMPLEXITY_KMER_LEN: usize = 4;
+const MIN_KMER_DIVERSITY: f64 = 0.5;
+
+/// Maximum fraction of low-complexity bases in the part of a flank used to
+/// locate the repeat
+const MAX_MASKED_FLANK_FRAC: f64 = 0.5;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Genotyper {
     Size,
@@ -29,10 +41,14 @@ pub struct Locus {
     pub left_flank: String,
     pub tr: String,
     pub right_flank: String,
+    /// Number of bases separating the repeat from the part of each flank used
+    /// to locate it in reads; these bases are included in the flanks
+    pub flank_offsets: (usize, usize),
     pub region: GenomicRegion,
     pub motifs: Vec<String>,
     pub struc: String,
-    pub ploidy: Ploidy,
+    /// Number of copies of the repeat in each sample
+    pub ploidies: Vec<Ploidy>,
     pub genotyper: Genotyper,
 }
 
@@ -40,99 +56,236 @@ impl Locus {
     pub fn new(
         genome_reader: &faidx::Reader,
         chrom_lookup: &HashMap<String, u32>,
-        line: &str,
+        entry: CatalogEntry,
         flank_len: usize,
-        karyotype: &Karyotype,
+        fixed_flanks: bool,
+        karyotypes: &[Karyotype],
         genotyper: Genotyper,
     ) -> Result<Self, String> {
-        const EXPECTED_FIELD_COUNT: usize = 4;
-        let split_line: Vec<&str> = line.split_whitespace().collect();
-        if split_line.len() != EXPECTED_FIELD_COUNT {
-            return Err(format!(
-                "Expected {} fields in the format 'chrom start end info', found {}: {}",
-                EXPECTED_FIELD_COUNT,
-                split_line.len(),
-                line
-            ));
-        }
+        let CatalogEntry {
+            id,
+            region,
+            motifs,
+            struc,
+            ..
+        } = entry;
 
-        let (chrom, start, end, info_fields) = match &split_line[..] {
-            [chrom, start, end, info_fields] => (*chrom, *start, *end, *info_fields),
-            _ => unreachable!(),
+        check_region_bounds(&region, flank_len, chrom_lookup)?;
+
+        let ploidies = karyotypes
+            .iter()
+            .map(|karyotype| karyotype.get_ploidy(&region))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let max_shift = if fixed_flanks {
+            0
+        } else {
+            CLIP_RADIUS.saturating_sub(flank_len)
         };
+        let chrom_len = chrom_lookup[&region.contig] as usize;
+        let (left_flank, tr, right_flank) =
+            get_tr_and_flanks(genome_reader, &region, flank_len + max_shift, chrom_len)?;
+        let (left_flank, left_offset) = choose_flank(&left_flank, flank_len, true);
+        let (right_flank, right_offset) = choose_flank(&right_flank, flank_len, false);
 
-        let region = GenomicRegion::new(&format!("{}:{}-{}", chrom, start, end))?;
+        Ok(Locus {
+            id,
+            left_flank,
+            tr,
+            right_flank,
+            flank_offsets: (left_offset, right_offset),
+            region,
+            motifs,
+            struc,
+            ploidies,
+            genotyper,
+        })
+    }
 
-        check_region_bounds(&region, flank_len, chrom_lookup)?;
+    /// Parts of the left and right flanks used to locate the repeat
+    pub fn get_flank_pieces(&self, flank_len: usize) -> (&str, &str) {
+        let (lf, rf) = (&self.left_flank, &self.right_flank);
+        let (lf_offset, rf_offset) = self.flank_offsets;
+        (
+            &lf[lf.len() - lf_offset - flank_len..lf.len() - lf_offset],
+            &rf[rf_offset..rf_offset + flank_len],
+        )
+    }
 
-        let ploidy = karyotype.get_ploidy(chrom)?;
+    /// Reference coordinates (0-based, half-open) of the parts of the left and
+    /// right flanks used to locate the repeat
+    pub fn get_flank_bounds(&self) -> ((u32, u32), (u32, u32)) {
+        let (start, end) = (self.region.start, self.region.end);
+        let left = (
+            start - self.left_flank.len() as u32,
+            start - self.flank_offsets.0 as u32,
+        );
+        let right = (
+            end + self.flank_offsets.1 as u32,
+            end + self.right_flank.len() as u32,
+        );
+        (left, right)
+    }
+}
 
-        let fields = decode_fields(info_fields)?;
+/// Catalog line whose repeat and flank sequences have not been fetched yet
+#[derive(Debug)]
+pub struct CatalogEntry {
+    pub line_number: usize,
+    pub id: String,
+    pub region: GenomicRegion,
+    motifs: Vec<String>,
+    struc: String,
+}
 
-        let get_field = |key: &str| {
+impl CatalogEntry {
+    fn new(line_number: usize, line: String) -> Result<Self, String> {
+        let (chrom, start, end, info_fields) = split_line(&line)?;
+        let region = GenomicRegion::new(&format!("{}:{}-{}", chrom, start, end))?;
+        let mut fields = decode_fields(info_fields)?;
+        let mut get_field = |key: &str| {
             fields
-                .get(key)
+                .remove(key)
                 .ok_or_else(|| format!("{} field missing", key))
-                .map(|s| s.to_string())
         };
-
         let id = get_field("ID")?;
         let motifs = get_field("MOTIFS")?
             .split(',')
             .map(|s| s.to_string())
             .collect();
         let struc = get_field("STRUC")?;
-
-        let (left_flank, tr, right_flank) = get_tr_and_flanks(genome_reader, &region, flank_len)?;
-
-        Ok(Locus {
+        Ok(CatalogEntry {
+            line_number,
             id,
-            left_flank,
-            tr,
-            right_flank,
             region,
             motifs,
             struc,
-            ploidy,
-            genotyper,
         })
     }
 }
 
-pub fn get_loci(
+/// Restricts the catalog to repeats overlapping target regions and/or having
+/// the given IDs; an empty list of regions or IDs places no restriction
+#[derive(Debug, Default)]
+pub struct LocusFilter {
+    regions: Vec<GenomicRegion>,
+    ids: HashSet<String>,
+}
+
+impl LocusFilter {
+    /// Regions are expected in BED coordinates (0-based, half-open)
+    pub fn new(regions: Vec<GenomicRegion>, ids: HashSet<String>) -> Self {
+        LocusFilter { regions, ids }
+    }
+
+    fn is_match(&self, entry: &CatalogEntry) -> bool {
+        let overlaps = |region: &GenomicRegion| {
+            region.contig == entry.region.contig
+                && region.start < entry.region.end
+                && entry.region.start < region.end
+        };
+        let in_regions = self.regions.is_empty() || self.regions.iter().any(overlaps);
+        in_regions && self.is_requested(entry)
+    }
+
+    fn is_requested(&self, entry: &CatalogEntry) -> bool {
+        self.ids.is_empty() || self.ids.contains(&entry.id)
+    }
+}
+
+/// Reads the catalog entries that pass the filter without fetching sequences,
+/// along with the number of requested IDs absent from the catalog. Entries
+/// are collected rather than streamed because they have to be sorted in the
+/// contig order of the reads before any output is written; they hold only the
+/// parsed fields of each line, which are small next to the sequences that
+/// loci hold while in progress.
+pub fn read_catalog(
     catalog_reader: BufReader<Box<dyn ioRead>>,
+    filter: &LocusFilter,
+) -> Result<(Vec<CatalogEntry>, usize), String> {
+    let mut entries = Vec::new();
+    let mut found_ids = HashSet::new();
+    for (line_number, result_line) in catalog_reader.lines().enumerate() {
+        let error = |e: String| format!("Error at BED line {}: {}", line_number + 1, e);
+        let line = result_line.map_err(|e| error(e.to_string()))?;
+        let entry = CatalogEntry::new(line_number, line).map_err(error)?;
+        if !filter.ids.is_empty() && filter.is_requested(&entry) {
+            found_ids.insert(entry.id.clone());
+        }
+        if filter.is_match(&entry) {
+            entries.push(entry);
+        }
+    }
+    Ok((entries, filter.ids.len() - found_ids.len()))
+}
+
+/// Runs the checks of `Locus::new` that do not need sequences, so that catalog
+/// errors are reported before any repeat is analyzed
+pub fn check_catalog(
+    entries: &[CatalogEntry],
     genome_reader: &faidx::Reader,
-    karyotype: Karyotype,
     flank_len: usize,
+    karyotypes: &[Karyotype],
+) -> Result<(), String> {
+    let chrom_lookup = genome_reader.create_chrom_lookup()?;
+    for entry in entries {
+        check_region_bounds(&entry.region, flank_len, &chrom_lookup)
+            .and_then(|_| {
+                karyotypes
+                    .iter()
+                    .try_for_each(|karyotype| karyotype.get_ploidy(&entry.region).map(|_| ()))
+            })
+            .map_err(|e| format!("Error at BED line {}: {}", entry.line_number + 1, e))?;
+    }
+    Ok(())
+}
+
+/// Creates loci lazily so that sequences are only held for loci in progress
+pub fn get_loci<'a>(
+    entries: Vec<CatalogEntry>,
+    genome_reader: &'a faidx::Reader,
+    karyotypes: Vec<Karyotype>,
+    flank_len: usize,
+    fixed_flanks: bool,
     genotyper: Genotyper,
-) -> impl Iterator<Item = Result<Locus, String>> + '_ {
+) -> impl Iterator<Item = Result<Locus, String>> + 'a {
     let chrom_lookup = genome_reader.create_chrom_lookup().unwrap();
 
-    catalog_reader
-        .lines()
-        .enumerate()
-        .filter_map(move |(line_number, result_line)| match result_line {
-            Ok(line) => {
-                match Locus::new(
-                    genome_reader,
-                    &chrom_lookup,
-                    &line,
-                    flank_len,
-                    &karyotype,
-                    genotyper,
-                ) {
-                    Ok(locus) => Some(Ok(locus)),
-                    Err(e) => Some(Err(format!("Error at BED line {}: {}", line_number + 1, e))),
-                }
-            }
-            Err(e) => Some(Err(format!("Error at BED line {}: {}", line_number + 1, e))),
-        })
+    entries.into_iter().map(move |entry| {
+        let line_number = entry.line_number;
+        Locus::new(
+            genome_reader,
+            &chrom_lookup,
+            entry,
+            flank_len,
+            fixed_flanks,
+            &karyotypes,
+            genotyper,
+        )
+        .map_err(|e| format!("Error at BED line {}: {}", line_number + 1, e))
+    })
 }
 
+fn split_line(line: &str) -> Result<(&str, &str, &str, &str), String> {
+    const EXPECTED_FIELD_COUNT: usize = 4;
+    let split_line: Vec<&str> = line.split_whitespace().collect();
+    match &split_line[..] {
+        [chrom, start, end, info_fields] => Ok((*chrom, *start, *end, *info_fields)),
+        _ => Err(format!(
+            "Expected {} fields in the format 'chrom start end info', found {}: {}",
+            EXPECTED_FIELD_COUNT,
+            split_line.len(),
+            line
+        )),
+    }
+}
+
+/// Fetches the repeat and up to `max_flank_len` bases on each side of it
 fn get_tr_and_flanks(
     genome: &faidx::Reader,
     region: &GenomicRegion,
-    flank_len: usize,
+    max_flank_len: usize,
+    chrom_len: usize,
 ) -> Result<(String, String, String), String> {
     let fetch_flank = |start: usize, end: usize| {
         genome
@@ -146,13 +299,123 @@ fn get_tr_and_flanks(
             .map(|seq| seq.to_uppercase())
     };
 
-    let left_flank = fetch_flank(region.start as usize - flank_len, region.start as usize - 1)?;
-    let tr = fetch_flank(region.start as usize, region.end as usize - 1)?;
-    let right_flank = fetch_flank(region.end as usize, region.end as usize + flank_len - 1)?;
+    let (start, end) = (region.start as usize, region.end as usize);
+    let left_flank_len = max_flank_len.min(start);
+    let right_flank_len = max_flank_len.min(chrom_len - end);
+    let left_flank = fetch_flank(start - left_flank_len, start - 1)?;
+    let tr = fetch_flank(start, end - 1)?;
+    let right_flank = fetch_flank(end, end + right_flank_len - 1)?;
 
     Ok((left_flank, tr, right_flank))
 }
 
+/// Picks the part of the flank used to locate the repeat: the closest window
+/// of `flank_len` bases that has no Ns, is not mostly low-complexity, and
+/// whose bases next to the repeat are not low-complexity, as is the case for
+/// adjacent repeats. Returns the flank trimmed to end at the chosen
+/// window and the distance between the window and the repeat.
+fn choose_flank(flank: &str, flank_len: usize, is_left: bool) -> (String, usize) {
+    let mut seq = flank.as_bytes().to_vec();
+    if is_left {
+        seq.reverse();
+    }
+    let max_shift = seq.len() - flank_len;
+    let mask = get_low_complexity_mask(&seq);
+    let get_prefix_counts = |is_counted: &dyn Fn(usize) -> bool| {
+        let counts = (0..seq.len()).scan(0, |count, i| {
+            *count += is_counted(i) as usize;
+            Some(*count)
+        });
+        std::iter::once(0).chain(counts).collect_vec()
+    };
+    let ns = get_prefix_counts(&|i| seq[i] == b'N');
+    let masked = get_prefix_counts(&|i| mask[i]);
+    let scores = (0..=max_shift).map(|shift| {
+        let end = shift + flank_len;
+        let proximal_end = end.min(shift + COMPLEXITY_WINDOW_LEN);
+        let num_ns = ns[end] - ns[shift];
+        let num_proximal_masked = masked[proximal_end] - masked[shift];
+        (
+            num_ns,
+            num_proximal_masked,
+            masked[end] - masked[shift],
+            shift,
+        )
+    });
+
+    let max_masked = (MAX_MASKED_FLANK_FRAC * flank_len as f64) as usize;
+    let shift = match scores
+        .clone()
+        .find(|(num_ns, num_proximal_masked, num_masked, _)| {
+            *num_ns == 0 && *num_proximal_masked == 0 && *num_masked <= max_masked
+        }) {
+        Some((_, _, _, shift)) => shift,
+        None => scores.min().unwrap().3,
+    };
+
+    let len = shift + flank_len;
+    let flank = if is_left {
+        &flank[flank.len() - len..]
+    } else {
+        &flank[..len]
+    };
+    (flank.to_string(), shift)
+}
+
+/// Marks bases that are not ACGT or belong to k-mers repeated in a window with
+/// few distinct k-mers
+fn get_low_complexity_mask(seq: &[u8]) -> Vec<bool> {
+    const K: usize = COMPLEXITY_KMER_LEN;
+    let encode = |base: u8| match base {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    };
+    let mut mask = seq.iter().map(|b| encode(*b).is_none()).collect_vec();
+    if seq.len() < COMPLEXITY_WINDOW_LEN {
+        return mask;
+    }
+
+    // K-mers containing other bases are given a code of their own
+    let kmers = seq
+        .windows(K)
+        .map(|kmer| {
+            kmer.iter()
+                .try_fold(0, |code, base| Some(code * 4 + encode(*base)?))
+                .unwrap_or(1 << (2 * K))
+        })
+        .collect_vec();
+    let num_window_kmers = COMPLEXITY_WINDOW_LEN - K + 1;
+    let min_distinct = (MIN_KMER_DIVERSITY * num_window_kmers as f64) as usize;
+    let mut counts = vec![0; (1 << (2 * K)) + 1];
+    let mut num_distinct = 0;
+    for (index, kmer) in kmers.iter().enumerate() {
+        if counts[*kmer] == 0 {
+            num_distinct += 1;
+        }
+        counts[*kmer] += 1;
+        if index >= num_window_kmers {
+            let dropped = kmers[index - num_window_kmers];
+            counts[dropped] -= 1;
+            if counts[dropped] == 0 {
+                num_distinct -= 1;
+            }
+        }
+        if index + 1 >= num_window_kmers && num_distinct < min_distinct {
+            // Only k-mers repeated within the window are masked, so that
+            // unique sequence next to a repeat is kept
+            for start in index + 1 - num_window_kmers..=index {
+                if counts[kmers[start]] > 1 {
+                    mask[start..start + K].fill(true);
+                }
+            }
+        }
+    }
+    mask
+}
+
 fn decode_fields(info_fields: &str) -> Result<HashMap<&str, String>, String> {
     let mut fields = HashMap::new();
     for field_encoding in info_fields.split(';') {
@@ -211,3 +474,93 @@ fn check_region_bounds(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINE: &str = "chr1\t1000\t1030\tID=TR1;MOTIFS=CAG;STRUC=(CAG)n";
+
+    fn make_entry() -> CatalogEntry {
+        CatalogEntry::new(0, LINE.to_string()).unwrap()
+    }
+
+    fn make_region(contig: &str, start: u32, end: u32) -> GenomicRegion {
+        GenomicRegion {
+            contig: contig.to_string(),
+            start,
+            end,
+        }
+    }
+
+    #[test]
+    fn flanks_avoid_adjacent_repeats_and_ns() {
+        let unique = "ACGTTGCAAGCTTCGATCCGATGACTGGTACAGTCAGGATCTAGCATGCCTAGAAGTCCAT";
+
+        let (flank, offset) = choose_flank(unique, 20, true);
+        assert_eq!((flank.as_str(), offset), (&unique[unique.len() - 20..], 0));
+
+        let right_flank = format!("{}{}", "CA".repeat(30), unique);
+        let (flank, offset) = choose_flank(&right_flank, 20, false);
+        assert_eq!(offset, 60);
+        assert_eq!(flank, right_flank[..80]);
+
+        let left_flank = format!("{}NNN{}", &unique[..40], &unique[40..]);
+        let (_, offset) = choose_flank(&left_flank, 20, true);
+        assert_eq!(offset, 0);
+        let (_, offset) = choose_flank(&left_flank, 30, true);
+        assert_eq!(offset, 24);
+    }
+
+    #[test]
+    fn init_catalog_entry_from_invalid_line_err() {
+        assert!(CatalogEntry::new(0, "chr1\t1000\t1030".to_string()).is_err());
+        assert_eq!(
+            CatalogEntry::new(0, "chr1\t1000\t1030\tMOTIFS=CAG".to_string()).unwrap_err(),
+            "ID field missing"
+        );
+        assert_eq!(
+            CatalogEntry::new(0, "chr1\t1000\t1030\tID=TR1;MOTIFS=CAG".to_string()).unwrap_err(),
+            "STRUC field missing"
+        );
+    }
+
+    #[test]
+    fn empty_filter_matches_all_loci() {
+        assert!(LocusFilter::default().is_match(&make_entry()));
+    }
+
+    #[test]
+    fn filter_loci_by_region() {
+        let ids = HashSet::new();
+        let filter = LocusFilter::new(vec![make_region("chr1", 1029, 2000)], ids.clone());
+        assert!(filter.is_match(&make_entry()));
+        let filter = LocusFilter::new(vec![make_region("chr1", 1030, 2000)], ids.clone());
+        assert!(!filter.is_match(&make_entry()));
+        let filter = LocusFilter::new(vec![make_region("chr2", 0, u32::MAX)], ids);
+        assert!(!filter.is_match(&make_entry()));
+    }
+
+    #[test]
+    fn filter_loci_by_id() {
+        let ids = HashSet::from(["TR1".to_string()]);
+        assert!(LocusFilter::new(Vec::new(), ids).is_match(&make_entry()));
+        let ids = HashSet::from(["TR10".to_string()]);
+        assert!(!LocusFilter::new(Vec::new(), ids).is_match(&make_entry()));
+    }
+
+    #[test]
+    fn count_requested_ids_missing_from_catalog() {
+        let catalog = "chr1\t1000\t1030\tID=TR1;MOTIFS=CAG;STRUC=(CAG)n\n\
+                       chr2\t1000\t1030\tID=TR2;MOTIFS=CAG;STRUC=(CAG)n\n";
+        let reader = || BufReader::new(Box::new(catalog.as_bytes()) as Box<dyn ioRead>);
+        let ids = HashSet::from(["TR1".to_string(), "TR2".to_string(), "TR3".to_string()]);
+        // TR2 is in the catalog but outside of the target regions
+        let filter = LocusFilter::new(vec![make_region("chr1", 0, 2000)], ids);
+        let (entries, num_missing) = read_catalog(reader(), &filter).unwrap();
+        assert_eq!(entries.iter().map(|e| e.id.as_str()).collect_vec(), ["TR1"]);
+        assert_eq!(num_missing, 1);
+        let (entries, num_missing) = read_catalog(reader(), &LocusFilter::default()).unwrap();
+        assert_eq!((entries.len(), num_missing), (2, 0));
+    }
+}
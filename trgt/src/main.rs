@@ -10,59 +10,181 @@
 //!
 //! TRGT can be run like so:
 //! ```bash
-//!  ./trgt --genome reference.fasta \
+//!  ./trgt genotype --genome reference.fasta \
 //!         --repeats repeats.bed \
 //!         --reads read_alignments.bam \
 //!         --output-prefix sample
 //! ```
 
-use cli::{get_cli_params, handle_error_and_exit};
+use checkpoint::{CheckpointWriter, Recovery};
+use cli::{get_cli_params, handle_error_and_exit, Command, GenotypeArgs};
 use flate2::read::GzDecoder;
 use karyotype::Karyotype;
+use locus::{Locus, LocusFilter};
 use rust_htslib::bam;
 use rust_htslib::bam::Read;
 use std::cell::RefCell;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
-use std::io::{BufReader, Read as ioRead};
+use std::io::{BufRead, BufReader, Read as ioRead};
+use std::panic;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, sync_channel};
 use std::sync::Arc;
 use std::{thread, time};
 use threadpool::ThreadPool;
-use workflows::analyze_tr;
-use writers::{BamWriter, VcfWriter};
+use utils::GenomicRegion;
+use workflows::{analyze_tr, analyze_tr_reads, LocusResult};
+use writers::{index_bam, index_vcf, BamWriter, ReadTableWriter, VcfWriter};
+mod checkpoint;
 mod cli;
 mod cluster;
+mod consensus;
 mod faidx;
 mod genotype;
 mod karyotype;
 mod label;
 mod locate;
 mod locus;
+mod merge;
+mod preset;
 mod reads;
+mod recruit;
 mod utils;
 mod workflows;
 mod writers;
 
 pub type Result<T> = std::result::Result<T, String>;
 
+/// Number of loci per thread that can be queued or awaiting output at once
+const MAX_PENDING_LOCI_PER_THREAD: usize = 32;
+
+/// Position of a locus in the catalog, the locus, and its results in each
+/// sample, which are missing for loci that were already written or failed in
+/// every sample
+type AnalyzedLocus = (usize, Locus, Result<Option<Vec<LocusResult>>>);
+
 struct ThreadLocalData {
-    bam: RefCell<Option<bam::IndexedReader>>,
+    bams: RefCell<Vec<bam::IndexedReader>>,
+    genome: RefCell<Option<faidx::Reader>>,
 }
 
 thread_local! {
     static LOCAL: ThreadLocalData = ThreadLocalData {
-        bam: RefCell::new(None),
+        bams: RefCell::new(Vec::new()),
+        genome: RefCell::new(None),
     };
 }
 
+/// Genotypes a locus in all samples using the readers of the current thread,
+/// which are opened on first use. Errors in individual samples are logged and
+/// give empty results; the locus has no results if it failed in every sample.
+fn analyze_locus(
+    locus: &Locus,
+    samples: &[Sample],
+    params: &workflows::Params,
+    genome_path: &Path,
+) -> Result<Option<Vec<LocusResult>>> {
+    LOCAL.with(|local| {
+        let mut bams = local.bams.borrow_mut();
+        if bams.is_empty() {
+            *bams = samples
+                .iter()
+                .map(|sample| open_reads(&sample.reads_path, genome_path))
+                .collect::<Result<_>>()?;
+        }
+        let mut genome = local.genome.borrow_mut();
+        if genome.is_none() {
+            *genome = Some(open_genome_reader(genome_path)?);
+        }
+        let genome = genome.as_ref().unwrap();
+
+        let mut num_failed = 0;
+        let mut results_by_sample = Vec::with_capacity(samples.len());
+        let inputs = samples.iter().zip(bams.iter_mut()).zip(&locus.ploidies);
+        for ((sample, bam), ploidy) in inputs {
+            let read_groups = sample.read_groups.as_ref();
+            match analyze_tr(locus, params, bam, genome, read_groups, *ploidy) {
+                Ok(results) => results_by_sample.push(results),
+                Err(err) => {
+                    log::error!("Error occurred while analyzing {}: {}", sample.name, err);
+                    results_by_sample.push(LocusResult::empty());
+                    num_failed += 1;
+                }
+            }
+        }
+
+        if num_failed == samples.len() {
+            Ok(None)
+        } else {
+            Ok(Some(results_by_sample))
+        }
+    })
+}
+
+/// Turns a panic during the analysis of a locus into an error, so that the
+/// locus still reaches the writer and the run fails instead of hanging
+fn catch_panic<F>(analyze: F) -> Result<Option<Vec<LocusResult>>>
+where
+    F: FnOnce() -> Result<Option<Vec<LocusResult>>>,
+{
+    panic::catch_unwind(panic::AssertUnwindSafe(analyze)).unwrap_or_else(|payload| {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|msg| msg.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(format!("Analysis panicked: {}", msg))
+    })
+}
+
+/// Opens a BAM or CRAM file; CRAM records are decoded against the reference genome
+fn open_reads(reads_path: &PathBuf, genome_path: &Path) -> Result<bam::IndexedReader> {
+    let mut reads = bam::IndexedReader::from_path(reads_path)
+        .map_err(|e| format!("Failed to create bam reader: {}", e))?;
+    reads.set_reference(genome_path).map_err(|e| {
+        format!(
+            "Failed to set reference for {}: {}",
+            reads_path.display(),
+            e
+        )
+    })?;
+    Ok(reads)
+}
+
 pub fn get_bam_header(bam_path: &PathBuf) -> Result<bam::Header> {
     let bam = bam::IndexedReader::from_path(bam_path)
         .map_err(|e| format!("Failed to create bam reader: {}", e))?;
     Ok(bam::Header::from_template(bam.header()))
 }
 
+/// Reads the header of a BAM file that may be unsorted and lack an index
+fn get_unaligned_bam_header(bam_path: &PathBuf) -> Result<bam::Header> {
+    let bam = bam::Reader::from_path(bam_path)
+        .map_err(|e| format!("Failed to create bam reader: {}", e))?;
+    Ok(bam::Header::from_template(bam.header()))
+}
+
+/// Creates a header listing the reference contigs for outputs of runs on
+/// unaligned reads
+fn get_genome_header(genome_reader: &faidx::Reader) -> Result<bam::Header> {
+    let mut header = bam::Header::new();
+    for index in 0..genome_reader.n_seqs() {
+        let name = genome_reader
+            .seq_name(index as i32)
+            .map_err(|e| e.to_string())?;
+        let len = genome_reader
+            .fetch_seq_len(&name)
+            .ok_or(format!("Failed to get length of {}", name))?;
+        header.push_record(
+            bam::header::HeaderRecord::new(b"SQ")
+                .push_tag(b"SN", &name)
+                .push_tag(b"LN", len),
+        );
+    }
+    Ok(header)
+}
+
 fn is_bam_mapped(bam_header: &bam::Header) -> bool {
     // input is already sorted because it fails an index.
     // If it is mapped, the index needs the SQ tags to fetch data.
@@ -74,43 +196,160 @@ fn is_bam_mapped(bam_header: &bam::Header) -> bool {
     false
 }
 
-fn get_sample_name(reads_path: &PathBuf) -> Result<String> {
-    let bam_header = get_bam_header(reads_path)?;
+/// A sample whose reads are stored in a BAM file, possibly alongside reads of
+/// other samples distinguished by read groups
+pub struct Sample {
+    pub name: String,
+    pub reads_path: PathBuf,
+    pub read_groups: Option<HashSet<String>>,
+}
+
+fn get_samples(
+    reads_paths: &[PathBuf],
+    sample_name: Option<String>,
+    unaligned: bool,
+) -> Result<Vec<Sample>> {
+    let mut samples = Vec::new();
+    for reads_path in reads_paths {
+        if unaligned && recruit::is_fastq(reads_path) {
+            samples.push(get_sample_from_path(reads_path)?);
+        } else {
+            samples.extend(get_samples_from_bam(reads_path, unaligned)?);
+        }
+    }
+
+    if let Some(sample_name) = sample_name {
+        if samples.len() != 1 {
+            return Err(format!(
+                "Sample name can only be set for a single sample, found {}",
+                samples.len()
+            ));
+        }
+        samples[0].name = sample_name;
+    }
+
+    let mut names = HashSet::new();
+    for sample in &samples {
+        if !names.insert(&sample.name) {
+            return Err(format!("Duplicate sample name: {}", sample.name));
+        }
+    }
+
+    Ok(samples)
+}
+
+fn get_samples_from_bam(reads_path: &PathBuf, unaligned: bool) -> Result<Vec<Sample>> {
+    let bam_header = if unaligned {
+        get_unaligned_bam_header(reads_path)?
+    } else {
+        get_bam_header(reads_path)?
+    };
 
     let header_hashmap = bam_header.to_hashmap();
-    let mut sample_names = HashSet::new();
+    let mut read_groups_by_sample: BTreeMap<String, HashSet<String>> = BTreeMap::new();
 
     if let Some(rg_fields) = header_hashmap.get("RG") {
         for rg_field in rg_fields {
             if let Some(sample_name) = rg_field.get("SM") {
-                sample_names.insert(sample_name.to_owned());
+                let read_groups = read_groups_by_sample
+                    .entry(sample_name.to_owned())
+                    .or_default();
+                if let Some(rg_id) = rg_field.get("ID") {
+                    read_groups.insert(rg_id.to_owned());
+                }
             }
         }
     }
 
-    match sample_names.len() {
-        1 => return Ok(sample_names.into_iter().next().unwrap()),
-        0 => log::warn!("No sample names found"),
-        _ => log::warn!("Multiple sample names found"),
+    match read_groups_by_sample.len() {
+        0 => log::warn!("No sample names found in {}", reads_path.display()),
+        1 => {
+            return Ok(vec![Sample {
+                name: read_groups_by_sample.into_keys().next().unwrap(),
+                reads_path: reads_path.clone(),
+                read_groups: None,
+            }])
+        }
+        _ => {
+            log::info!(
+                "Found {} samples in {}; reads will be split by read group",
+                read_groups_by_sample.len(),
+                reads_path.display()
+            );
+            return Ok(read_groups_by_sample
+                .into_iter()
+                .map(|(name, read_groups)| Sample {
+                    name,
+                    reads_path: reads_path.clone(),
+                    read_groups: Some(read_groups),
+                })
+                .collect());
+        }
     };
 
-    let sample = reads_path
+    Ok(vec![get_sample_from_path(reads_path)?])
+}
+
+/// Names the sample after the reads file, ignoring a compression extension
+fn get_sample_from_path(reads_path: &Path) -> Result<Sample> {
+    let stem_path = match reads_path.extension() {
+        Some(ext) if ext == "gz" => Path::new(reads_path.file_stem().unwrap()),
+        _ => reads_path,
+    };
+    let sample = stem_path
         .file_stem()
         .and_then(|stem| stem.to_str())
         .ok_or("Invalid reads file name")?
         .to_string();
 
-    Ok(sample)
+    Ok(Sample {
+        name: sample,
+        reads_path: reads_path.to_path_buf(),
+        read_groups: None,
+    })
+}
+
+fn get_output_path(output_prefix: &str, output_suffix: &str) -> String {
+    format!("{}.{}", output_prefix, output_suffix)
 }
 
 fn create_writer<T, F>(output_prefix: &str, output_suffix: &str, f: F) -> Result<T>
 where
     F: FnOnce(&str) -> Result<T>,
 {
-    let output_path = format!("{}.{}", output_prefix, output_suffix);
+    let output_path = get_output_path(output_prefix, output_suffix);
     f(&output_path)
 }
 
+/// Spanning reads of each sample are written to a separate BAM file
+fn get_bam_suffixes(samples: &[Sample]) -> Vec<String> {
+    if samples.len() == 1 {
+        return vec!["spanning.bam".to_string()];
+    }
+    samples
+        .iter()
+        .map(|sample| format!("{}.spanning.bam", sample.name))
+        .collect()
+}
+
+/// Maps each contig name to its rank and length in the BAM header
+fn get_contig_info(bam_header: &bam::Header) -> HashMap<String, (usize, u64)> {
+    let mut contigs = HashMap::new();
+    if let Some(records) = bam_header.to_hashmap().get("SQ") {
+        for (rank, record) in records.iter().enumerate() {
+            let len = record["LN"].parse().unwrap_or(0);
+            contigs.insert(record["SN"].to_string(), (rank, len));
+        }
+    }
+    contigs
+}
+
+/// Tabix and BAI indexes cannot address positions beyond 2^29 - 1
+fn requires_csi(contigs: &HashMap<String, (usize, u64)>) -> bool {
+    const MAX_TBI_LEN: u64 = (1 << 29) - 1;
+    contigs.values().any(|(_, len)| *len > MAX_TBI_LEN)
+}
+
 fn open_catalog_reader(path: &PathBuf) -> Result<BufReader<Box<dyn ioRead>>> {
     fn is_gzipped(path: &Path) -> bool {
         let path_str = path.to_string_lossy();
@@ -130,6 +369,68 @@ fn open_catalog_reader(path: &PathBuf) -> Result<BufReader<Box<dyn ioRead>>> {
     }
 }
 
+/// Target regions are given as contig names, 1-based "contig:start-end"
+/// intervals, or BED files; all are converted to BED coordinates
+fn get_target_regions(encodings: &[String]) -> Result<Vec<GenomicRegion>> {
+    let mut regions = Vec::new();
+    for encoding in encodings {
+        let path = PathBuf::from(encoding);
+        if path.is_file() {
+            let reader = open_catalog_reader(&path)?;
+            for (line_number, line) in reader.lines().enumerate() {
+                let line = line.map_err(|e| e.to_string())?;
+                if line.trim().is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let error = || {
+                    format!(
+                        "Error at line {} of {}: {}",
+                        line_number + 1,
+                        encoding,
+                        line
+                    )
+                };
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                if fields.len() < 3 {
+                    return Err(error());
+                }
+                let start: u32 = fields[1].parse().map_err(|_| error())?;
+                let end: u32 = fields[2].parse().map_err(|_| error())?;
+                regions.push(GenomicRegion {
+                    contig: fields[0].to_string(),
+                    start,
+                    end,
+                });
+            }
+        } else if encoding.contains(':') {
+            let mut region = GenomicRegion::new(encoding)?;
+            region.start = region.start.saturating_sub(1);
+            regions.push(region);
+        } else {
+            regions.push(GenomicRegion {
+                contig: encoding.clone(),
+                start: 0,
+                end: u32::MAX,
+            });
+        }
+    }
+    Ok(regions)
+}
+
+fn get_target_ids(loci_ids: &[String], loci_path: Option<&PathBuf>) -> Result<HashSet<String>> {
+    let mut ids: HashSet<String> = loci_ids.iter().cloned().collect();
+    if let Some(path) = loci_path {
+        let reader = open_catalog_reader(path)?;
+        for line in reader.lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            if !line.trim().is_empty() {
+                ids.insert(line.trim().to_string());
+            }
+        }
+    }
+    Ok(ids)
+}
+
 fn open_genome_reader(path: &Path) -> Result<faidx::Reader> {
     let extension = path.extension().unwrap().to_str().unwrap();
     let fai_path = path.with_extension(extension.to_owned() + ".fai");
@@ -144,13 +445,7 @@ fn open_genome_reader(path: &Path) -> Result<faidx::Reader> {
 }
 
 fn main() {
-    if let Err(e) = run_trgt() {
-        handle_error_and_exit(e);
-    }
-}
-
-fn run_trgt() -> Result<()> {
-    let params = get_cli_params();
+    let cli = get_cli_params();
 
     log::info!(
         "Running {}-{}",
@@ -159,87 +454,325 @@ fn run_trgt() -> Result<()> {
     );
     let start_timer = time::Instant::now();
 
-    let karyotype = Karyotype::new(&params.karyotype)?;
+    let result = match cli.command {
+        Command::Genotype(params) => run_trgt(*params),
+        Command::Merge(params) => merge::run(params),
+    };
+    if let Err(e) = result {
+        handle_error_and_exit(e);
+    }
 
-    let sample_name = params
-        .sample_name
-        .unwrap_or(get_sample_name(&params.reads_path)?);
+    log::info!("Total execution time: {:?}", start_timer.elapsed());
+    log::info!("{} end", env!("CARGO_PKG_NAME"));
+}
+
+fn run_trgt(params: GenotypeArgs) -> Result<()> {
+    if params.unaligned && params.karyotype.starts_with("auto") {
+        return Err("Karyotype cannot be inferred from unaligned reads".into());
+    }
+    let samples = get_samples(&params.reads_paths, params.sample_name, params.unaligned)?;
+    let karyotypes = samples
+        .iter()
+        .map(|sample| Karyotype::new(&params.karyotype, sample, &params.genome_path))
+        .collect::<Result<Vec<_>>>()?;
 
     let catalog_reader = open_catalog_reader(&params.repeats_path)?;
     let genome_reader = open_genome_reader(&params.genome_path)?;
 
-    let all_loci = locus::get_loci(
-        catalog_reader,
-        &genome_reader,
-        karyotype,
-        params.flank_len,
-        params.genotyper,
-    )
-    .collect::<Result<Vec<_>>>()?;
+    let target_ids = get_target_ids(&params.loci_ids, params.loci_path.as_ref())?;
+    let locus_filter = LocusFilter::new(get_target_regions(&params.regions)?, target_ids);
+    let (mut catalog, num_missing) = locus::read_catalog(catalog_reader, &locus_filter)?;
+    locus::check_catalog(&catalog, &genome_reader, params.flank_len, &karyotypes)?;
+
+    if num_missing > 0 {
+        log::warn!(
+            "{} requested repeat IDs were not found in the catalog",
+            num_missing
+        );
+    }
+    if catalog.is_empty() {
+        log::warn!("No repeats selected for analysis");
+    }
+
+    let mut bam_headers = Vec::new();
+    for sample in &samples {
+        if params.unaligned {
+            bam_headers.push(get_genome_header(&genome_reader)?);
+            continue;
+        }
+        let bam_header = get_bam_header(&sample.reads_path)?;
+        if !is_bam_mapped(&bam_header) {
+            handle_error_and_exit("Input BAM is not mapped".into());
+        }
+        bam_headers.push(bam_header);
+    }
+    let bam_header = &bam_headers[0];
 
-    let bam_header = get_bam_header(&params.reads_path)?;
-    if !is_bam_mapped(&bam_header) {
-        handle_error_and_exit("Input BAM is not mapped".into());
+    // Process loci in coordinate order so that outputs can be written sorted
+    let contigs = get_contig_info(bam_header);
+    if bam_headers
+        .iter()
+        .any(|header| get_contig_info(header) != contigs)
+    {
+        return Err("All BAM files must be aligned to the same reference".into());
     }
+    catalog.sort_by_key(|entry| {
+        let rank = contigs
+            .get(&entry.region.contig)
+            .map_or(usize::MAX, |(rank, _)| *rank);
+        (rank, entry.region.start, entry.region.end)
+    });
+
+    let sample_names = samples.iter().map(|s| s.name.as_str()).collect::<Vec<_>>();
+    let karyotype_names = karyotypes.iter().map(|k| k.name()).collect::<Vec<_>>();
+    let bam_suffixes = get_bam_suffixes(&samples);
+    let checkpoint_path = get_output_path(&params.output_prefix, "checkpoint");
+    let mut recovery = if params.resume {
+        let vcf_path = get_output_path(&params.output_prefix, "vcf.gz");
+        let bam_paths = bam_suffixes
+            .iter()
+            .map(|suffix| get_output_path(&params.output_prefix, suffix))
+            .collect::<Vec<_>>();
+        Recovery::new(&checkpoint_path, &vcf_path, &bam_paths)?
+    } else {
+        None
+    };
+    let completed = recovery
+        .as_ref()
+        .map_or_else(HashSet::new, |recovery| recovery.completed());
+    let mut checkpoint = if params.checkpoint || params.resume {
+        Some(CheckpointWriter::new(&checkpoint_path)?)
+    } else {
+        None
+    };
 
     let mut vcf_writer = create_writer(&params.output_prefix, "vcf.gz", |path| {
-        VcfWriter::new(path, &sample_name, &bam_header)
+        VcfWriter::new(path, &sample_names, &karyotype_names, bam_header)
     })?;
 
     let output_flank_len = std::cmp::min(params.flank_len, 50);
-    let mut bam_writer = create_writer(&params.output_prefix, "spanning.bam", |path| {
-        BamWriter::new(path, bam_header, output_flank_len)
-    })?;
+    let mut bam_writers = bam_headers
+        .into_iter()
+        .zip(bam_suffixes.iter())
+        .map(|(bam_header, suffix)| {
+            create_writer(&params.output_prefix, suffix, |path| {
+                BamWriter::new(path, bam_header, output_flank_len)
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut read_table_writer = if params.read_table {
+        Some(create_writer(
+            &params.output_prefix,
+            "reads.tsv.gz",
+            |path| ReadTableWriter::new(path, output_flank_len),
+        )?)
+    } else {
+        None
+    };
+    let table_sample_names = samples.iter().map(|s| s.name.clone()).collect::<Vec<_>>();
 
     log::info!("Starting job pool with {} threads...", params.num_threads);
     let pool: ThreadPool = ThreadPool::new(params.num_threads);
-    let (sender, receiver) = channel();
+    let (sender, receiver) = channel::<AnalyzedLocus>();
+
+    // Each locus holds a slot from submission until it is written, which bounds
+    // both the pool queue and the results waiting for slower preceding loci
+    let max_pending_loci = params.num_threads * MAX_PENDING_LOCI_PER_THREAD;
+    let (slot_sender, slot_receiver) = sync_channel(max_pending_loci);
+    for _ in 0..max_pending_loci {
+        slot_sender.send(()).unwrap();
+    }
+
+    let writer_thread = thread::spawn(move || -> Result<Vec<String>> {
+        // Results arrive in any order; buffer them until all preceding loci are done
+        let mut pending: BTreeMap<usize, (Locus, Option<Vec<LocusResult>>)> = BTreeMap::new();
+        let mut next_index = 0;
+        for (index, locus, results) in &receiver {
+            let results = results.map_err(|e| format!("Failed to analyze {}: {}", locus.id, e))?;
+            pending.insert(index, (locus, results));
+            while let Some((locus, results)) = pending.remove(&next_index) {
+                if let Some(recovery) = recovery.as_mut() {
+                    recovery.restore_reads(&locus, &mut bam_writers);
+                }
+
+                let mut read_counts = None;
+                if let Some(results) = results {
+                    vcf_writer.write(&locus, &results);
+                    if let Some(writer) = read_table_writer.as_mut() {
+                        for (sample_name, results) in table_sample_names.iter().zip(&results) {
+                            writer.write(sample_name, &locus, results)?;
+                        }
+                    }
+                    let counts = bam_writers
+                        .iter_mut()
+                        .zip(results.iter())
+                        .map(|(bam_writer, results)| bam_writer.write(&locus, results))
+                        .collect::<Vec<_>>();
+                    read_counts = Some(counts);
+                } else if let Some(recovery) = recovery.as_mut() {
+                    if let Some(counts) = recovery.get_read_counts(&locus.id).cloned() {
+                        recovery.restore_record(&locus.id, &mut vcf_writer)?;
+                        read_counts = Some(counts);
+                    }
+                }
+
+                if let (Some(checkpoint), Some(read_counts)) = (checkpoint.as_mut(), read_counts) {
+                    checkpoint.add(&locus.id, &read_counts)?;
+                }
+                next_index += 1;
+                let _ = slot_sender.send(());
+            }
+        }
+
+        // Every submitted locus is sent to the writer, so results can only be
+        // left waiting if the results of a preceding locus were lost
+        if !pending.is_empty() {
+            return Err(format!(
+                "Results of locus {} are missing; {} later loci were not written",
+                next_index + 1,
+                pending.len()
+            ));
+        }
 
-    let writer_thread = thread::spawn(move || {
-        for (locus, results) in &receiver {
-            vcf_writer.write(&locus, &results);
-            bam_writer.write(&locus, &results);
+        let partial_paths = match recovery {
+            Some(recovery) => recovery.finish(&mut bam_writers),
+            None => Vec::new(),
+        };
+        for bam_writer in bam_writers {
+            bam_writer.finish();
         }
+        if let Some(writer) = read_table_writer {
+            writer.finish()?;
+        }
+        Ok(partial_paths)
     });
 
-    let reads_path = Arc::new(params.reads_path.clone());
+    let samples = Arc::new(samples);
     let workflow_params = Arc::new(workflows::Params {
         search_flank_len: params.flank_len,
-        min_read_qual: params.min_hifi_read_qual,
+        min_read_qual: params
+            .min_read_qual
+            .unwrap_or(params.preset.min_read_qual()),
+        use_base_quals: params.preset.uses_base_quals(),
         max_depth: params.max_depth,
-        aln_scoring: params.aln_scoring,
-        min_flank_id_frac: params.min_flank_id_frac,
+        aln_scoring: params.aln_scoring.unwrap_or(params.preset.aln_scoring()),
+        min_flank_id_frac: params
+            .min_flank_id_frac
+            .unwrap_or(params.preset.min_flank_id_frac()),
+        len_errors: params.preset.len_errors(),
+        annotate_reads: params.annotate_reads || params.read_table,
+        cpg_profiles: params.cpg_profiles,
+        stitch_supplementary: params.stitch_supplementary,
     });
-    for locus in all_loci {
-        let reads_path = reads_path.clone();
+    let genome_path = Arc::new(params.genome_path.clone());
+    let all_loci = locus::get_loci(
+        catalog,
+        &genome_reader,
+        karyotypes,
+        params.flank_len,
+        params.fixed_flanks,
+        params.genotyper,
+    );
+    // Unaligned reads are assigned to all repeats up front and held in memory
+    let mut recruited_reads = Vec::new();
+    let all_loci: Box<dyn Iterator<Item = Result<Locus>>> = if params.unaligned {
+        let recruited = all_loci.collect::<Result<Vec<_>>>().and_then(|loci| {
+            for sample in samples.iter() {
+                recruited_reads.push(recruit::recruit_reads(&loci, &workflow_params, sample)?);
+            }
+            Ok(loci)
+        });
+        match recruited {
+            Ok(loci) => Box::new(loci.into_iter().map(Ok)),
+            Err(err) => Box::new(std::iter::once(Err(err))),
+        }
+    } else {
+        Box::new(all_loci)
+    };
+    // Loci that fail to load stop the submission, but outputs of the loci
+    // submitted so far are still written and closed
+    let mut load_result = Ok(());
+    for (index, locus) in all_loci.enumerate() {
+        // The writer thread only stops early if it failed
+        if slot_receiver.recv().is_err() {
+            break;
+        }
+        let locus = match locus {
+            Ok(locus) => locus,
+            Err(err) => {
+                load_result = Err(err);
+                break;
+            }
+        };
+        if completed.contains(&locus.id) {
+            sender.send((index, locus, Ok(None))).unwrap();
+            continue;
+        }
+        let reads_by_sample = if params.unaligned {
+            let reads = recruited_reads
+                .iter_mut()
+                .map(|reads| reads.remove(&locus.id).unwrap_or_default())
+                .collect::<Vec<_>>();
+            Some(reads)
+        } else {
+            None
+        };
+        let samples = samples.clone();
         let workflow_params = workflow_params.clone();
+        let genome_path = genome_path.clone();
         let sender = sender.clone();
 
-        pool.execute(move || {
-            LOCAL.with(|local| {
-                let mut bam = local.bam.borrow_mut();
-                if bam.is_none() {
-                    *bam = Some(
-                        bam::IndexedReader::from_path(&*reads_path)
-                            .expect("Failed to initialize bam file"),
-                    );
-                }
-                let results = analyze_tr(&locus, &workflow_params, bam.as_mut().unwrap());
-                match results {
-                    Ok(results) => {
-                        sender.send((locus, results)).unwrap();
-                    }
-                    Err(err) => {
-                        log::error!("Error occurred while analyzing: {}", err);
-                    }
-                }
+        if let Some(reads_by_sample) = reads_by_sample {
+            pool.execute(move || {
+                let results = catch_panic(|| {
+                    let results_by_sample = samples
+                        .iter()
+                        .zip(reads_by_sample)
+                        .zip(&locus.ploidies)
+                        .map(|((sample, reads), ploidy)| {
+                            analyze_tr_reads(&locus, &workflow_params, *ploidy, reads)
+                                .unwrap_or_else(|err| {
+                                    log::error!(
+                                        "Error occurred while analyzing {}: {}",
+                                        sample.name,
+                                        err
+                                    );
+                                    LocusResult::empty()
+                                })
+                        })
+                        .collect::<Vec<_>>();
+                    Ok(Some(results_by_sample))
+                });
+                let _ = sender.send((index, locus, results));
             });
+            continue;
+        }
+
+        pool.execute(move || {
+            let results =
+                catch_panic(|| analyze_locus(&locus, &samples, &workflow_params, &genome_path));
+            // The writer thread may have stopped after an error
+            let _ = sender.send((index, locus, results));
         });
     }
     pool.join();
     drop(sender);
-    writer_thread.join().unwrap();
-    log::info!("Total execution time: {:?}", start_timer.elapsed());
-    log::info!("{} end", env!("CARGO_PKG_NAME"));
+    let partial_paths = writer_thread.join().unwrap()?;
+    load_result?;
+    for path in partial_paths {
+        std::fs::remove_file(&path).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if params.checkpoint || params.resume {
+        std::fs::remove_file(&checkpoint_path).map_err(|e| e.to_string())?;
+    }
+
+    if params.index {
+        let use_csi = requires_csi(&contigs);
+        index_vcf(&get_output_path(&params.output_prefix, "vcf.gz"), use_csi)?;
+        for suffix in &bam_suffixes {
+            index_bam(&get_output_path(&params.output_prefix, suffix), use_csi)?;
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,779 @@
+//! Merging of VCF files generated by TRGT
+//!
+//! Records are matched by their repeat identifier (INFO/TRID). The alleles of
+//! all input records are combined into a single allele list, and genotypes are
+//! re-indexed against that list. Other genotype fields describe the alleles in
+//! the order given by GT, so they are carried over as they are.
+
+use crate::cli::MergeArgs;
+use crate::genotype;
+use crate::writers::{VECTOR_END_FLOAT, VECTOR_END_INTEGER};
+use itertools::Itertools;
+use rust_htslib::bcf::header::{HeaderRecord, HeaderView};
+use rust_htslib::bcf::record::{GenotypeAllele, Numeric};
+use rust_htslib::bcf::{self, Format, Read, Record};
+use std::collections::HashSet;
+use std::env;
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, String>;
+
+/// Per-sample read count fields
+const READ_COUNT_TAGS: [&[u8]; 6] = [b"NR", b"LF", b"RF", b"DF", b"SF", b"LB"];
+
+/// Contig index in the merged header and start of the repeat region
+type LocusKey = (u32, i64);
+
+struct SampleCall {
+    gt: Vec<GenotypeAllele>,
+    /// PL and GQ values, which are absent from older VCFs
+    pl: Vec<i32>,
+    gq: Vec<i32>,
+    /// Phase set, which is absent from older VCFs
+    ps: Vec<i32>,
+    al: Vec<i32>,
+    allr: Vec<u8>,
+    sd: Vec<i32>,
+    mc: Vec<u8>,
+    ms: Vec<u8>,
+    ap: Vec<f32>,
+    /// Low-support positions of allele consensus sequences, which are absent
+    /// from older VCFs
+    ls: Vec<u8>,
+    am: Vec<f32>,
+    /// Mean 5hmC and 6mA levels, which are absent from older VCFs
+    ah: Vec<f32>,
+    aa: Vec<f32>,
+    /// CpG methylation profiles, which are absent from older VCFs
+    mp: Vec<u8>,
+    /// Difference in methylation between alleles and its p-value, which are
+    /// absent from older VCFs
+    md: Vec<f32>,
+    mdp: Vec<f32>,
+    /// LQ and MI values, which are absent from older VCFs
+    lq: Vec<u8>,
+    mi: Vec<f32>,
+    /// Values of READ_COUNT_TAGS, which are absent from older VCFs
+    read_counts: Vec<Vec<i32>>,
+}
+
+/// Record of a single input file with alleles stripped of the padding base
+struct LocusEntry {
+    key: LocusKey,
+    trid: Vec<u8>,
+    end: i32,
+    motifs: Vec<u8>,
+    struc: Vec<u8>,
+    /// Flank bounds, which are absent from older VCFs
+    flanks: Vec<i32>,
+    qual: f32,
+    filters: Option<Vec<Vec<u8>>>,
+    alleles: Vec<Vec<u8>>,
+    pad_base: Option<u8>,
+    calls: Vec<SampleCall>,
+}
+
+struct InputVcf {
+    path: String,
+    reader: bcf::Reader,
+    next: Option<LocusEntry>,
+    last_key: Option<LocusKey>,
+}
+
+impl InputVcf {
+    fn new(path: &Path) -> Result<Self> {
+        let reader = bcf::Reader::from_path(path)
+            .map_err(|e| format!("Failed to open VCF {}: {}", path.display(), e))?;
+        Ok(InputVcf {
+            path: path.display().to_string(),
+            reader,
+            next: None,
+            last_key: None,
+        })
+    }
+
+    fn num_samples(&self) -> usize {
+        self.reader.header().sample_count() as usize
+    }
+
+    /// Loads the next record and checks that records are sorted
+    fn advance(&mut self, output_header: &HeaderView) -> Result<()> {
+        let mut record = self.reader.empty_record();
+        self.next = match self.reader.read(&mut record) {
+            None => None,
+            Some(Err(e)) => return Err(format!("Failed to read {}: {}", self.path, e)),
+            Some(Ok(())) => Some(
+                parse_record(&record, output_header)
+                    .map_err(|e| format!("{}: {}", self.path, e))?,
+            ),
+        };
+
+        if let Some(entry) = &self.next {
+            if self.last_key.is_some_and(|key| entry.key < key) {
+                return Err(format!(
+                    "{} is not sorted; records must be in the order written by TRGT",
+                    self.path
+                ));
+            }
+            self.last_key = Some(entry.key);
+        }
+        Ok(())
+    }
+
+    /// Takes all consecutive records at the given position
+    fn take(&mut self, key: LocusKey, output_header: &HeaderView) -> Result<Vec<LocusEntry>> {
+        let mut entries = Vec::new();
+        while self.next.as_ref().is_some_and(|e| e.key == key) {
+            entries.push(self.next.take().unwrap());
+            self.advance(output_header)?;
+        }
+        Ok(entries)
+    }
+}
+
+pub fn run(args: MergeArgs) -> Result<()> {
+    let mut inputs = args
+        .vcf_paths
+        .iter()
+        .map(|path| InputVcf::new(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut writer = create_writer(&args.output_path, &inputs)?;
+    let output_header = writer.header().clone();
+    for input in inputs.iter_mut() {
+        input.advance(&output_header)?;
+    }
+
+    let num_samples = inputs.iter().map(|i| i.num_samples()).collect_vec();
+    let paths = inputs.iter().map(|i| i.path.clone()).collect_vec();
+    let mut num_records = 0;
+    while let Some(key) = inputs
+        .iter()
+        .filter_map(|i| i.next.as_ref().map(|e| e.key))
+        .min()
+    {
+        let entries_by_input = inputs
+            .iter_mut()
+            .map(|input| input.take(key, &output_header))
+            .collect::<Result<Vec<_>>>()?;
+
+        let trids = entries_by_input
+            .iter()
+            .flatten()
+            .map(|e| &e.trid)
+            .unique()
+            .collect_vec();
+        for trid in trids {
+            let entries = entries_by_input
+                .iter()
+                .map(|entries| entries.iter().find(|e| &e.trid == trid))
+                .collect_vec();
+            let mut record = writer.empty_record();
+            set_merged_fields(&entries, &num_samples, &paths, &mut record)?;
+            writer
+                .write(&record)
+                .map_err(|e| format!("Failed to write record: {}", e))?;
+            num_records += 1;
+        }
+    }
+
+    log::info!(
+        "Merged {} records from {} files",
+        num_records,
+        args.vcf_paths.len()
+    );
+    Ok(())
+}
+
+fn create_writer(output_path: &str, inputs: &[InputVcf]) -> Result<bcf::Writer> {
+    let template = inputs[0].reader.header();
+    let mut header = bcf::Header::from_template_subset(template, &[])
+        .map_err(|e| format!("Failed to create VCF header: {}", e))?;
+
+    // Sample lines such as the karyotype of each sample are carried over
+    for input in &inputs[1..] {
+        for record in input.reader.header().header_records() {
+            if let HeaderRecord::Structured { key, values } = record {
+                if key == "SAMPLE" {
+                    let values = values
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect::<Vec<_>>();
+                    let line = format!("##SAMPLE=<{}>", values.join(","));
+                    header.push_record(line.as_bytes());
+                }
+            }
+        }
+    }
+
+    let mut sample_names = HashSet::new();
+    for input in inputs {
+        for sample in input.reader.header().samples() {
+            if !sample_names.insert(sample.to_vec()) {
+                return Err(format!(
+                    "Duplicate sample name: {}",
+                    String::from_utf8_lossy(sample)
+                ));
+            }
+            header.push_sample(sample);
+        }
+    }
+
+    let args: Vec<String> = env::args().collect();
+    let line = format!(
+        "##{}MergeCommand={}",
+        env!("CARGO_PKG_NAME"),
+        args.join(" ")
+    );
+    header.push_record(line.as_bytes());
+
+    bcf::Writer::from_path(output_path, &header, false, Format::Vcf)
+        .map_err(|_| format!("Invalid VCF output path: {}", output_path))
+}
+
+fn parse_record(record: &Record, output_header: &HeaderView) -> Result<LocusEntry> {
+    let rid = record.rid().ok_or("Record without contig")?;
+    let contig = record.header().rid2name(rid).map_err(|e| e.to_string())?;
+    let rid = output_header.name2rid(contig).map_err(|_| {
+        format!(
+            "Contig {} is missing from the first VCF",
+            String::from_utf8_lossy(contig)
+        )
+    })?;
+
+    let get_info = |tag: &[u8]| -> Result<Vec<u8>> {
+        let field = record.info(tag).string().map_err(|e| e.to_string())?;
+        let field =
+            field.ok_or_else(|| format!("{} field missing", String::from_utf8_lossy(tag)))?;
+        Ok(field[0].to_vec())
+    };
+    let trid = get_info(b"TRID")?;
+    let motifs = get_info(b"MOTIFS")?;
+    let struc = get_info(b"STRUC")?;
+    let end = record
+        .info(b"END")
+        .integer()
+        .map_err(|e| e.to_string())?
+        .ok_or("END field missing")?[0];
+    let flanks = match record.info(b"FLANKS").integer() {
+        Ok(Some(flanks)) => flanks.to_vec(),
+        _ => Vec::new(),
+    };
+
+    // Records of older versions have no filters set
+    let filters = if record.filters().next().is_none() {
+        None
+    } else {
+        let header = record.header();
+        Some(
+            record
+                .filters()
+                .map(|id| header.id_to_name(id))
+                .filter(|name| name != b"PASS")
+                .collect_vec(),
+        )
+    };
+
+    let calls = parse_calls(record)?;
+    let alleles = record.alleles();
+    let is_padded = is_padded(&alleles, &calls);
+    let pad_base = if is_padded { Some(alleles[0][0]) } else { None };
+    let alleles = alleles
+        .iter()
+        .map(|a| a[is_padded as usize..].to_vec())
+        .collect_vec();
+
+    Ok(LocusEntry {
+        key: (rid, record.pos() + is_padded as i64),
+        trid,
+        end,
+        motifs,
+        struc,
+        flanks,
+        qual: record.qual(),
+        filters,
+        alleles,
+        pad_base,
+        calls,
+    })
+}
+
+/// Padded records have each allele one base longer than its reported length
+fn is_padded(alleles: &[&[u8]], calls: &[SampleCall]) -> bool {
+    for call in calls {
+        for (allele, len) in call.gt.iter().zip(call.al.iter()) {
+            if let Some(index) = allele.index() {
+                return alleles[index as usize].len() as i32 == len + 1;
+            }
+        }
+    }
+    false
+}
+
+fn parse_calls(record: &Record) -> Result<Vec<SampleCall>> {
+    let genotypes = record.genotypes().map_err(|e| e.to_string())?;
+    let get_ints = |tag: &[u8]| -> Result<Vec<Vec<i32>>> {
+        let values = record.format(tag).integer().map_err(|e| e.to_string())?;
+        Ok(values.iter().map(|v| trim_ints(v)).collect_vec())
+    };
+    let get_floats = |tag: &[u8]| -> Result<Vec<Vec<f32>>> {
+        let values = record.format(tag).float().map_err(|e| e.to_string())?;
+        Ok(values.iter().map(|v| trim_floats(v)).collect_vec())
+    };
+    let get_strings = |tag: &[u8]| -> Result<Vec<Vec<u8>>> {
+        let values = record.format(tag).string().map_err(|e| e.to_string())?;
+        Ok(values.iter().map(|v| v.to_vec()).collect_vec())
+    };
+
+    let mut pl = get_ints(b"PL").unwrap_or_default().into_iter();
+    let mut gq = get_ints(b"GQ").unwrap_or_default().into_iter();
+    let mut ps = get_ints(b"PS").unwrap_or_default().into_iter();
+    let mut al = get_ints(b"AL")?.into_iter();
+    let mut allr = get_strings(b"ALLR")?.into_iter();
+    let mut sd = get_ints(b"SD")?.into_iter();
+    let mut mc = get_strings(b"MC")?.into_iter();
+    let mut ms = get_strings(b"MS")?.into_iter();
+    let mut ap = get_floats(b"AP")?.into_iter();
+    let mut ls = get_strings(b"LS").unwrap_or_default().into_iter();
+    let mut am = get_floats(b"AM")?.into_iter();
+    let mut ah = get_floats(b"AH").unwrap_or_default().into_iter();
+    let mut aa = get_floats(b"AA").unwrap_or_default().into_iter();
+    let mut mp = get_strings(b"MP").unwrap_or_default().into_iter();
+    let mut md = get_floats(b"MD").unwrap_or_default().into_iter();
+    let mut mdp = get_floats(b"MDP").unwrap_or_default().into_iter();
+    let mut lq = get_strings(b"LQ").unwrap_or_default().into_iter();
+    let mut mi = get_floats(b"MI").unwrap_or_default().into_iter();
+    let mut read_counts = READ_COUNT_TAGS
+        .iter()
+        .map(|tag| get_ints(tag).unwrap_or_default().into_iter())
+        .collect_vec();
+
+    let calls = (0..record.sample_count() as usize)
+        .map(|index| SampleCall {
+            gt: genotypes.get(index).iter().copied().collect_vec(),
+            pl: pl.next().unwrap_or_default(),
+            gq: gq.next().unwrap_or_default(),
+            ps: ps.next().unwrap_or_default(),
+            al: al.next().unwrap(),
+            allr: allr.next().unwrap(),
+            sd: sd.next().unwrap(),
+            mc: mc.next().unwrap(),
+            ms: ms.next().unwrap(),
+            ap: ap.next().unwrap(),
+            ls: ls.next().unwrap_or_else(|| b".".to_vec()),
+            am: am.next().unwrap(),
+            ah: ah.next().unwrap_or_default(),
+            aa: aa.next().unwrap_or_default(),
+            mp: mp.next().unwrap_or_else(|| b".".to_vec()),
+            md: md.next().unwrap_or_default(),
+            mdp: mdp.next().unwrap_or_default(),
+            lq: lq.next().unwrap_or_else(|| b".".to_vec()),
+            mi: mi.next().unwrap_or_default(),
+            read_counts: read_counts
+                .iter_mut()
+                .map(|counts| counts.next().unwrap_or_default())
+                .collect(),
+        })
+        .collect_vec();
+
+    Ok(calls)
+}
+
+fn trim_ints(values: &[i32]) -> Vec<i32> {
+    values
+        .iter()
+        .take_while(|v| **v != VECTOR_END_INTEGER)
+        .copied()
+        .collect()
+}
+
+fn trim_floats(values: &[f32]) -> Vec<f32> {
+    values
+        .iter()
+        .take_while(|v| v.to_bits() != VECTOR_END_FLOAT.to_bits())
+        .copied()
+        .collect()
+}
+
+fn set_merged_fields(
+    entries: &[Option<&LocusEntry>],
+    num_samples: &[usize],
+    paths: &[String],
+    record: &mut Record,
+) -> Result<()> {
+    let first = entries.iter().flatten().next().unwrap();
+
+    let mut alleles: Vec<&[u8]> = vec![&first.alleles[0]];
+    for entry in entries.iter().flatten() {
+        for allele in &entry.alleles[1..] {
+            if !alleles.contains(&allele.as_slice()) {
+                alleles.push(allele);
+            }
+        }
+    }
+
+    let pad_base = if alleles.iter().any(|a| a.is_empty()) {
+        entries.iter().flatten().find_map(|e| e.pad_base)
+    } else {
+        None
+    };
+
+    record.set_rid(Some(first.key.0));
+    record.set_pos(first.key.1 - pad_base.is_some() as i64);
+    let qual = entries
+        .iter()
+        .flatten()
+        .map(|e| e.qual)
+        .filter(|q| !q.is_missing())
+        .reduce(f32::min)
+        .unwrap_or_else(f32::missing);
+    record.set_qual(qual);
+    set_merged_filters(entries, record)?;
+    let encoding = alleles
+        .iter()
+        .map(|a| match pad_base {
+            Some(base) => [&[base], *a].concat(),
+            None => a.to_vec(),
+        })
+        .collect_vec();
+    record
+        .set_alleles(&encoding.iter().map(|a| &a[..]).collect_vec())
+        .map_err(|e| e.to_string())?;
+
+    let push_info = |record: &mut Record, tag: &[u8], value: &[u8]| {
+        record
+            .push_info_string(tag, &[value])
+            .map_err(|e| e.to_string())
+    };
+    push_info(record, b"TRID", &first.trid)?;
+    record
+        .push_info_integer(b"END", &[first.end])
+        .map_err(|e| e.to_string())?;
+    push_info(record, b"MOTIFS", &first.motifs)?;
+    push_info(record, b"STRUC", &first.struc)?;
+    if !first.flanks.is_empty() && record.header().name_to_id(b"FLANKS").is_ok() {
+        record
+            .push_info_integer(b"FLANKS", &first.flanks)
+            .map_err(|e| e.to_string())?;
+    }
+
+    let calls = entries
+        .iter()
+        .zip(num_samples)
+        .zip(paths)
+        .flat_map(|((entry, num_samples), path)| {
+            (0..*num_samples).map(move |index| entry.map(|e| (e, &e.calls[index], path)))
+        })
+        .collect_vec();
+
+    let gts = calls
+        .iter()
+        .map(|call| match call {
+            Some((entry, call, path)) => call
+                .gt
+                .iter()
+                .map(|allele| remap_allele(*allele, &entry.alleles, &alleles))
+                .collect::<Result<Vec<_>>>()
+                .map_err(|e| {
+                    let trid = String::from_utf8_lossy(&entry.trid);
+                    format!("Failed to merge {} from {}: {}", trid, path, e)
+                }),
+            None => Ok(vec![GenotypeAllele::UnphasedMissing]),
+        })
+        .map_ok(|gt| gt.into_iter().map(i32::from).collect_vec())
+        .collect::<Result<Vec<_>>>()?;
+    push_ints(
+        record,
+        b"GT",
+        gts,
+        i32::from(GenotypeAllele::UnphasedMissing),
+    )?;
+
+    if record.header().name_to_id(b"PL").is_ok() {
+        let pls = calls
+            .iter()
+            .map(|call| match call {
+                Some((entry, call, _)) => remap_pl(call, &entry.alleles, &alleles),
+                None => Vec::new(),
+            })
+            .collect_vec();
+        push_ints(record, b"PL", pls, i32::missing())?;
+        let gqs = calls
+            .iter()
+            .map(|c| c.map_or_else(Vec::new, |(_, call, _)| call.gq.clone()))
+            .collect_vec();
+        push_ints(record, b"GQ", gqs, i32::missing())?;
+    }
+
+    let get_ints = |get: fn(&SampleCall) -> &Vec<i32>| {
+        calls
+            .iter()
+            .map(|c| c.map_or_else(Vec::new, |(_, call, _)| get(call).clone()))
+            .collect_vec()
+    };
+    let get_floats = |get: fn(&SampleCall) -> &Vec<f32>| {
+        calls
+            .iter()
+            .map(|c| c.map_or_else(Vec::new, |(_, call, _)| get(call).clone()))
+            .collect_vec()
+    };
+    let get_strings = |get: fn(&SampleCall) -> &Vec<u8>| {
+        calls
+            .iter()
+            .map(|c| c.map_or_else(|| b".".to_vec(), |(_, call, _)| get(call).clone()))
+            .collect_vec()
+    };
+
+    if record.header().name_to_id(b"PS").is_ok() {
+        push_ints(record, b"PS", get_ints(|c| &c.ps), i32::missing())?;
+    }
+    push_ints(record, b"AL", get_ints(|c| &c.al), i32::missing())?;
+    push_strings(record, b"ALLR", get_strings(|c| &c.allr))?;
+    push_ints(record, b"SD", get_ints(|c| &c.sd), i32::missing())?;
+    push_strings(record, b"MC", get_strings(|c| &c.mc))?;
+    push_strings(record, b"MS", get_strings(|c| &c.ms))?;
+    push_floats(record, b"AP", get_floats(|c| &c.ap))?;
+    if record.header().name_to_id(b"LS").is_ok() {
+        push_strings(record, b"LS", get_strings(|c| &c.ls))?;
+    }
+    push_floats(record, b"AM", get_floats(|c| &c.am))?;
+    if record.header().name_to_id(b"AH").is_ok() {
+        push_floats(record, b"AH", get_floats(|c| &c.ah))?;
+        push_floats(record, b"AA", get_floats(|c| &c.aa))?;
+    }
+    if record.header().name_to_id(b"MP").is_ok() {
+        push_strings(record, b"MP", get_strings(|c| &c.mp))?;
+    }
+    if record.header().name_to_id(b"MD").is_ok() {
+        push_floats(record, b"MD", get_floats(|c| &c.md))?;
+        push_floats(record, b"MDP", get_floats(|c| &c.mdp))?;
+    }
+    if record.header().name_to_id(b"LQ").is_ok() {
+        push_strings(record, b"LQ", get_strings(|c| &c.lq))?;
+        push_floats(record, b"MI", get_floats(|c| &c.mi))?;
+    }
+
+    for (index, tag) in READ_COUNT_TAGS.iter().enumerate() {
+        if record.header().name_to_id(tag).is_err() {
+            continue;
+        }
+        let values = calls
+            .iter()
+            .map(|c| c.map_or_else(Vec::new, |(_, call, _)| call.read_counts[index].clone()))
+            .collect_vec();
+        push_ints(record, tag, values, i32::missing())?;
+    }
+
+    Ok(())
+}
+
+/// Takes the union of the filters of all inputs; a repeat without spanning
+/// reads in one input may still have them in another
+fn set_merged_filters(entries: &[Option<&LocusEntry>], record: &mut Record) -> Result<()> {
+    let Some(entry_filters) = entries
+        .iter()
+        .flatten()
+        .map(|e| e.filters.as_ref())
+        .collect::<Option<Vec<_>>>()
+    else {
+        return Ok(());
+    };
+
+    let is_no_spanning = entry_filters
+        .iter()
+        .all(|filters| filters.iter().any(|f| f == b"NoSpanning"));
+    let mut merged: Vec<&[u8]> = Vec::new();
+    for filter in entry_filters.iter().flat_map(|filters| filters.iter()) {
+        if (filter != b"NoSpanning" || is_no_spanning) && !merged.contains(&filter.as_slice()) {
+            merged.push(filter);
+        }
+    }
+    if merged.is_empty() {
+        merged.push(b"PASS");
+    }
+    record.set_filters(&merged).map_err(|e| e.to_string())
+}
+
+/// Finds the allele in the merged allele list; only the reference allele can
+/// be missing from it, when inputs were generated with different references
+/// or repeat catalogs
+fn remap_allele(
+    allele: GenotypeAllele,
+    input_alleles: &[Vec<u8>],
+    alleles: &[&[u8]],
+) -> Result<GenotypeAllele> {
+    let remap = |index: i32| {
+        let seq = &input_alleles[index as usize];
+        match alleles.iter().position(|a| a == seq) {
+            Some(position) => Ok(position as i32),
+            None => Err(format!(
+                "allele {} does not match the reference allele {} of other inputs",
+                String::from_utf8_lossy(seq),
+                String::from_utf8_lossy(alleles[0])
+            )),
+        }
+    };
+    Ok(match allele {
+        GenotypeAllele::Unphased(index) => GenotypeAllele::Unphased(remap(index)?),
+        GenotypeAllele::Phased(index) => GenotypeAllele::Phased(remap(index)?),
+        missing => missing,
+    })
+}
+
+/// Reorders genotype likelihoods to match the merged alleles; genotypes with
+/// alleles absent from the input get missing values
+fn remap_pl(call: &SampleCall, input_alleles: &[Vec<u8>], alleles: &[&[u8]]) -> Vec<i32> {
+    let ploidy = call.gt.len();
+    if call.pl.len() != genotype::count_genotypes(input_alleles.len(), ploidy)
+        || call.pl.iter().all(|v| v.is_missing())
+        || genotype::count_genotypes(alleles.len(), ploidy) > genotype::MAX_PL_GENOTYPES
+    {
+        return Vec::new();
+    }
+    let input_genotypes = genotype::get_genotypes(input_alleles.len(), ploidy);
+
+    let input_indexes = alleles
+        .iter()
+        .map(|allele| input_alleles.iter().position(|a| a == allele))
+        .collect_vec();
+    genotype::get_genotypes(alleles.len(), ploidy)
+        .into_iter()
+        .map(|gt| {
+            let input_gt = gt
+                .iter()
+                .map(|index| input_indexes[*index])
+                .collect::<Option<Vec<_>>>();
+            let Some(mut input_gt) = input_gt else {
+                return i32::missing();
+            };
+            input_gt.sort();
+            let position = input_genotypes.iter().position(|g| *g == input_gt).unwrap();
+            call.pl[position]
+        })
+        .collect()
+}
+
+fn push_ints(record: &mut Record, tag: &[u8], values: Vec<Vec<i32>>, missing: i32) -> Result<()> {
+    let width = values.iter().map(|v| v.len()).max().unwrap_or(0).max(1);
+    let mut encoding = Vec::with_capacity(width * values.len());
+    for mut value in values {
+        if value.is_empty() {
+            value.push(missing);
+        }
+        value.resize(width, VECTOR_END_INTEGER);
+        encoding.extend(value);
+    }
+    record
+        .push_format_integer(tag, &encoding)
+        .map_err(|e| e.to_string())
+}
+
+fn push_floats(record: &mut Record, tag: &[u8], values: Vec<Vec<f32>>) -> Result<()> {
+    let width = values.iter().map(|v| v.len()).max().unwrap_or(0).max(1);
+    let mut encoding = Vec::with_capacity(width * values.len());
+    for mut value in values {
+        if value.is_empty() {
+            value.push(f32::missing());
+        }
+        value.resize(width, *VECTOR_END_FLOAT);
+        encoding.extend(value);
+    }
+    record
+        .push_format_float(tag, &encoding)
+        .map_err(|e| e.to_string())
+}
+
+fn push_strings(record: &mut Record, tag: &[u8], values: Vec<Vec<u8>>) -> Result<()> {
+    record
+        .push_format_string(tag, &values)
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_call(gt: Vec<GenotypeAllele>, al: Vec<i32>) -> SampleCall {
+        SampleCall {
+            gt,
+            pl: Vec::new(),
+            gq: Vec::new(),
+            ps: Vec::new(),
+            al,
+            allr: Vec::new(),
+            sd: Vec::new(),
+            mc: Vec::new(),
+            ms: Vec::new(),
+            ap: Vec::new(),
+            ls: Vec::new(),
+            am: Vec::new(),
+            ah: Vec::new(),
+            aa: Vec::new(),
+            mp: Vec::new(),
+            md: Vec::new(),
+            mdp: Vec::new(),
+            lq: Vec::new(),
+            mi: Vec::new(),
+            read_counts: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn remap_alleles_to_merged_allele_list() {
+        let input_alleles = vec![b"CAG".to_vec(), b"CAGCAG".to_vec()];
+        let alleles: Vec<&[u8]> = vec![b"CAG", b"", b"CAGCAG"];
+        assert_eq!(
+            remap_allele(GenotypeAllele::Phased(1), &input_alleles, &alleles),
+            Ok(GenotypeAllele::Phased(2))
+        );
+        assert_eq!(
+            remap_allele(GenotypeAllele::Unphased(0), &input_alleles, &alleles),
+            Ok(GenotypeAllele::Unphased(0))
+        );
+        assert_eq!(
+            remap_allele(GenotypeAllele::UnphasedMissing, &input_alleles, &alleles),
+            Ok(GenotypeAllele::UnphasedMissing)
+        );
+
+        let other_ref: Vec<&[u8]> = vec![b"CAGCAA", b"CAGCAG"];
+        assert!(remap_allele(GenotypeAllele::Unphased(0), &input_alleles, &other_ref).is_err());
+    }
+
+    #[test]
+    fn remap_likelihoods_to_merged_allele_list() {
+        let input_alleles = vec![b"CAG".to_vec(), b"CAGCAG".to_vec()];
+        let alleles: Vec<&[u8]> = vec![b"CAG", b"", b"CAGCAG"];
+        let gt = vec![GenotypeAllele::Unphased(0), GenotypeAllele::Unphased(1)];
+        let mut call = make_call(gt, vec![3, 6]);
+        call.pl = vec![40, 0, 25];
+
+        let missing = i32::missing();
+        assert_eq!(
+            remap_pl(&call, &input_alleles, &alleles),
+            vec![40, missing, missing, 0, missing, 25]
+        );
+    }
+
+    #[test]
+    fn detect_padded_records() {
+        let alleles: Vec<&[u8]> = vec![b"TCAG", b"T"];
+        let calls = vec![
+            make_call(vec![GenotypeAllele::UnphasedMissing], vec![]),
+            make_call(
+                vec![GenotypeAllele::Unphased(1), GenotypeAllele::Unphased(0)],
+                vec![0, 3],
+            ),
+        ];
+        assert!(is_padded(&alleles, &calls));
+
+        let alleles: Vec<&[u8]> = vec![b"CAG", b"CAGCAG"];
+        let calls = vec![make_call(vec![GenotypeAllele::Unphased(1)], vec![6])];
+        assert!(!is_padded(&alleles, &calls));
+    }
+
+    #[test]
+    fn trim_vector_end_values() {
+        assert_eq!(trim_ints(&[3, VECTOR_END_INTEGER]), vec![3]);
+        assert_eq!(trim_floats(&[0.5, *VECTOR_END_FLOAT]), vec![0.5]);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::genotype::LenErrors;
+use crate::locate::TrgtScoring;
+use std::str::FromStr;
+
+/// Sequencing platform whose error profile determines the default settings
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Preset {
+    Hifi,
+    Ont,
+}
+
+impl FromStr for Preset {
+    type Err = &'static str;
+    fn from_str(preset: &str) -> Result<Self, Self::Err> {
+        match preset {
+            "hifi" => Ok(Preset::Hifi),
+            "ont" => Ok(Preset::Ont),
+            _ => Err("Invalid preset"),
+        }
+    }
+}
+
+impl Preset {
+    /// Scoring of flank alignments; noisier reads need shorter k-mers to seed
+    /// the alignment, wider bands, and cheaper gaps
+    pub fn aln_scoring(&self) -> TrgtScoring {
+        let (gapo_scr, kmer_len, bandwidth) = match self {
+            Preset::Hifi => (5, 8, 6),
+            Preset::Ont => (4, 7, 20),
+        };
+        TrgtScoring {
+            match_scr: 1,
+            mism_scr: 1,
+            gapo_scr,
+            gape_scr: 1,
+            kmer_len,
+            bandwidth,
+        }
+    }
+
+    pub fn min_flank_id_frac(&self) -> f32 {
+        match self {
+            Preset::Hifi => 0.7,
+            Preset::Ont => 0.65,
+        }
+    }
+
+    /// Minimum read accuracy given by the rq tag or, if it is missing and
+    /// base qualities are used, estimated from base qualities
+    pub fn min_read_qual(&self) -> f64 {
+        match self {
+            Preset::Hifi => 0.98,
+            Preset::Ont => 0.9,
+        }
+    }
+
+    /// Whether the accuracy of reads without the rq tag is estimated from base
+    /// qualities; HiFi reads lacking the tag are kept unfiltered
+    pub fn uses_base_quals(&self) -> bool {
+        matches!(self, Preset::Ont)
+    }
+
+    pub fn len_errors(&self) -> LenErrors {
+        match self {
+            Preset::Hifi => LenErrors::NONE,
+            Preset::Ont => LenErrors {
+                min_tolerance: 2,
+                tolerance_frac: 0.05,
+            },
+        }
+    }
+}
@@ -11,6 +11,45 @@ impl Cigar {
     pub fn query_len(&self) -> usize {
         self.ops.iter().map(|op| get_query_len(op) as usize).sum()
     }
+
+    pub fn ref_len(&self) -> i64 {
+        self.ops.iter().map(get_ref_len).sum()
+    }
+
+    pub fn has_matches(&self) -> bool {
+        self.ops.iter().any(|op| matches!(op, CigarOp::Match(_)))
+    }
+
+    /// Splits alignment matches (M) into sequence matches (=) and mismatches (X)
+    /// by comparing the query to the reference starting at `ref_start`; bases
+    /// outside of the reference are treated as matches
+    pub fn resolve_matches(&mut self, query: &[u8], reference: &[u8], ref_start: i64) {
+        let mut ops: Vec<CigarOp> = Vec::with_capacity(self.ops.len());
+        let mut ref_pos = self.ref_pos;
+        let mut query_pos = 0;
+        for op in &self.ops {
+            if let CigarOp::Match(len) = op {
+                for index in 0..*len as usize {
+                    let ref_base = reference.get((ref_pos - ref_start) as usize + index);
+                    let query_base = query[query_pos + index];
+                    let is_diff =
+                        ref_base.is_some_and(|base| !base.eq_ignore_ascii_case(&query_base));
+                    match (ops.last_mut(), is_diff) {
+                        (Some(CigarOp::Diff(len)), true) | (Some(CigarOp::Equal(len)), false) => {
+                            *len += 1
+                        }
+                        (_, true) => ops.push(CigarOp::Diff(1)),
+                        (_, false) => ops.push(CigarOp::Equal(1)),
+                    }
+                }
+            } else {
+                ops.push(*op);
+            }
+            ref_pos += get_ref_len(op);
+            query_pos += get_query_len(op) as usize;
+        }
+        self.ops = ops;
+    }
 }
 
 pub fn get_ref_len(op: &CigarOp) -> i64 {
@@ -34,3 +73,32 @@ pub fn get_query_len(op: &CigarOp) -> i64 {
         CigarOp::RefSkip(_) | CigarOp::Del(_) | CigarOp::HardClip(_) | CigarOp::Pad(_) => 0,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_matches_into_equal_and_diff_ops() {
+        let mut cigar = Cigar {
+            ref_pos: 12,
+            ops: vec![
+                CigarOp::SoftClip(2),
+                CigarOp::Match(4),
+                CigarOp::Ins(1),
+                CigarOp::Match(3),
+            ],
+        };
+        let reference = b"AAcgtaCGTA";
+        cigar.resolve_matches(b"TTCGTCAGGT", reference, 10);
+        let expected = vec![
+            CigarOp::SoftClip(2),
+            CigarOp::Equal(3),
+            CigarOp::Diff(1),
+            CigarOp::Ins(1),
+            CigarOp::Diff(1),
+            CigarOp::Equal(2),
+        ];
+        assert_eq!(cigar.ops, expected);
+    }
+}
@@ -1,4 +1,5 @@
 use super::cigar::{Cigar, CigarOp};
+use super::meth::clip_mods;
 use super::HiFiRead;
 use crate::cli::handle_error_and_exit;
 use crate::reads::cigar::{get_query_len, get_ref_len};
@@ -46,6 +47,7 @@ pub fn clip_bases(read: &HiFiRead, left_len: usize, right_len: usize) -> Option<
         cigar: clipped_cigar,
         id: read.id.clone(),
         mismatch_offsets: read.mismatch_offsets.clone(),
+        mods: clip_mods(&read.mods, left_len, read.bases.len() - right_len),
         ..*read
     })
 }
@@ -130,7 +132,10 @@ mod tests {
             end_offset: 0,
             cigar: Some(cigar),
             hp_tag: None,
+            ps_tag: None,
             mapq: 60,
+            is_reverse: false,
+            mods: Vec::new(),
         }
     }
 
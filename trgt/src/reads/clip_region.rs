@@ -1,3 +1,4 @@
+use super::meth::clip_mods;
 use super::HiFiRead;
 use crate::reads::cigar::{Cigar, CigarOp};
 
@@ -45,10 +46,17 @@ pub fn clip_to_region(read: HiFiRead, region: (i64, i64)) -> Option<HiFiRead> {
         ops: clipped_cigar,
     };
 
+    let clipped_mods = clip_mods(
+        &read.mods,
+        clipped_query_start as usize,
+        clipped_query_end as usize,
+    );
+
     Some(HiFiRead {
         bases: clipped_bases,
         meth: clipped_meth,
         cigar: Some(cigar),
+        mods: clipped_mods,
         ..read
     })
 }
@@ -185,7 +193,10 @@ mod tests {
             end_offset: 0,
             cigar: Some(cigar),
             hp_tag: None,
+            ps_tag: None,
             mapq: 60,
+            is_reverse: false,
+            mods: Vec::new(),
         }
     }
 
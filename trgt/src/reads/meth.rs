@@ -1,82 +1,223 @@
-use super::read::MethInfo;
-
-pub fn decode_on_plus(bases: &[u8], meth: &MethInfo) -> Option<Vec<u8>> {
-    let mut profile = Vec::new();
-
-    let mut num_cs_skipped = 0;
-    let mut cite_index = 0;
-    let mut non_cpgs_called = 0;
+//! Parsing of base modification calls stored in MM and ML tags as described
+//! in the SAM tags specification
+
+/// Calls of one modification type parsed from an MM tag entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModCalls {
+    /// Unmodified base in the original read
+    pub base: u8,
+    /// Whether the modification is on the opposite strand of the base
+    pub is_opposite_strand: bool,
+    /// Modification code, such as `m` for 5mC, or a ChEBI identifier
+    pub code: String,
+    /// Whether bases skipped by the tag are unmodified rather than unknown
+    pub skipped_unmodified: bool,
+    /// Positions in the stored read sequence and modification probabilities
+    pub calls: Vec<(usize, u8)>,
+}
 
-    for (index, base) in bases.iter().enumerate() {
-        if *base != b'C' || index + 1 == bases.len() {
-            continue;
+/// Parses MM and ML tags; positions in the tags refer to the original read,
+/// which is the reverse complement of the stored sequence of reverse strand
+/// alignments
+pub fn parse_mods(
+    mm_tag: &str,
+    ml_tag: &[u8],
+    bases: &[u8],
+    is_reverse: bool,
+) -> Result<Vec<ModCalls>, String> {
+    let read_bases = if is_reverse {
+        bases.iter().rev().map(|b| complement(*b)).collect()
+    } else {
+        bases.to_vec()
+    };
+
+    let mut probs = ml_tag.iter();
+    let mut all_mods = Vec::new();
+    for entry in mm_tag.split(';').filter(|entry| !entry.is_empty()) {
+        let mut fields = entry.split(',');
+        let header = fields.next().unwrap_or_default().as_bytes();
+        if header.len() < 3 || !matches!(header[1], b'+' | b'-') {
+            return Err(format!("Malformed MM entry: {}", entry));
         }
-
-        let dinuc = &bases[index..index + 2];
-
-        if dinuc == b"CG" {
-            profile.push(0);
+        let (base, is_opposite_strand) = (header[0], header[1] == b'-');
+        let (codes, skipped_unmodified) = match header[header.len() - 1] {
+            b'?' => (&header[2..header.len() - 1], false),
+            b'.' => (&header[2..header.len() - 1], true),
+            _ => (&header[2..], true),
+        };
+        let codes = std::str::from_utf8(codes).map_err(|e| e.to_string())?;
+        let codes = if codes.chars().all(|c| c.is_ascii_digit()) {
+            vec![codes.to_string()]
+        } else {
+            codes.chars().map(|c| c.to_string()).collect()
+        };
+        if codes.is_empty() || codes[0].is_empty() {
+            return Err(format!("Malformed MM entry: {}", entry));
         }
 
-        // TODO: Check if the first condition needed
-        if cite_index != meth.poses.len() && num_cs_skipped == meth.poses[cite_index] {
-            if dinuc == b"CG" {
-                *profile.last_mut().unwrap() = meth.probs[cite_index];
+        let mut positions = read_bases
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| base == b'N' || **b == base)
+            .map(|(pos, _)| pos);
+        let mut calls = vec![Vec::new(); codes.len()];
+        for delta in fields {
+            let delta = delta
+                .parse::<usize>()
+                .map_err(|_| format!("Malformed MM entry: {}", entry))?;
+            let pos = positions
+                .nth(delta)
+                .ok_or("MM tag refers to bases past the end of the read")?;
+            let pos = if is_reverse {
+                bases.len() - 1 - pos
             } else {
-                non_cpgs_called += 1;
+                pos
+            };
+            for code_calls in calls.iter_mut() {
+                let prob = probs.next().ok_or("ML tag is shorter than MM tag")?;
+                code_calls.push((pos, *prob));
             }
+        }
 
-            num_cs_skipped = 0;
-            cite_index += 1;
-        } else {
-            num_cs_skipped += 1;
+        for (code, mut calls) in codes.into_iter().zip(calls) {
+            if is_reverse {
+                calls.reverse();
+            }
+            all_mods.push(ModCalls {
+                base,
+                is_opposite_strand,
+                code,
+                skipped_unmodified,
+                calls,
+            });
         }
     }
 
-    if non_cpgs_called > 0 {
-        log::warn!("Warning: non_cpgs_called = {non_cpgs_called}");
+    if probs.next().is_some() {
+        return Err("ML tag is longer than MM tag".to_string());
     }
-
-    Some(profile)
+    Ok(all_mods)
 }
 
-pub fn decode_on_minus(bases: &[u8], meth: &MethInfo) -> Option<Vec<u8>> {
+/// Methylation probabilities of CpGs in the order of the stored sequence,
+/// taken from the 5mC calls of the C on the strand of the original read
+pub fn get_cpg_profile(bases: &[u8], is_reverse: bool, mods: &[ModCalls]) -> Option<Vec<u8>> {
+    let mods = mods
+        .iter()
+        .find(|m| m.code == "m" && m.base == b'C' && !m.is_opposite_strand)?;
+    let mut calls = mods.calls.iter().peekable();
     let mut profile = Vec::new();
-
-    let mut num_cs_skipped = 0;
-    let mut cite_index = 0;
-    let mut non_cpgs_called = 0;
-
-    for (index_rev, base) in bases.iter().rev().enumerate() {
-        let index = bases.len() - 1 - index_rev;
-
-        if *base != b'G' || index == 0 {
+    for index in 0..bases.len().saturating_sub(1) {
+        if &bases[index..index + 2] != b"CG" {
             continue;
         }
+        let pos = if is_reverse { index + 1 } else { index };
+        while calls.next_if(|(call_pos, _)| *call_pos < pos).is_some() {}
+        let prob = calls.next_if(|(call_pos, _)| *call_pos == pos);
+        profile.push(prob.map_or(0, |(_, prob)| *prob));
+    }
+    Some(profile)
+}
 
-        let dinuc = &bases[index - 1..index + 1];
+/// Mean probability of a modification over bases in the span that can carry
+/// it; bases skipped by the tag count as unmodified if the tag says so
+pub fn get_mod_level(
+    mods: &[ModCalls],
+    code: &str,
+    bases: &[u8],
+    is_reverse: bool,
+    span: &(usize, usize),
+) -> Option<f64> {
+    let mut total = 0.0;
+    let mut count = 0;
+    for mods in mods.iter().filter(|m| m.code == code) {
+        let in_span = |pos: &usize| span.0 <= *pos && *pos < span.1;
+        let called = mods.calls.iter().filter(|(pos, _)| in_span(pos));
+        total += called
+            .clone()
+            .map(|(_, prob)| *prob as f64 / 255.0)
+            .sum::<f64>();
+        count += if mods.skipped_unmodified {
+            let base = if is_reverse {
+                complement(mods.base)
+            } else {
+                mods.base
+            };
+            bases[span.0..span.1]
+                .iter()
+                .filter(|b| mods.base == b'N' || **b == base)
+                .count()
+        } else {
+            called.count()
+        };
+    }
 
-        if dinuc == b"CG" {
-            profile.push(0);
-        }
+    if count == 0 {
+        None
+    } else {
+        Some(total / count as f64)
+    }
+}
 
-        if cite_index != meth.poses.len() && num_cs_skipped == meth.poses[cite_index] {
-            if dinuc == b"CG" {
-                *profile.last_mut().unwrap() = meth.probs[cite_index];
-            } else {
-                non_cpgs_called += 1;
-            }
+/// Shifts modification calls to a subsequence of the read
+pub fn clip_mods(mods: &[ModCalls], start: usize, end: usize) -> Vec<ModCalls> {
+    mods.iter()
+        .map(|m| ModCalls {
+            calls: m
+                .calls
+                .iter()
+                .filter(|(pos, _)| start <= *pos && *pos < end)
+                .map(|(pos, prob)| (pos - start, *prob))
+                .collect(),
+            code: m.code.clone(),
+            ..*m
+        })
+        .collect()
+}
 
-            num_cs_skipped = 0;
-            cite_index += 1;
-        } else {
-            num_cs_skipped += 1;
-        }
+fn complement(base: u8) -> u8 {
+    match base {
+        b'A' => b'T',
+        b'C' => b'G',
+        b'G' => b'C',
+        b'T' => b'A',
+        _ => base,
     }
+}
 
-    if non_cpgs_called > 0 {
-        log::warn!("Warning: non_cpgs_called = {non_cpgs_called}");
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_multiple_modifications() {
+        let bases = b"ACGTCGACCA";
+        let mm_tag = "C+mh?,0,1;A+a.,2;";
+        let ml_tag = [200, 10, 100, 20, 250];
+        let mods = parse_mods(mm_tag, &ml_tag, bases, false).unwrap();
+
+        assert_eq!(mods.len(), 3);
+        assert_eq!(mods[0].code, "m");
+        assert_eq!(mods[0].calls, vec![(1, 200), (7, 100)]);
+        assert_eq!(mods[1].code, "h");
+        assert_eq!(mods[1].calls, vec![(1, 10), (7, 20)]);
+        assert!(!mods[1].skipped_unmodified);
+        assert_eq!(mods[2].calls, vec![(9, 250)]);
+        assert!(mods[2].skipped_unmodified);
+
+        assert_eq!(get_cpg_profile(bases, false, &mods), Some(vec![200, 0]));
+        let level = get_mod_level(&mods, "a", bases, false, &(0, 10)).unwrap();
+        assert!((level - 250.0 / 255.0 / 3.0).abs() < 1e-9);
+        assert!(parse_mods("C+m,5", &[100], bases, false).is_err());
+        assert!(parse_mods("C+m,0", &[100, 20], bases, false).is_err());
     }
 
-    Some(profile)
+    #[test]
+    fn parse_modifications_of_reverse_read() {
+        // The original read is CGATCGT
+        let bases = b"ACGATCG";
+        let mods = parse_mods("C+m,0,0", &[50, 150], bases, true).unwrap();
+        assert_eq!(mods[0].calls, vec![(2, 150), (6, 50)]);
+        assert_eq!(get_cpg_profile(bases, true, &mods), Some(vec![150, 50]));
+    }
 }
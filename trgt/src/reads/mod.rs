@@ -4,7 +4,10 @@ mod clip_region;
 mod meth;
 mod read;
 mod snp;
+mod stitch;
 
 pub use clip_bases::clip_bases;
 pub use clip_region::clip_to_region;
-pub use read::HiFiRead;
+pub use meth::get_mod_level;
+pub use read::{get_mean_base_accuracy, HiFiRead};
+pub use stitch::{replaces_primary, stitch_supplementary};
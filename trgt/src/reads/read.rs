@@ -1,13 +1,16 @@
-use super::{cigar::Cigar, meth, snp::extract_snps_offset};
+use super::meth::{self, ModCalls};
+use super::{cigar::Cigar, snp::extract_snps_offset};
 use crate::utils::GenomicRegion;
+use bio::alphabets::dna::revcomp;
 use itertools::Itertools;
+use lazy_static::lazy_static;
 use rust_htslib::bam::{self, ext::BamRecordExtensions, record::Aux};
 use std::str;
 
-#[derive(Debug)]
-pub struct MethInfo {
-    pub poses: Vec<usize>,
-    pub probs: Vec<u8>,
+lazy_static! {
+    /// Error probabilities of all Phred-scaled base qualities
+    static ref ERROR_PROBS: [f64; 256] =
+        std::array::from_fn(|qual| 10.0_f64.powf(-(qual as f64) / 10.0));
 }
 
 #[derive(PartialEq, Clone)]
@@ -21,7 +24,12 @@ pub struct HiFiRead {
     pub end_offset: i32,
     pub cigar: Option<Cigar>,
     pub hp_tag: Option<u8>,
+    /// Phase set of the haplotype given by the HP tag
+    pub ps_tag: Option<i32>,
     pub mapq: u8,
+    pub is_reverse: bool,
+    /// Base modification calls, including those summarized by `meth`
+    pub mods: Vec<ModCalls>,
 }
 
 impl std::fmt::Debug for HiFiRead {
@@ -41,25 +49,31 @@ impl std::fmt::Debug for HiFiRead {
 }
 
 impl HiFiRead {
-    pub fn from_hts_rec(rec: bam::Record, region: &GenomicRegion) -> HiFiRead {
+    /// Recovers mismatches of alignments that only use M operations
+    pub fn resolve_matches(&mut self, reference: &[u8], ref_start: i64, region: &GenomicRegion) {
+        if let Some(cigar) = self.cigar.as_mut() {
+            cigar.resolve_matches(&self.bases, reference, ref_start);
+            self.mismatch_offsets = Some(extract_snps_offset(cigar, region));
+        }
+    }
+
+    /// Creates a read from an aligned record; the accuracy of reads without the
+    /// rq tag is estimated from base qualities if `use_base_quals` is set
+    pub fn from_hts_rec(
+        rec: bam::Record,
+        region: &GenomicRegion,
+        use_base_quals: bool,
+    ) -> HiFiRead {
         let id = str::from_utf8(rec.qname()).unwrap().to_string();
         let bases = rec.seq().as_bytes();
 
-        let meth = get_mm_tag(&rec).and_then(|mm_tag| {
-            get_ml_tag(&rec)
-                .and_then(|ml_tag| parse_meth_tags(mm_tag, ml_tag))
-                .and_then(|tags| {
-                    if rec.is_reverse() {
-                        meth::decode_on_minus(&bases, &tags)
-                    } else {
-                        meth::decode_on_plus(&bases, &tags)
-                    }
-                })
-        });
+        let mods = get_mods(&rec, &bases, rec.is_reverse());
+        let meth = meth::get_cpg_profile(&bases, rec.is_reverse(), &mods);
 
         let mapq = rec.mapq();
         let hp_tag = get_hp_tag(&rec);
-        let read_qual = get_rq_tag(&rec);
+        let ps_tag = get_ps_tag(&rec);
+        let read_qual = get_read_qual(&rec, use_base_quals);
 
         let cigar = if !rec.is_unmapped() {
             Some(Cigar {
@@ -85,42 +99,81 @@ impl HiFiRead {
             end_offset,
             cigar,
             hp_tag,
+            ps_tag,
             mapq,
+            is_reverse: rec.is_reverse(),
+            mods,
         }
     }
-}
 
-fn parse_meth_tags(mm_tag: Aux, ml_tag: Aux) -> Option<MethInfo> {
-    let mm_tag = match mm_tag {
-        Aux::String(tag) => tag,
-        _ => panic!("Unexpected MM tag format: {:?}", mm_tag),
-    };
+    /// Creates a read from an unaligned record; the sequence is reverse
+    /// complemented if the read comes from the reverse strand of the reference
+    pub fn from_unaligned_rec(
+        rec: &bam::Record,
+        is_reverse: bool,
+        use_base_quals: bool,
+    ) -> HiFiRead {
+        let bases = rec.seq().as_bytes();
+        let bases = if is_reverse { revcomp(bases) } else { bases };
+        let mods = get_mods(rec, &bases, is_reverse);
+        let meth = meth::get_cpg_profile(&bases, is_reverse, &mods);
 
-    if mm_tag.is_empty() || mm_tag.len() <= 5 {
-        return None;
+        HiFiRead {
+            id: str::from_utf8(rec.qname()).unwrap().to_string(),
+            meth,
+            read_qual: get_read_qual(rec, use_base_quals),
+            hp_tag: get_hp_tag(rec),
+            ps_tag: get_ps_tag(rec),
+            mapq: rec.mapq(),
+            mods,
+            ..HiFiRead::from_seq(String::new(), bases, is_reverse)
+        }
     }
 
-    let mm_tag = if mm_tag.contains('?') {
-        &mm_tag[5..mm_tag.len() - 1]
-    } else {
-        &mm_tag[4..mm_tag.len() - 1]
-    };
-    let mm_tag = mm_tag
-        .split(',')
-        .map(|n| n.parse::<usize>().unwrap())
-        .collect::<Vec<_>>();
-
-    let ml_tag = match ml_tag {
-        Aux::ArrayU8(tag) => tag.iter().collect::<Vec<_>>(),
-        _ => panic!("Unexpected ML tag format: {:?}", ml_tag),
-    };
-
-    assert_eq!(mm_tag.len(), ml_tag.len());
+    /// Creates a read from a sequence without alignment or tags, such as a
+    /// FASTQ record
+    pub fn from_seq(id: String, bases: Vec<u8>, is_reverse: bool) -> HiFiRead {
+        HiFiRead {
+            id,
+            bases,
+            meth: None,
+            read_qual: None,
+            mismatch_offsets: None,
+            start_offset: 0,
+            end_offset: 0,
+            cigar: None,
+            hp_tag: None,
+            ps_tag: None,
+            mapq: 0,
+            is_reverse,
+            mods: Vec::new(),
+        }
+    }
+}
 
-    Some(MethInfo {
-        poses: mm_tag,
-        probs: ml_tag,
-    })
+/// Parses modification calls, which are discarded if the tags are malformed
+/// or describe a different sequence, such as that of a hard-clipped alignment
+fn get_mods(rec: &bam::Record, bases: &[u8], is_reverse: bool) -> Vec<ModCalls> {
+    let (Some(Aux::String(mm_tag)), Some(Aux::ArrayU8(ml_tag))) =
+        (get_mm_tag(rec), get_ml_tag(rec))
+    else {
+        return Vec::new();
+    };
+    if get_int_tag(rec, b"MN").is_some_and(|len| len != bases.len() as i64) {
+        return Vec::new();
+    }
+    let ml_tag = ml_tag.iter().collect::<Vec<_>>();
+    match meth::parse_mods(mm_tag, &ml_tag, bases, is_reverse) {
+        Ok(mods) => mods,
+        Err(err) => {
+            log::warn!(
+                "Ignoring modifications of read {}: {}",
+                str::from_utf8(rec.qname()).unwrap_or("?"),
+                err
+            );
+            Vec::new()
+        }
+    }
 }
 
 fn get_mm_tag(rec: &bam::Record) -> Option<Aux> {
@@ -131,6 +184,29 @@ fn get_ml_tag(rec: &bam::Record) -> Option<Aux> {
     rec.aux(b"ML").or_else(|_| rec.aux(b"Ml")).ok()
 }
 
+/// Read accuracy from the rq tag or, for reads without it, from base qualities
+/// if requested
+fn get_read_qual(rec: &bam::Record, use_base_quals: bool) -> Option<f64> {
+    match get_rq_tag(rec) {
+        Some(read_qual) => Some(read_qual),
+        None if use_base_quals => get_mean_base_accuracy(rec.qual()),
+        None => None,
+    }
+}
+
+/// Accuracy implied by the mean error probability of Phred-scaled base
+/// qualities; missing qualities are stored as 255
+pub fn get_mean_base_accuracy(quals: &[u8]) -> Option<f64> {
+    if quals.is_empty() || quals[0] == 255 {
+        return None;
+    }
+    let error_prob_sum = quals
+        .iter()
+        .map(|qual| ERROR_PROBS[*qual as usize])
+        .sum::<f64>();
+    Some(1.0 - error_prob_sum / quals.len() as f64)
+}
+
 fn get_rq_tag(rec: &bam::Record) -> Option<f64> {
     match rec.aux(b"rq") {
         Ok(Aux::Float(value)) => Some(f64::from(value)),
@@ -139,8 +215,39 @@ fn get_rq_tag(rec: &bam::Record) -> Option<f64> {
 }
 
 fn get_hp_tag(rec: &bam::Record) -> Option<u8> {
-    match rec.aux(b"HP") {
-        Ok(Aux::U8(value)) => Some(u8::from(value)),
+    get_int_tag(rec, b"HP").and_then(|value| u8::try_from(value).ok())
+}
+
+fn get_ps_tag(rec: &bam::Record) -> Option<i32> {
+    get_int_tag(rec, b"PS").and_then(|value| i32::try_from(value).ok())
+}
+
+/// Phasing tools store integer tags with varying widths and signedness
+fn get_int_tag(rec: &bam::Record, tag: &[u8]) -> Option<i64> {
+    match rec.aux(tag) {
+        Ok(Aux::U8(value)) => Some(value as i64),
+        Ok(Aux::U16(value)) => Some(value as i64),
+        Ok(Aux::U32(value)) => Some(value as i64),
+        Ok(Aux::I8(value)) => Some(value as i64),
+        Ok(Aux::I16(value)) => Some(value as i64),
+        Ok(Aux::I32(value)) => Some(value as i64),
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_qual_from_base_quals_only_if_requested() {
+        let mut rec = bam::Record::new();
+        rec.set(b"read", None, b"ACGT", &[10, 20, 20, 10]);
+        assert_eq!(get_read_qual(&rec, false), None);
+        let read_qual = get_read_qual(&rec, true).unwrap();
+        assert!((read_qual - 0.945).abs() < 1e-9);
+
+        rec.push_aux(b"rq", Aux::Float(0.99)).unwrap();
+        assert_eq!(get_read_qual(&rec, false), Some(f64::from(0.99_f32)));
+    }
+}
@@ -0,0 +1,296 @@
+use super::cigar::{get_query_len, get_ref_len, Cigar, CigarOp};
+use super::snp::extract_snps_offset;
+use super::HiFiRead;
+use crate::utils::GenomicRegion;
+use rust_htslib::bam::record::CigarString;
+
+/// Part of a read aligned by a primary or a supplementary record
+#[derive(Debug)]
+struct Segment {
+    ref_start: i64,
+    ref_end: i64,
+    query_start: usize,
+    query_end: usize,
+    /// Operations without the clips
+    ops: Vec<CigarOp>,
+}
+
+impl Segment {
+    fn new(ref_start: i64, ops: &[CigarOp]) -> Segment {
+        let is_clip = |op: &&CigarOp| matches!(op, CigarOp::SoftClip(_) | CigarOp::HardClip(_));
+        let query_start = ops
+            .iter()
+            .take_while(is_clip)
+            .map(|op| op.len() as usize)
+            .sum();
+        let ops: Vec<_> = ops
+            .iter()
+            .skip_while(is_clip)
+            .take_while(|op| !is_clip(op))
+            .copied()
+            .collect();
+        let ref_end = ref_start + ops.iter().map(get_ref_len).sum::<i64>();
+        let query_end = query_start + ops.iter().map(get_query_len).sum::<i64>() as usize;
+        Segment {
+            ref_start,
+            ref_end,
+            query_start,
+            query_end,
+            ops,
+        }
+    }
+}
+
+/// Joins the primary alignment of a read with one of its supplementary
+/// alignments listed in the SA tag if together, but neither alone, they span
+/// the region; the sequence between the two alignments becomes an insertion or
+/// a deletion. Reads with hard-clipped primary alignments are left as is, and
+/// reads with a supplementary alignment spanning the region are dropped since
+/// that alignment is used instead (see `replaces_primary`).
+pub fn stitch_supplementary(
+    read: HiFiRead,
+    sa_tag: &str,
+    region: &GenomicRegion,
+) -> Option<HiFiRead> {
+    let Some(cigar) = read.cigar.as_ref() else {
+        return Some(read);
+    };
+    if cigar
+        .ops
+        .iter()
+        .any(|op| matches!(op, CigarOp::HardClip(_)))
+    {
+        return Some(read);
+    }
+    let primary = Segment::new(cigar.ref_pos, &cigar.ops);
+    if spans(&primary, region) {
+        return Some(read);
+    }
+
+    let supplementaries = parse_sa_tag(sa_tag, &region.contig, read.is_reverse);
+    if supplementaries.iter().any(|s| spans(s, region)) {
+        return None;
+    }
+    let (start, end) = (region.start as i64, region.end as i64);
+
+    let read_len = read.bases.len();
+    let stitched_cigar = supplementaries
+        .iter()
+        .filter_map(|supplementary| {
+            let (left, right) = if supplementary.ref_start < primary.ref_start {
+                (supplementary, &primary)
+            } else {
+                (&primary, supplementary)
+            };
+            if left.ref_start <= start && end <= right.ref_end {
+                join_segments(left, right, read_len)
+            } else {
+                None
+            }
+        })
+        .next();
+    let Some(cigar) = stitched_cigar else {
+        return Some(read);
+    };
+
+    Some(HiFiRead {
+        start_offset: (cigar.ref_pos - start) as i32,
+        end_offset: (cigar.ref_pos + cigar.ref_len() - end) as i32,
+        mismatch_offsets: Some(extract_snps_offset(&cigar, region)),
+        cigar: Some(cigar),
+        ..read
+    })
+}
+
+/// Checks if a supplementary alignment spans the region while the primary
+/// alignment, which comes first in its SA tag, does not; of several such
+/// supplementary alignments, the leftmost one is used
+pub fn replaces_primary(supplementary: &HiFiRead, sa_tag: &str, region: &GenomicRegion) -> bool {
+    let Some(cigar) = supplementary.cigar.as_ref() else {
+        return false;
+    };
+    let segment = Segment::new(cigar.ref_pos, &cigar.ops);
+    if !spans(&segment, region) {
+        return false;
+    }
+    let Some((primary, others)) = sa_tag.split_once(';') else {
+        return false;
+    };
+    let is_reverse = supplementary.is_reverse;
+    let primary = parse_sa_tag(primary, &region.contig, is_reverse);
+    let others = parse_sa_tag(others, &region.contig, is_reverse);
+    !primary.iter().any(|p| spans(p, region))
+        && !others
+            .iter()
+            .any(|other| spans(other, region) && other.ref_start < segment.ref_start)
+}
+
+fn spans(segment: &Segment, region: &GenomicRegion) -> bool {
+    segment.ref_start <= region.start as i64 && region.end as i64 <= segment.ref_end
+}
+
+/// Parses alignments in the SA tag that are on the given contig and strand
+fn parse_sa_tag(sa_tag: &str, contig: &str, is_reverse: bool) -> Vec<Segment> {
+    sa_tag
+        .split(';')
+        .filter_map(|entry| {
+            let fields: Vec<&str> = entry.split(',').collect();
+            if fields.len() < 4 || fields[0] != contig || (fields[2] == "-") != is_reverse {
+                return None;
+            }
+            let pos = fields[1].parse::<i64>().ok()?;
+            let ops = CigarString::try_from(fields[3]).ok()?.to_vec();
+            Some(Segment::new(pos - 1, &ops))
+        })
+        .collect()
+}
+
+/// Creates an alignment of the whole read from two segments ordered along
+/// the reference; the part of the right segment overlapping the left one is
+/// dropped
+fn join_segments(left: &Segment, right: &Segment, read_len: usize) -> Option<Cigar> {
+    if right.query_start <= left.query_start || read_len < right.query_end {
+        return None;
+    }
+
+    let mut ref_pos = right.ref_start;
+    let mut query_pos = right.query_start as i64;
+    let mut gap = None;
+    let mut right_ops = Vec::new();
+    for op in &right.ops {
+        let (ref_len, query_len) = (get_ref_len(op), get_query_len(op));
+        let len = op.len() as i64;
+        let ref_overlap = left.ref_end - ref_pos;
+        let query_overlap = left.query_end as i64 - query_pos;
+        let skip = match (ref_len > 0, query_len > 0) {
+            (true, true) => ref_overlap.max(query_overlap),
+            (false, true) if ref_overlap > 0 => len,
+            (false, true) => query_overlap,
+            (true, false) if query_overlap > 0 => len,
+            (true, false) => ref_overlap,
+            (false, false) => 0,
+        }
+        .clamp(0, len);
+        ref_pos += ref_len.min(skip);
+        query_pos += query_len.min(skip);
+        if skip == len {
+            continue;
+        }
+
+        gap.get_or_insert((query_pos - left.query_end as i64, ref_pos - left.ref_end));
+        right_ops.push(resize_op(op, (len - skip) as u32));
+        ref_pos += ref_len - ref_len.min(skip);
+        query_pos += query_len - query_len.min(skip);
+    }
+    let (query_gap, ref_gap) = gap?;
+
+    let mut ops = Vec::new();
+    if left.query_start > 0 {
+        ops.push(CigarOp::SoftClip(left.query_start as u32));
+    }
+    ops.extend(&left.ops);
+    if query_gap > 0 {
+        ops.push(CigarOp::Ins(query_gap as u32));
+    }
+    if ref_gap > 0 {
+        ops.push(CigarOp::Del(ref_gap as u32));
+    }
+    ops.extend(right_ops);
+    if right.query_end < read_len {
+        ops.push(CigarOp::SoftClip((read_len - right.query_end) as u32));
+    }
+
+    Some(Cigar {
+        ref_pos: left.ref_start,
+        ops,
+    })
+}
+
+fn resize_op(op: &CigarOp, len: u32) -> CigarOp {
+    match op {
+        CigarOp::Match(_) => CigarOp::Match(len),
+        CigarOp::Ins(_) => CigarOp::Ins(len),
+        CigarOp::Del(_) => CigarOp::Del(len),
+        CigarOp::RefSkip(_) => CigarOp::RefSkip(len),
+        CigarOp::SoftClip(_) => CigarOp::SoftClip(len),
+        CigarOp::HardClip(_) => CigarOp::HardClip(len),
+        CigarOp::Pad(_) => CigarOp::Pad(len),
+        CigarOp::Equal(_) => CigarOp::Equal(len),
+        CigarOp::Diff(_) => CigarOp::Diff(len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_read(ref_pos: i64, cigar: &str) -> HiFiRead {
+        HiFiRead {
+            id: "read".to_string(),
+            bases: vec![b'A'; 150],
+            meth: None,
+            read_qual: None,
+            mismatch_offsets: None,
+            start_offset: 0,
+            end_offset: 0,
+            cigar: Some(Cigar {
+                ref_pos,
+                ops: CigarString::try_from(cigar).unwrap().to_vec(),
+            }),
+            hp_tag: None,
+            ps_tag: None,
+            mapq: 60,
+            is_reverse: false,
+            mods: Vec::new(),
+        }
+    }
+
+    fn get_stitched_cigar(sa_tag: &str) -> String {
+        let region = GenomicRegion::new("chrA:1080-1220").unwrap();
+        let read = stitch_supplementary(make_read(1000, "100=50S"), sa_tag, &region).unwrap();
+        CigarString(read.cigar.unwrap().ops).to_string()
+    }
+
+    #[test]
+    fn stitch_primary_and_supplementary_alignments() {
+        assert_eq!(
+            get_stitched_cigar("chrA,1201,+,100H50=,60,0;"),
+            "100=100D50="
+        );
+        assert_eq!(
+            get_stitched_cigar("chrA,1201,+,120H30=,60,0;"),
+            "100=20I100D30="
+        );
+        assert_eq!(
+            get_stitched_cigar("chrA,1191,+,90S60=,60,0;"),
+            "100=100D50="
+        );
+    }
+
+    #[test]
+    fn skip_unrelated_supplementary_alignments() {
+        assert_eq!(get_stitched_cigar("chrB,1201,+,100H50=,60,0;"), "100=50S");
+        assert_eq!(get_stitched_cigar("chrA,1201,-,100H50=,60,0;"), "100=50S");
+        assert_eq!(get_stitched_cigar("chrA,1101,+,100H50=,60,0;"), "100=50S");
+    }
+
+    #[test]
+    fn use_supplementary_alignment_spanning_repeat() {
+        let region = GenomicRegion::new("chrA:1080-1220").unwrap();
+        let primary = make_read(1000, "100=50S");
+        let sa_tag = "chrA,1071,+,20S60=20D70=,60,0;";
+        assert!(stitch_supplementary(primary, sa_tag, &region).is_none());
+
+        let supplementary = make_read(1070, "20H60=20D70=");
+        let sa_tag = "chrA,1001,+,100=50S,60,0;";
+        assert!(replaces_primary(&supplementary, sa_tag, &region));
+        // The primary alignment spans the repeat and is used instead
+        let sa_tag = "chrA,1001,+,100=150D50=,60,0;";
+        assert!(!replaces_primary(&supplementary, sa_tag, &region));
+        // Another supplementary alignment spans the repeat further left
+        let sa_tag = "chrA,1001,+,100=50S,60,0;chrA,1061,+,10S170=,60,0;";
+        assert!(!replaces_primary(&supplementary, sa_tag, &region));
+        let sa_tag = "chrA,1001,+,100=50S,60,0;chrA,1075,+,10S170=,60,0;";
+        assert!(replaces_primary(&supplementary, sa_tag, &region));
+    }
+}
@@ -0,0 +1,220 @@
+//! Assignment of unaligned reads to repeats by k-mers of their flanks
+
+use crate::locus::Locus;
+use crate::reads::{get_mean_base_accuracy, HiFiRead};
+use crate::workflows::Params;
+use crate::Sample;
+use bio::io::fastq;
+use flate2::read::MultiGzDecoder;
+use rust_htslib::bam::{self, record::Aux, Read};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read as ioRead};
+use std::path::Path;
+
+const KMER_LEN: usize = 16;
+
+/// Minimum number of flank k-mers that a read must share with a repeat
+const MIN_KMER_HITS: usize = 10;
+
+/// Index of k-mers of the repeat flanks; nearby repeats can share k-mers
+struct FlankIndex {
+    loci_by_kmer: HashMap<u32, Vec<usize>>,
+}
+
+impl FlankIndex {
+    fn new(loci: &[Locus], flank_len: usize) -> FlankIndex {
+        let mut index = FlankIndex {
+            loci_by_kmer: HashMap::new(),
+        };
+        for (locus_index, locus) in loci.iter().enumerate() {
+            let (left_piece, right_piece) = locus.get_flank_pieces(flank_len);
+            index.add_flanks(locus_index, &[left_piece, right_piece]);
+        }
+        index
+    }
+
+    fn add_flanks(&mut self, locus_index: usize, flanks: &[&str]) {
+        for flank in flanks {
+            for (kmer, _) in get_kmers(flank.as_bytes()) {
+                let loci = self.loci_by_kmer.entry(kmer).or_default();
+                if loci.last() != Some(&locus_index) {
+                    loci.push(locus_index);
+                }
+            }
+        }
+    }
+
+    /// Finds the repeats whose flanks share enough k-mers with the read and
+    /// whether the read is reverse complemented relative to the reference
+    fn recruit(&self, bases: &[u8]) -> Vec<(usize, bool)> {
+        let mut hits: HashMap<usize, (usize, usize)> = HashMap::new();
+        for (kmer, rc_kmer) in get_kmers(bases) {
+            for index in self.loci_by_kmer.get(&kmer).into_iter().flatten() {
+                hits.entry(*index).or_default().0 += 1;
+            }
+            for index in self.loci_by_kmer.get(&rc_kmer).into_iter().flatten() {
+                hits.entry(*index).or_default().1 += 1;
+            }
+        }
+
+        let mut recruited = hits
+            .into_iter()
+            .filter(|(_, (fw_hits, rc_hits))| fw_hits.max(rc_hits) >= &MIN_KMER_HITS)
+            .map(|(index, (fw_hits, rc_hits))| (index, rc_hits > fw_hits))
+            .collect::<Vec<_>>();
+        recruited.sort_unstable();
+        recruited
+    }
+}
+
+/// Encodes k-mers of the sequence and their reverse complements, skipping
+/// k-mers with bases other than ACGT
+fn get_kmers(seq: &[u8]) -> impl Iterator<Item = (u32, u32)> + '_ {
+    let mask = u32::MAX >> (32 - 2 * KMER_LEN);
+    let (mut kmer, mut rc_kmer, mut num_valid) = (0u32, 0u32, 0);
+    seq.iter().filter_map(move |base| {
+        let code = match base.to_ascii_uppercase() {
+            b'A' => 0,
+            b'C' => 1,
+            b'G' => 2,
+            b'T' => 3,
+            _ => {
+                num_valid = 0;
+                return None;
+            }
+        };
+        kmer = ((kmer << 2) | code) & mask;
+        rc_kmer = (rc_kmer >> 2) | ((3 - code) << (2 * (KMER_LEN - 1)));
+        num_valid += 1;
+        (num_valid >= KMER_LEN).then_some((kmer, rc_kmer))
+    })
+}
+
+/// Assigns unaligned reads of a sample (BAM or FASTQ) to repeats and orients
+/// them along the reference; the reads are grouped by repeat ID
+pub fn recruit_reads(
+    loci: &[Locus],
+    params: &Params,
+    sample: &Sample,
+) -> Result<HashMap<String, Vec<HiFiRead>>, String> {
+    let index = FlankIndex::new(loci, params.search_flank_len);
+    let mut reads_by_locus: HashMap<String, Vec<HiFiRead>> = HashMap::new();
+    let mut num_reads = 0;
+    let mut num_recruited = 0;
+    // Reads are quality filtered when genotyping each repeat
+    let mut add_read = |locus_index: usize, read: HiFiRead| {
+        let id = loci[locus_index].id.clone();
+        reads_by_locus.entry(id).or_default().push(read);
+    };
+
+    if is_fastq(&sample.reads_path) {
+        let file = File::open(&sample.reads_path).map_err(|e| e.to_string())?;
+        let file: Box<dyn ioRead> = if has_extension(&sample.reads_path, "gz") {
+            Box::new(MultiGzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        for record in fastq::Reader::new(BufReader::new(file)).records() {
+            let record = record.map_err(|e| e.to_string())?;
+            num_reads += 1;
+            let bases = record.seq().to_ascii_uppercase();
+            let recruited = index.recruit(&bases);
+            num_recruited += !recruited.is_empty() as usize;
+            let read_qual = if params.use_base_quals {
+                let quals = record.qual().iter().map(|q| q.saturating_sub(33));
+                get_mean_base_accuracy(&quals.collect::<Vec<_>>())
+            } else {
+                None
+            };
+            for (locus_index, is_reverse) in recruited {
+                let bases = if is_reverse {
+                    bio::alphabets::dna::revcomp(&bases)
+                } else {
+                    bases.clone()
+                };
+                let read = HiFiRead {
+                    read_qual,
+                    ..HiFiRead::from_seq(record.id().to_string(), bases, is_reverse)
+                };
+                add_read(locus_index, read);
+            }
+        }
+    } else {
+        let mut reader = bam::Reader::from_path(&sample.reads_path)
+            .map_err(|e| format!("Failed to create bam reader: {}", e))?;
+        for rec in reader.records() {
+            let rec = rec.map_err(|e| e.to_string())?;
+            if rec.is_supplementary() || rec.is_secondary() {
+                continue;
+            }
+            if let Some(read_groups) = &sample.read_groups {
+                match rec.aux(b"RG") {
+                    Ok(Aux::String(rg)) if read_groups.contains(rg) => {}
+                    _ => continue,
+                }
+            }
+            num_reads += 1;
+            let recruited = index.recruit(&rec.seq().as_bytes());
+            num_recruited += !recruited.is_empty() as usize;
+            for (locus_index, is_reverse) in recruited {
+                add_read(
+                    locus_index,
+                    HiFiRead::from_unaligned_rec(&rec, is_reverse, params.use_base_quals),
+                );
+            }
+        }
+    }
+
+    log::info!(
+        "{}: Recruited {} of {} reads to repeats",
+        sample.name,
+        num_recruited,
+        num_reads
+    );
+    Ok(reads_by_locus)
+}
+
+pub fn is_fastq(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    [".fastq", ".fq"].iter().any(|ext| name.ends_with(ext))
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().is_some_and(|ext| ext == extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_kmers_of_both_strands() {
+        let seq = b"ACGTTGCAAGCTTCGATCN";
+        let kmers = get_kmers(seq).collect::<Vec<_>>();
+        assert_eq!(kmers.len(), 3);
+        let rc = bio::alphabets::dna::revcomp(&seq[..18]);
+        let rc_kmers = get_kmers(&rc).collect::<Vec<_>>();
+        assert_eq!(kmers[0].0, rc_kmers[2].1);
+        assert_eq!(kmers[2].1, rc_kmers[0].0);
+    }
+
+    #[test]
+    fn recruit_reads_by_flanks_on_either_strand() {
+        let left = "TGACCTAGGCATTCAGGTACCATGGATCGAATCCGTAGCTTGACA";
+        let right = "GGATCCTTAGCAATGCGTTACGGCTAACGTTAGCCATGGTTACAG";
+        let other = "CAGTTCGACGGATACTTGGCAACTGTGCACTATCGGATTACGCAA";
+        let mut index = FlankIndex {
+            loci_by_kmer: HashMap::new(),
+        };
+        index.add_flanks(0, &[left, right]);
+        index.add_flanks(1, &[right, other]);
+
+        let read = format!("{}{}{}", left, "CAG".repeat(20), right);
+        assert_eq!(index.recruit(read.as_bytes()), vec![(0, false), (1, false)]);
+        let read = bio::alphabets::dna::revcomp(format!("{}CAGCAG", left).as_bytes());
+        assert_eq!(index.recruit(&read), vec![(0, true)]);
+        assert!(index.recruit(&left.as_bytes()[..20]).is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+use crate::reads::HiFiRead;
+use crate::workflows::CpgSite;
+use bio::alignment::pairwise::{banded::Aligner, Scoring, MIN_SCORE};
+use bio::alignment::AlignmentOperation;
+use std::collections::BTreeMap;
+
+/// The band around the diagonal is widened by the length difference between
+/// the read and the allele
+const MIN_BANDWIDTH: usize = 50;
+
+/// Aggregates methylation of CpGs in reads assigned to an allele by their
+/// position relative to the start of the allele; positions in the left flank
+/// are negative and positions in the right flank follow the end of the allele
+pub fn get_cpg_profile(
+    allele_seq: &str,
+    flank_lens: (usize, usize),
+    reads: &[&HiFiRead],
+    spans: &[&(usize, usize)],
+) -> Vec<CpgSite> {
+    let mut levels: BTreeMap<i64, (f64, usize)> = BTreeMap::new();
+    for (read, span) in reads.iter().zip(spans) {
+        let Some(meth) = read.meth.as_ref().filter(|meth| !meth.is_empty()) else {
+            continue;
+        };
+        let read_tr = &read.bases[span.0..span.1];
+        let tr_map = map_tr_positions(read_tr, allele_seq.as_bytes());
+        let cpg_poses =
+            (0..read.bases.len() - 1).filter(|pos| &read.bases[*pos..*pos + 2] == b"CG");
+        for (pos, level) in cpg_poses.zip(meth) {
+            let allele_pos = if pos < span.0 {
+                Some(pos as i64 - span.0 as i64).filter(|p| -p <= flank_lens.0 as i64)
+            } else if pos < span.1 {
+                tr_map[pos - span.0].map(|p| p as i64)
+            } else {
+                let offset = pos - span.1;
+                Some((allele_seq.len() + offset) as i64).filter(|_| offset < flank_lens.1)
+            };
+            if let Some(allele_pos) = allele_pos {
+                let entry = levels.entry(allele_pos).or_default();
+                entry.0 += *level as f64 / 255.0;
+                entry.1 += 1;
+            }
+        }
+    }
+
+    levels
+        .into_iter()
+        .map(|(pos, (total, depth))| CpgSite {
+            pos,
+            level: total / depth as f64,
+            depth,
+        })
+        .collect()
+}
+
+/// Maps each position of the repeat sequence of a read to the aligned
+/// position of the allele, if any. Sequences are aligned within a band along
+/// the diagonal; no position is mapped if the band would be too large.
+fn map_tr_positions(read_tr: &[u8], allele: &[u8]) -> Vec<Option<usize>> {
+    if read_tr == allele {
+        return (0..read_tr.len()).map(Some).collect();
+    }
+    if read_tr.is_empty() || allele.is_empty() {
+        return vec![None; read_tr.len()];
+    }
+
+    let scoring = Scoring::new(-5, -1, |a: u8, b: u8| if a == b { 1i32 } else { -1i32 })
+        .xclip(MIN_SCORE)
+        .yclip(MIN_SCORE);
+    let bandwidth = MIN_BANDWIDTH + read_tr.len().abs_diff(allele.len());
+    let mut aligner = Aligner::with_scoring(scoring, 1, bandwidth);
+    let ends = [(0, 0), (read_tr.len() as u32 - 1, allele.len() as u32 - 1)];
+    let alignment = aligner.custom_with_match_path(read_tr, allele, &ends, &[0, 1]);
+    if alignment.operations.is_empty() {
+        return vec![None; read_tr.len()];
+    }
+    let mut positions = Vec::with_capacity(read_tr.len());
+    let mut allele_pos = 0;
+    for op in alignment.operations {
+        match op {
+            AlignmentOperation::Match | AlignmentOperation::Subst => {
+                positions.push(Some(allele_pos));
+                allele_pos += 1;
+            }
+            AlignmentOperation::Ins => positions.push(None),
+            AlignmentOperation::Del => allele_pos += 1,
+            AlignmentOperation::Xclip(_) | AlignmentOperation::Yclip(_) => {}
+        }
+    }
+    positions
+}
+
+/// Encodes CpG sites as `position|level|depth` separated by `_`; colons
+/// would be taken for separators of FORMAT fields
+pub fn encode_cpg_profile(profile: &[CpgSite]) -> String {
+    if profile.is_empty() {
+        return ".".to_string();
+    }
+    profile
+        .iter()
+        .map(|site| format!("{}|{:.2}|{}", site.pos, site.level, site.depth))
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_read(bases: &str, meth: Vec<u8>) -> HiFiRead {
+        HiFiRead {
+            id: "read".to_string(),
+            bases: bases.as_bytes().to_vec(),
+            meth: Some(meth),
+            read_qual: None,
+            mismatch_offsets: None,
+            start_offset: 0,
+            end_offset: 0,
+            cigar: None,
+            hp_tag: None,
+            ps_tag: None,
+            mapq: 60,
+            is_reverse: false,
+            mods: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn profile_in_allele_coordinates() {
+        // Flanks are ACGT and TCGA around the repeat
+        let read1 = make_read("ACGTCGGCGGCGGTCGA", vec![255, 255, 255, 255, 0]);
+        // The second read lacks one CGG unit
+        let read2 = make_read("ACGTCGGCGGTCGA", vec![0, 255, 255, 255]);
+        let spans = [(4, 13), (4, 10)];
+        let profile = get_cpg_profile(
+            "CGGCGGCGG",
+            (4, 4),
+            &[&read1, &read2],
+            &[&spans[0], &spans[1]],
+        );
+
+        let encoding = encode_cpg_profile(&profile);
+        assert_eq!(encoding, "-3|0.50|2_0|1.00|1_3|1.00|2_6|1.00|2_10|0.50|2");
+    }
+
+    #[test]
+    fn map_long_repeat_with_deletion() {
+        let allele = format!("{}T{}", "CGG".repeat(2000), "CGG".repeat(2000));
+        let read = format!("{}T{}", "CGG".repeat(1990), "CGG".repeat(2000));
+        let positions = map_tr_positions(read.as_bytes(), allele.as_bytes());
+        assert_eq!(positions.len(), read.len());
+        assert!(positions.iter().all(|pos| pos.is_some()));
+        assert_eq!(positions[5970], Some(6000));
+        assert_eq!(positions[read.len() - 1], Some(allele.len() - 1));
+    }
+}
@@ -1,16 +1,96 @@
 use crate::{label::Annotation, reads::HiFiRead};
-use arrayvec::ArrayVec;
 
 #[derive(Debug)]
 pub struct Allele {
     pub seq: String,
+    /// Fraction of reads agreeing with each base of the allele sequence
+    pub support: Vec<f64>,
     pub annotation: Annotation,
     pub ci: (usize, usize),
     pub num_spanning: usize,
     pub meth: Option<f64>,
+    /// Mean 5hmC and 6mA levels of the repeat sequence
+    pub hmc: Option<f64>,
+    pub m6a: Option<f64>,
+    pub len_dist: Option<LenDist>,
+    /// Methylation of CpGs in the allele and its flanks, if requested
+    pub cpg_profile: Vec<CpgSite>,
 }
 
-pub type Genotype = ArrayVec<Allele, 2>;
+/// Mean methylation of a CpG across reads assigned to an allele
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpgSite {
+    /// Position relative to the start of the allele; negative in the left flank
+    pub pos: i64,
+    pub level: f64,
+    pub depth: usize,
+}
+
+/// Distribution of repeat lengths of reads assigned to an allele
+#[derive(Debug, Clone, PartialEq)]
+pub struct LenDist {
+    /// 5th, 25th, 50th, 75th, and 95th percentiles
+    pub quantiles: [usize; 5],
+    /// Mean change from the modal length in motif units
+    pub instability: f64,
+}
+
+pub type Genotype = Vec<Allele>;
+
+/// Quality metrics of a genotyped locus
+#[derive(Debug, Default)]
+pub struct LocusQc {
+    /// Phred-scaled confidence in the genotype
+    pub quality: f64,
+    /// Whether spanning reads were downsampled to the maximum depth
+    pub is_downsampled: bool,
+    /// Number of reads overlapping the repeat after clipping
+    pub num_reads: usize,
+    /// Number of reads overlapping the repeat removed by the read quality
+    /// filter
+    pub num_quality_filtered: usize,
+    /// Number of reads containing only the left flank
+    pub num_left_flanking: usize,
+    /// Number of reads containing only the right flank
+    pub num_right_flanking: usize,
+    /// Number of reads with the flanks out of order
+    pub num_discordant: usize,
+    /// Number of spanning reads whose flanks were too short for genotyping
+    pub num_short_flanks: usize,
+    /// Lower bound on the length of the longest allele, set when flanking
+    /// reads contain more repeat sequence than any spanning read
+    pub min_allele_len: Option<usize>,
+}
+
+/// Phase of a genotype whose alleles are ordered by haplotype
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phasing {
+    /// Phase set given by the PS tags of the haplotagged reads
+    pub phase_set: Option<i32>,
+}
+
+/// Difference in methylation between the alleles of a heterozygous genotype
+#[derive(Debug, Clone, PartialEq)]
+pub struct MethDiff {
+    /// Mean methylation of the second allele minus that of the first one
+    pub effect: f64,
+    /// P-value of the Mann-Whitney U test on per-read methylation levels
+    pub p_value: f64,
+}
+
+/// Likelihoods of the genotypes formed from the lengths of the reference
+/// allele and of the called alleles, which the length model cannot tell apart
+/// beyond their lengths
+#[derive(Debug, Clone, PartialEq)]
+pub struct Likelihoods {
+    /// Distinct allele lengths, starting with the reference allele
+    pub allele_lens: Vec<usize>,
+    /// Phred-scaled likelihoods of genotypes over allele_lens in the order
+    /// used by the PL field
+    pub pl: Vec<i32>,
+    /// Phred-scaled probability that the called genotype is wrong
+    pub gq: i32,
+}
 
 #[derive(Debug)]
 pub struct LocusResult {
@@ -18,6 +98,13 @@ pub struct LocusResult {
     pub reads: Vec<HiFiRead>,
     pub tr_spans: Vec<(usize, usize)>,
     pub classification: Vec<i32>,
+    pub qc: LocusQc,
+    pub phasing: Option<Phasing>,
+    pub meth_diff: Option<MethDiff>,
+    /// Set for repeats genotyped by size
+    pub likelihoods: Option<Likelihoods>,
+    /// Motif annotations of the repeat sequence of each read, if requested
+    pub read_annotations: Vec<Annotation>,
 }
 
 impl LocusResult {
@@ -27,6 +114,11 @@ impl LocusResult {
             reads: Vec::new(),
             tr_spans: Vec::new(),
             classification: Vec::new(),
+            qc: LocusQc::default(),
+            phasing: None,
+            meth_diff: None,
+            likelihoods: None,
+            read_annotations: Vec::new(),
         }
     }
 }
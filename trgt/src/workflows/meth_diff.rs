@@ -0,0 +1,94 @@
+use crate::reads::HiFiRead;
+use crate::workflows::{get_tr_meth, Genotype, MethDiff};
+use statrs::distribution::{ContinuousCDF, Normal};
+
+/// Minimum number of reads with methylation calls required on each allele
+const MIN_READS_PER_ALLELE: usize = 3;
+
+/// Compares per-read mean methylation of the repeat sequence between the two
+/// alleles of a heterozygous diploid genotype with a Mann-Whitney U test
+pub fn get_meth_diff(
+    genotype: &Genotype,
+    reads: &[HiFiRead],
+    spans: &[(usize, usize)],
+    classification: &[i32],
+) -> Option<MethDiff> {
+    if genotype.len() != 2 || genotype[0].seq == genotype[1].seq {
+        return None;
+    }
+
+    let mut meths = [Vec::new(), Vec::new()];
+    for ((read, span), allele) in reads.iter().zip(spans).zip(classification) {
+        if *allele != 0 && *allele != 1 {
+            continue;
+        }
+        if let Some(meth) = get_tr_meth(read, span) {
+            meths[*allele as usize].push(meth);
+        }
+    }
+    if meths.iter().any(|m| m.len() < MIN_READS_PER_ALLELE) {
+        return None;
+    }
+
+    let mean = |values: &[f64]| values.iter().sum::<f64>() / values.len() as f64;
+    Some(MethDiff {
+        effect: mean(&meths[1]) - mean(&meths[0]),
+        p_value: mann_whitney_test(&meths[0], &meths[1]),
+    })
+}
+
+/// Two-sided p-value of the Mann-Whitney U test under the normal
+/// approximation with tie and continuity corrections
+fn mann_whitney_test(values1: &[f64], values2: &[f64]) -> f64 {
+    let (n1, n2) = (values1.len() as f64, values2.len() as f64);
+    let mut values = values1
+        .iter()
+        .map(|v| (*v, true))
+        .chain(values2.iter().map(|v| (*v, false)))
+        .collect::<Vec<_>>();
+    values.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut rank_sum = 0.0;
+    let mut tie_term = 0.0;
+    let mut start = 0;
+    while start < values.len() {
+        let end = start
+            + values[start..]
+                .iter()
+                .take_while(|v| v.0 == values[start].0)
+                .count();
+        let rank = (start + end + 1) as f64 / 2.0;
+        rank_sum += rank * values[start..end].iter().filter(|v| v.1).count() as f64;
+        let ties = (end - start) as f64;
+        tie_term += ties.powi(3) - ties;
+        start = end;
+    }
+
+    let n = n1 + n2;
+    let u = rank_sum - n1 * (n1 + 1.0) / 2.0;
+    let var = n1 * n2 / 12.0 * ((n + 1.0) - tie_term / (n * (n - 1.0)));
+    if var <= 0.0 {
+        return 1.0;
+    }
+    let z = ((u - n1 * n2 / 2.0).abs() - 0.5).max(0.0) / var.sqrt();
+    let normal = Normal::new(0.0, 1.0).unwrap();
+    (2.0 * (1.0 - normal.cdf(z))).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mann_whitney_p_values() {
+        // Matches scipy.stats.mannwhitneyu with method="asymptotic"
+        let p_value = mann_whitney_test(&[0.1, 0.2, 0.15, 0.3], &[0.8, 0.9, 0.7, 0.85, 0.95]);
+        assert!((p_value - 0.01996).abs() < 1e-4);
+
+        let p_value = mann_whitney_test(&[0.5, 0.5, 0.5], &[0.5, 0.5, 0.5]);
+        assert_eq!(p_value, 1.0);
+
+        let p_value = mann_whitney_test(&[0.1, 0.5, 0.9], &[0.2, 0.4, 0.8]);
+        assert!(p_value > 0.5);
+    }
+}
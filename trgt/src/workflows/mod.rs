@@ -1,6 +1,16 @@
 mod tr;
 pub use tr::analyze as analyze_tr;
+pub use tr::analyze_reads as analyze_tr_reads;
+pub use tr::get_tr_meth;
 pub use tr::Params;
+pub use tr::CLIP_RADIUS;
 
 mod locus_result;
-pub use locus_result::{Allele, Genotype, LocusResult};
+pub use locus_result::{
+    Allele, CpgSite, Genotype, LenDist, Likelihoods, LocusQc, LocusResult, MethDiff, Phasing,
+};
+
+mod cpg_profile;
+pub use cpg_profile::encode_cpg_profile;
+
+mod meth_diff;
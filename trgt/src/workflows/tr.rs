@@ -1,48 +1,115 @@
 use crate::cluster;
-use crate::genotype::{self, flank_genotype, Gt};
+use crate::faidx;
+use crate::genotype::{self, flank_genotype, Gt, LenErrors, Ploidy};
 use crate::label::label_alleles;
-use crate::locate::{find_tr_spans, TrgtScoring};
+use crate::locate::{find_tr_spans, TrSpan, TrgtScoring};
 use crate::locus::{Genotyper, Locus};
-use crate::reads::{clip_to_region, HiFiRead};
-use crate::workflows::{Allele, Genotype, LocusResult};
+use crate::reads::{
+    clip_bases, clip_to_region, get_mod_level, replaces_primary, stitch_supplementary, HiFiRead,
+};
+use crate::workflows::cpg_profile::get_cpg_profile;
+use crate::workflows::meth_diff::get_meth_diff;
+use crate::workflows::{Allele, Genotype, LenDist, Likelihoods, LocusQc, LocusResult, Phasing};
 use itertools::Itertools;
-use rust_htslib::bam;
+use rust_htslib::bam::{self, record::Aux};
+use std::collections::HashSet;
 use std::vec;
 
 pub type Result<T> = std::result::Result<T, String>;
 
+/// Reads are clipped to this many bases around the repeat before analysis
+pub const CLIP_RADIUS: usize = 500;
+
+/// Probability that all reads supporting an allele are artifacts
+const ARTIFACT_PROB: f64 = 0.1;
+
+/// Upper bound on the genotype quality
+const MAX_QUALITY: f64 = 100.0;
+
+/// Minimum number of haplotagged reads supporting each allele of a phased
+/// genotype and the minimum fraction of haplotagged reads agreeing with it
+const MIN_PHASED_READS: usize = 2;
+const MIN_PHASE_CONSISTENCY: f64 = 0.8;
+
+/// Modification codes of 5hmC and 6mA in MM tags
+const MOD_CODE_5HMC: &str = "h";
+const MOD_CODE_6MA: &str = "a";
+
+/// Lengths supported by fewer reads relative to the modal length are ignored
+/// by the instability index
+const MIN_PEAK_FRAC: f64 = 0.2;
+
 pub struct Params {
     pub search_flank_len: usize,
     pub min_read_qual: f64,
+    /// Whether the accuracy of reads without the rq tag is estimated from
+    /// their base qualities
+    pub use_base_quals: bool,
     pub max_depth: usize,
     pub aln_scoring: TrgtScoring,
     pub min_flank_id_frac: f32,
+    /// Length errors tolerated when genotyping repeats by size
+    pub len_errors: LenErrors,
+    /// Whether motifs are labeled in the repeat sequence of each read
+    pub annotate_reads: bool,
+    /// Whether methylation of individual CpGs is profiled for each allele
+    pub cpg_profiles: bool,
+    /// Whether primary alignments are joined with supplementary alignments of
+    /// the same read to span the repeat
+    pub stitch_supplementary: bool,
 }
 
 pub fn analyze(
     locus: &Locus,
     params: &Params,
     bam: &mut bam::IndexedReader,
+    genome: &faidx::Reader,
+    read_groups: Option<&HashSet<String>>,
+    ploidy: Ploidy,
 ) -> Result<LocusResult> {
-    if locus.ploidy == genotype::Ploidy::Zero {
+    if ploidy == Ploidy::ZERO {
         return Ok(LocusResult::empty());
     }
-    let reads = extract_reads(
+    let mut reads = extract_reads(
         locus,
         bam,
         params.search_flank_len as u32,
-        params.min_read_qual,
+        params.use_base_quals,
+        params.stitch_supplementary,
+        read_groups,
     )?;
+    if reads
+        .iter()
+        .any(|r| r.cigar.as_ref().is_some_and(|c| c.has_matches()))
+    {
+        resolve_matches(locus, genome, &mut reads)?;
+    }
     log::debug!("{}: Collected {} reads", locus.id, reads.len());
 
-    let clip_radius = 500;
-    let reads = clip_reads(locus, clip_radius, reads);
+    let reads = clip_reads(locus, CLIP_RADIUS, reads);
     log::debug!("{}: {} reads left after clipping", locus.id, reads.len());
 
-    let (reads, spans) = get_spanning_reads(locus, params, reads);
-    if reads.is_empty() {
+    analyze_reads(locus, params, ploidy, reads)
+}
+
+/// Genotypes the repeat using reads that were clipped around it or, for
+/// unaligned input, recruited to it
+pub fn analyze_reads(
+    locus: &Locus,
+    params: &Params,
+    ploidy: Ploidy,
+    reads: Vec<HiFiRead>,
+) -> Result<LocusResult> {
+    if ploidy == Ploidy::ZERO {
         return Ok(LocusResult::empty());
     }
+    let (reads, spans, mut qc) = get_spanning_reads(locus, params, reads);
+    if reads.is_empty() {
+        return Ok(LocusResult {
+            qc,
+            ..LocusResult::empty()
+        });
+    }
 
     let trs = reads
         .iter()
@@ -50,11 +117,11 @@ pub fn analyze(
         .map(|(r, s)| std::str::from_utf8(&r.bases[s.0..s.1]).unwrap())
         .collect_vec();
 
-    let (mut gt, mut allele_seqs, mut classification) = match locus.genotyper {
-        Genotyper::Size => genotype::genotype(locus.ploidy, &trs),
+    let (mut gt, mut alleles, mut classification) = match locus.genotyper {
+        Genotyper::Size => genotype::genotype(ploidy, &trs, &params.len_errors),
         Genotyper::Cluster => {
             let spanning = reads.iter().map(|r| &r.bases[..]).collect_vec();
-            cluster::genotype(&spanning, &trs)
+            cluster::genotype(ploidy.count(), &spanning, &trs)
         }
     };
 
@@ -62,70 +129,295 @@ pub fn analyze(
     if gt.len() == 2 && gt[0].size.abs_diff(gt[1].size) <= 10 {
         let snp_result = flank_genotype(&reads, &trs);
         if let Some((snp_gt, snp_alleles, snp_assignment)) = snp_result {
-            (gt, allele_seqs, classification) = (snp_gt, snp_alleles, snp_assignment);
+            (gt, alleles, classification) = (snp_gt, snp_alleles, snp_assignment);
         }
     }
 
+    let allele_seqs = alleles.iter().map(|a| a.seq.clone()).collect_vec();
     let annotations = label_alleles(locus, &allele_seqs);
 
-    let spanning_by_hap = [
-        classification.iter().filter(|&x| *x == 0).count(),
-        classification.iter().filter(|&x| *x == 1).count(),
-    ];
-    let meth_by_hap = get_meth(&gt, &reads, &spans);
+    let spanning_by_hap = (0..gt.len())
+        .map(|index| {
+            classification
+                .iter()
+                .filter(|&x| *x == index as i32)
+                .count()
+        })
+        .collect_vec();
+    let meth_by_hap = get_meth(&gt, &reads, &spans, get_tr_meth);
+    let get_mod_levels = |code: &'static str| {
+        get_meth(&gt, &reads, &spans, |read, span| {
+            get_mod_level(&read.mods, code, &read.bases, read.is_reverse, span)
+        })
+    };
+    let hmc_by_hap = get_mod_levels(MOD_CODE_5HMC);
+    let m6a_by_hap = get_mod_levels(MOD_CODE_6MA);
+    let motif_len = locus.motifs[0].len();
     let mut genotype = Genotype::new();
     for allele_index in 0..gt.len() {
+        let lens = spans
+            .iter()
+            .zip(&classification)
+            .filter(|(_s, c)| **c == allele_index as i32)
+            .map(|(s, _c)| s.1 - s.0)
+            .collect_vec();
+        let cpg_profile = if params.cpg_profiles {
+            let (allele_reads, allele_spans): (Vec<_>, Vec<_>) = reads
+                .iter()
+                .zip(&spans)
+                .zip(&classification)
+                .filter(|(_, c)| **c == allele_index as i32)
+                .map(|(read_and_span, _)| read_and_span)
+                .unzip();
+            let flank_lens = (locus.left_flank.len(), locus.right_flank.len());
+            let allele_seq = &allele_seqs[allele_index];
+            get_cpg_profile(allele_seq, flank_lens, &allele_reads, &allele_spans)
+        } else {
+            Vec::new()
+        };
         genotype.push(Allele {
             seq: allele_seqs[allele_index].clone(),
+            support: alleles[allele_index].support.clone(),
             annotation: annotations[allele_index].clone(),
             ci: gt[allele_index].ci,
             num_spanning: spanning_by_hap[allele_index],
             meth: meth_by_hap[allele_index],
+            hmc: hmc_by_hap[allele_index],
+            m6a: m6a_by_hap[allele_index],
+            len_dist: get_len_dist(lens, motif_len),
+            cpg_profile,
         });
     }
 
     // Put reference allele first
-    if genotype.len() != 1 && genotype[0].seq != locus.tr && genotype[1].seq == locus.tr {
-        genotype.swap(0, 1);
+    let ref_index = genotype.iter().position(|a| a.seq == locus.tr);
+    if let Some(ref_index) = ref_index.filter(|index| *index != 0) {
+        genotype.swap(0, ref_index);
         for c in classification.iter_mut() {
-            *c = 1 - *c;
+            if *c == 0 {
+                *c = ref_index as i32;
+            } else if *c == ref_index as i32 {
+                *c = 0;
+            }
         }
     }
 
+    // Order alleles by haplotype if reads were haplotagged
+    let phasing = get_phasing(&genotype, &reads, &classification).map(|(is_swapped, phasing)| {
+        if is_swapped {
+            genotype.swap(0, 1);
+            for c in classification.iter_mut().filter(|c| (0..2).contains(*c)) {
+                *c = 1 - *c;
+            }
+        }
+        phasing
+    });
+
+    qc.quality = get_genotype_quality(&genotype, reads.len());
+    let likelihoods = match locus.genotyper {
+        Genotyper::Size => get_likelihoods(locus, &genotype, &spans, &params.len_errors),
+        Genotyper::Cluster => None,
+    };
+    let meth_diff = get_meth_diff(&genotype, &reads, &spans, &classification);
+
+    let read_annotations = if params.annotate_reads {
+        label_alleles(locus, &trs.iter().map(|tr| tr.to_string()).collect())
+    } else {
+        Vec::new()
+    };
+
     Ok(LocusResult {
         genotype,
         reads,
         tr_spans: spans,
         classification,
+        qc,
+        phasing,
+        meth_diff,
+        likelihoods,
+        read_annotations,
+    })
+}
+
+/// Computes likelihoods of genotypes over the lengths of the reference allele
+/// and the called alleles from the repeat lengths of spanning reads
+fn get_likelihoods(
+    locus: &Locus,
+    genotype: &Genotype,
+    spans: &[(usize, usize)],
+    len_errors: &LenErrors,
+) -> Option<Likelihoods> {
+    let mut allele_lens = vec![locus.tr.len()];
+    for allele in genotype {
+        if !allele_lens.contains(&allele.seq.len()) {
+            allele_lens.push(allele.seq.len());
+        }
+    }
+    let ploidy = genotype.len();
+    if genotype::count_genotypes(allele_lens.len(), ploidy) > genotype::MAX_PL_GENOTYPES {
+        return None;
+    }
+
+    let genotypes = genotype::get_genotypes(allele_lens.len(), ploidy);
+    let read_lens = spans.iter().map(|s| s.1 - s.0).collect_vec();
+    let lls = genotype::get_log_likelihoods(&allele_lens, &genotypes, &read_lens, len_errors);
+    let called = genotype
+        .iter()
+        .map(|allele| allele_lens.iter().position(|len| *len == allele.seq.len()))
+        .collect::<Option<Vec<_>>>()?
+        .into_iter()
+        .sorted()
+        .collect_vec();
+    let called_index = genotypes.iter().position(|gt| *gt == called)?;
+    Some(Likelihoods {
+        allele_lens,
+        pl: genotype::get_pl(&lls),
+        gq: genotype::get_gq(&lls, called_index),
     })
 }
 
+/// Phases heterozygous diploid genotypes using the HP tags of reads assigned
+/// to each allele; also returns whether the alleles must be swapped so that
+/// the first one comes from haplotype 1. HP tags are only comparable within a
+/// phase set, so only reads from the phase set with the most haplotagged reads
+/// are counted.
+fn get_phasing(
+    genotype: &Genotype,
+    reads: &[HiFiRead],
+    classification: &[i32],
+) -> Option<(bool, Phasing)> {
+    if genotype.len() != 2 || genotype[0].seq == genotype[1].seq {
+        return None;
+    }
+
+    let tagged = reads
+        .iter()
+        .zip(classification)
+        .filter_map(|(read, allele)| match (read.hp_tag, allele) {
+            (Some(hp @ (1 | 2)), 0 | 1) => Some((read.ps_tag, *allele as usize, hp as usize - 1)),
+            _ => None,
+        })
+        .collect_vec();
+    let phase_set = tagged
+        .iter()
+        .filter_map(|(ps, _, _)| *ps)
+        .counts()
+        .into_iter()
+        .max_by_key(|(ps, count)| (*count, -ps))
+        .map(|(ps, _count)| ps);
+
+    let mut counts = [[0; 2]; 2];
+    for (ps, allele, hp) in tagged {
+        if ps == phase_set {
+            counts[allele][hp] += 1;
+        }
+    }
+
+    let (concordant, discordant) = (counts[0][0] + counts[1][1], counts[0][1] + counts[1][0]);
+    let is_swapped = discordant > concordant;
+    let (support, total) = if is_swapped {
+        ([counts[0][1], counts[1][0]], discordant)
+    } else {
+        ([counts[0][0], counts[1][1]], concordant)
+    };
+    let consistency = total as f64 / (concordant + discordant) as f64;
+    if support.iter().any(|s| *s < MIN_PHASED_READS) || consistency < MIN_PHASE_CONSISTENCY {
+        return None;
+    }
+
+    Some((is_swapped, Phasing { phase_set }))
+}
+
+/// Phred-scaled probability that the genotype is wrong, either because an
+/// allele is supported by artifactual reads only or because all reads of a
+/// heterozygous locus happen to come from the same allele
+fn get_genotype_quality(genotype: &Genotype, num_reads: usize) -> f64 {
+    let ploidy = genotype.len();
+    let is_homozygous = ploidy >= 2 && genotype.iter().all(|a| a.seq == genotype[0].seq);
+    let error_prob = if is_homozygous {
+        ARTIFACT_PROB.powi(num_reads as i32) + (1.0 / ploidy as f64).powi(num_reads as i32 - 1)
+    } else {
+        genotype
+            .iter()
+            .map(|allele| ARTIFACT_PROB.powi(allele.num_spanning as i32))
+            .sum()
+    };
+    let quality = -10.0 * error_prob.min(1.0).log10();
+    (quality.min(MAX_QUALITY) * 10.0).round() / 10.0
+}
+
+/// Returns spanning reads, their repeat spans, and read counts of the locus
 fn get_spanning_reads(
     locus: &Locus,
     params: &Params,
     reads: Vec<HiFiRead>,
-) -> (Vec<HiFiRead>, Vec<(usize, usize)>) {
-    let tr_spans = find_tr_spans(&locus.left_flank, &locus.right_flank, &reads, params);
-
-    let reads_and_spans = reads
+) -> (Vec<HiFiRead>, Vec<(usize, usize)>, LocusQc) {
+    let num_fetched = reads.len();
+    let reads = reads
         .into_iter()
-        .zip(tr_spans)
-        .filter(|(_r, s)| s.is_some())
-        .map(|(r, s)| (r, s.unwrap()))
+        .filter(|r| r.read_qual.is_none_or(|qual| qual >= params.min_read_qual))
         .collect_vec();
+    let num_quality_filtered = num_fetched - reads.len();
+    if num_quality_filtered > 0 {
+        log::warn!(
+            "{}: Quality filtered {} out of {} reads",
+            locus.id,
+            num_quality_filtered,
+            num_fetched
+        );
+    }
+    let tr_spans = find_tr_spans(locus, &reads, params);
+
+    let mut qc = LocusQc {
+        num_reads: reads.len(),
+        num_quality_filtered,
+        ..LocusQc::default()
+    };
+    // Flanking reads may end within the other flank before it can be found,
+    // so the repeat is only guaranteed to cover all but that many bases
+    let mut max_flanking_len = 0;
+    let mut reads_and_spans = Vec::new();
+    for (read, span) in reads.into_iter().zip(tr_spans) {
+        match span {
+            TrSpan::Spanning(span) => reads_and_spans.push((read, span)),
+            TrSpan::LeftFlanking(start) => {
+                qc.num_left_flanking += 1;
+                let len = (read.bases.len() - start).saturating_sub(params.search_flank_len);
+                max_flanking_len = max_flanking_len.max(len);
+            }
+            TrSpan::RightFlanking(end) => {
+                qc.num_right_flanking += 1;
+                let len = end.saturating_sub(params.search_flank_len);
+                max_flanking_len = max_flanking_len.max(len);
+            }
+            TrSpan::Discordant => qc.num_discordant += 1,
+            TrSpan::Unplaced => {}
+        }
+    }
     log::debug!(
         "{}: Found {} spanning reads",
         locus.id,
         reads_and_spans.len()
     );
 
+    let max_spanning_len = reads_and_spans
+        .iter()
+        .map(|(_r, s)| s.1 - s.0)
+        .max()
+        .unwrap_or(0);
+    if max_flanking_len > max_spanning_len {
+        qc.min_allele_len = Some(max_flanking_len);
+    }
+
     // Check flanks
+    let num_spanning = reads_and_spans.len();
     let mut reads_and_spans = reads_and_spans
         .into_iter()
         .filter(|(r, s)| {
             s.0 >= params.search_flank_len && r.bases.len() - s.1 >= params.search_flank_len
         })
         .collect_vec();
+    qc.num_short_flanks = num_spanning - reads_and_spans.len();
     log::debug!(
         "{}: {} spanning reads had sufficiently long flanks",
         locus.id,
@@ -133,11 +425,12 @@ fn get_spanning_reads(
     );
 
     if reads_and_spans.is_empty() {
-        return (Vec::new(), Vec::new());
+        return (Vec::new(), Vec::new(), qc);
     }
 
     // Sort and downsample
     reads_and_spans.sort_by(|(_ra, sa), (_rb, sb)| (sa.1 - sa.0).cmp(&(sb.1 - sb.0)));
+    qc.is_downsampled = reads_and_spans.len() > params.max_depth;
     uniform_downsample(&mut reads_and_spans, params.max_depth);
     log::debug!(
         "{}: downsampled to {} reads",
@@ -145,9 +438,62 @@ fn get_spanning_reads(
         reads_and_spans.len()
     );
 
-    let (reads, spans): (Vec<_>, Vec<_>) = reads_and_spans.into_iter().unzip();
+    // Recruited reads were not clipped around the repeat before it was
+    // located, so their flanks are trimmed to the same radius here
+    let (reads, spans): (Vec<_>, Vec<_>) = reads_and_spans
+        .into_iter()
+        .map(|(read, span)| match read.cigar {
+            Some(_) => (read, span),
+            None => clip_around_span(read, span, CLIP_RADIUS),
+        })
+        .unzip();
+
+    (reads, spans, qc)
+}
+
+/// Clips the read to `radius` bases around the repeat span and shifts the span
+/// accordingly
+fn clip_around_span(
+    read: HiFiRead,
+    span: (usize, usize),
+    radius: usize,
+) -> (HiFiRead, (usize, usize)) {
+    let left_len = span.0.saturating_sub(radius);
+    let right_len = (read.bases.len() - span.1).saturating_sub(radius);
+    match clip_bases(&read, left_len, right_len) {
+        Some(clipped) => (clipped, (span.0 - left_len, span.1 - left_len)),
+        None => (read, span),
+    }
+}
+
+/// Summarizes repeat lengths of reads assigned to an allele; the instability
+/// index follows Lee et al. (2010) with lengths weighted by their read counts
+fn get_len_dist(mut lens: Vec<usize>, motif_len: usize) -> Option<LenDist> {
+    if lens.is_empty() {
+        return None;
+    }
+    lens.sort_unstable();
+    let quantile = |q: f64| lens[(q * (lens.len() - 1) as f64).round() as usize];
+    let quantiles = [0.05, 0.25, 0.5, 0.75, 0.95].map(quantile);
+
+    let counts = lens.iter().dedup_with_count().collect_vec();
+    let (mode_count, mode) = *counts.iter().max_by_key(|(count, _len)| *count).unwrap();
+    let min_count = MIN_PEAK_FRAC * mode_count as f64;
+    let peaks = counts
+        .iter()
+        .filter(|(count, _len)| *count as f64 >= min_count)
+        .collect_vec();
+    let total = peaks.iter().map(|(count, _len)| count).sum::<usize>();
+    let instability = peaks
+        .iter()
+        .map(|(count, len)| *count as f64 * (**len as f64 - *mode as f64))
+        .sum::<f64>()
+        / (total * motif_len) as f64;
 
-    (reads, spans)
+    Some(LenDist {
+        quantiles,
+        instability,
+    })
 }
 
 fn uniform_downsample(reads_and_spans: &mut Vec<(HiFiRead, (usize, usize))>, output_length: usize) {
@@ -178,92 +524,65 @@ fn clip_reads(locus: &Locus, radius: usize, reads: Vec<HiFiRead>) -> Vec<HiFiRea
         .collect_vec()
 }
 
-fn get_meth(gt: &Gt, reads: &[HiFiRead], spans: &[(usize, usize)]) -> Vec<Option<f64>> {
-    let mut meths_1 = Vec::new();
-    let mut meths_2 = Vec::new();
+/// Averages per-read levels, such as methylation of the repeat sequence, over
+/// reads assigned to each allele
+fn get_meth(
+    gt: &Gt,
+    reads: &[HiFiRead],
+    spans: &[(usize, usize)],
+    get_level: impl Fn(&HiFiRead, &(usize, usize)) -> Option<f64>,
+) -> Vec<Option<f64>> {
+    let mut meths_by_allele = vec![Vec::new(); gt.len()];
 
     for (read, span) in reads.iter().zip(spans.iter()) {
-        if read.meth.is_none() {
-            continue;
-        }
-        let level = get_tr_meth(read, span);
-        if level.is_none() {
+        let Some(level) = get_level(read, span) else {
             continue;
-        }
-        let level = level.unwrap();
-        let assignment = assign_read(gt, span.1 - span.0);
-        match assignment {
-            Assignment::First => meths_1.push(level),
-            Assignment::Second => meths_2.push(level),
-            Assignment::Both => {
-                meths_1.push(level);
-                meths_2.push(level);
-            }
-            Assignment::None => {}
         };
+        for index in assign_read(gt, span.1 - span.0) {
+            meths_by_allele[index].push(level);
+        }
     }
 
-    let meth_1 = if !meths_1.is_empty() {
-        let meth = meths_1.iter().sum::<f64>() / meths_1.len() as f64;
-        Some(meth)
-    } else {
-        None
-    };
-    let meth_2 = if !meths_2.is_empty() {
-        let meth = meths_2.iter().sum::<f64>() / meths_2.len() as f64;
-        Some(meth)
-    } else {
-        None
-    };
-
-    if gt.len() == 2 {
-        vec![meth_1, meth_2]
-    } else {
-        vec![meth_1]
-    }
-}
-
-enum Assignment {
-    First,
-    Second,
-    Both,
-    None,
+    meths_by_allele
+        .into_iter()
+        .map(|meths| {
+            if meths.is_empty() {
+                None
+            } else {
+                Some(meths.iter().sum::<f64>() / meths.len() as f64)
+            }
+        })
+        .collect()
 }
 
-fn assign_read(gt: &Gt, tr_len: usize) -> Assignment {
+/// Assigns a read to the closest allele if the read is within its length
+/// range; reads are assigned to all alleles of the same size
+fn assign_read(gt: &Gt, tr_len: usize) -> Vec<usize> {
     if gt.len() == 1 {
-        return Assignment::First;
-    }
-
-    let hap1_len = gt[0].size;
-    let hap2_len = gt[1].size;
-
-    let spans_1 = gt[0].ci.0 <= tr_len && tr_len <= gt[0].ci.1;
-    let spans_2 = gt[1].ci.0 <= tr_len && tr_len <= gt[1].ci.1;
-
-    let dist_1 = tr_len.abs_diff(gt[0].size);
-    let dist_2 = tr_len.abs_diff(gt[1].size);
-
-    if dist_1 < dist_2 && spans_1 {
-        return Assignment::First;
-    }
-
-    if dist_2 < dist_1 && spans_2 {
-        return Assignment::Second;
+        return vec![0];
     }
 
-    if hap1_len == hap2_len && spans_1 {
-        return Assignment::Both;
+    let min_dist = gt.iter().map(|a| tr_len.abs_diff(a.size)).min().unwrap();
+    let closest = gt
+        .iter()
+        .positions(|a| tr_len.abs_diff(a.size) == min_dist)
+        .collect_vec();
+    let first = &gt[closest[0]];
+    let spans_first = first.ci.0 <= tr_len && tr_len <= first.ci.1;
+    if spans_first && closest.iter().all(|index| gt[*index].size == first.size) {
+        closest
+    } else {
+        Vec::new()
     }
-
-    Assignment::None
 }
 
 fn extract_reads(
     locus: &Locus,
     bam: &mut bam::IndexedReader,
     flank_len: u32,
-    min_read_qual: f64,
+    use_base_quals: bool,
+    stitch: bool,
+    read_groups: Option<&HashSet<String>>,
 ) -> Result<Vec<HiFiRead>> {
     let mut reads = Vec::new();
     let extraction_region = (
@@ -276,33 +595,68 @@ fn extract_reads(
         return Ok(Vec::new());
     }
 
-    let mut num_filtered = 0;
     for rec in bam::Read::records(bam) {
         let rec = rec.map_err(|e| e.to_string())?;
-        if rec.is_supplementary() || rec.is_secondary() {
+        // Supplementary records can only stand in for their reads when
+        // stitching
+        if rec.is_secondary() || (rec.is_supplementary() && !stitch) {
             continue;
         }
 
-        let read = HiFiRead::from_hts_rec(rec, &locus.region);
-        if let Some(qual) = read.read_qual {
-            if qual >= min_read_qual {
-                reads.push(read);
-            } else {
-                num_filtered += 1;
+        if let Some(read_groups) = read_groups {
+            match rec.aux(b"RG") {
+                Ok(Aux::String(rg)) if read_groups.contains(rg) => {}
+                _ => continue,
             }
-        } else {
+        }
+
+        let sa_tag = match rec.aux(b"SA") {
+            Ok(Aux::String(sa_tag)) if stitch => Some(sa_tag.to_string()),
+            _ => None,
+        };
+        let is_supplementary = rec.is_supplementary();
+        let read = HiFiRead::from_hts_rec(rec, &locus.region, use_base_quals);
+        let read = match sa_tag {
+            Some(sa_tag) if is_supplementary => {
+                Some(read).filter(|read| replaces_primary(read, &sa_tag, &locus.region))
+            }
+            Some(sa_tag) => stitch_supplementary(read, &sa_tag, &locus.region),
+            None if is_supplementary => None,
+            None => Some(read),
+        };
+        if let Some(read) = read {
             reads.push(read);
         }
     }
+    Ok(reads)
+}
+
+/// Alignments without =/X operations, such as those decoded from CRAM, carry
+/// no mismatches, which are needed to phase reads by flanking variants
+fn resolve_matches(locus: &Locus, genome: &faidx::Reader, reads: &mut [HiFiRead]) -> Result<()> {
+    let spans = reads
+        .iter()
+        .filter_map(|r| r.cigar.as_ref())
+        .map(|c| (c.ref_pos, c.ref_pos + c.ref_len()))
+        .collect_vec();
+    let start = spans.iter().map(|s| s.0).min().unwrap_or(0).max(0);
+    let end = spans.iter().map(|s| s.1).max().unwrap_or(0);
+    if start >= end {
+        return Ok(());
+    }
 
-    if num_filtered > 0 {
-        let total = num_filtered + reads.len();
-        log::warn!("Quality filtered {} out of {} reads", num_filtered, total);
+    let reference = genome
+        .fetch_seq_string(&locus.region.contig, start as usize, end as usize - 1)
+        .map_err(|e| e.to_string())?
+        .into_bytes();
+    for read in reads.iter_mut() {
+        read.resolve_matches(&reference, start, &locus.region);
     }
-    Ok(reads)
+    Ok(())
 }
 
-fn get_tr_meth(read: &HiFiRead, span: &(usize, usize)) -> Option<f64> {
+/// Mean methylation level of CpGs within the repeat span of the read
+pub fn get_tr_meth(read: &HiFiRead, span: &(usize, usize)) -> Option<f64> {
     if read.meth.is_none() || read.meth.as_ref().unwrap().is_empty() {
         return None;
     }
@@ -338,3 +692,178 @@ fn get_tr_meth(read: &HiFiRead, span: &(usize, usize)) -> Option<f64> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label::Annotation;
+
+    fn make_allele(seq: &str, num_spanning: usize) -> Allele {
+        Allele {
+            seq: seq.to_string(),
+            support: vec![1.0; seq.len()],
+            annotation: Annotation {
+                labels: None,
+                motif_counts: Vec::new(),
+                purity: 1.0,
+            },
+            ci: (seq.len(), seq.len()),
+            num_spanning,
+            meth: None,
+            hmc: None,
+            m6a: None,
+            len_dist: None,
+            cpg_profile: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn genotype_quality_grows_with_support() {
+        let het = Genotype::from_iter([make_allele("CAG", 2), make_allele("CAGCAG", 2)]);
+        assert_eq!(get_genotype_quality(&het, 4), 17.0);
+
+        let hom = Genotype::from_iter([make_allele("CAG", 2), make_allele("CAG", 2)]);
+        assert_eq!(get_genotype_quality(&hom, 1), 0.0);
+        assert_eq!(get_genotype_quality(&hom, 4), 9.0);
+
+        let deep = Genotype::from_iter([make_allele("CAG", 30), make_allele("CAGCAG", 30)]);
+        assert_eq!(get_genotype_quality(&deep, 60), MAX_QUALITY);
+    }
+
+    #[test]
+    fn len_dist_of_mosaic_allele() {
+        let mut lens = vec![30; 10];
+        lens.extend([33, 33, 33, 33, 36, 36, 60]);
+        let dist = get_len_dist(lens, 3).unwrap();
+        assert_eq!(dist.quantiles, [30, 30, 30, 33, 36]);
+        // The single read at 60bp falls below the peak threshold
+        assert_eq!(dist.instability, 0.5);
+        assert_eq!(get_len_dist(Vec::new(), 3), None);
+    }
+
+    #[test]
+    fn phase_alleles_by_hp_tags() {
+        let make_read = |hp_tag: Option<u8>, ps_tag: Option<i32>| HiFiRead {
+            id: "read".to_string(),
+            bases: Vec::new(),
+            meth: None,
+            read_qual: None,
+            mismatch_offsets: None,
+            start_offset: 0,
+            end_offset: 0,
+            cigar: None,
+            hp_tag,
+            ps_tag,
+            mapq: 60,
+            is_reverse: false,
+            mods: Vec::new(),
+        };
+        let genotype = Genotype::from_iter([make_allele("CAG", 3), make_allele("CAGCAG", 3)]);
+        let reads = vec![
+            make_read(Some(2), Some(1000)),
+            make_read(Some(2), Some(1000)),
+            make_read(None, None),
+            make_read(Some(1), Some(1000)),
+            make_read(Some(1), Some(1000)),
+            make_read(Some(1), None),
+        ];
+        let classification = vec![0, 0, 0, 1, 1, 1];
+        let phasing = Phasing {
+            phase_set: Some(1000),
+        };
+        assert_eq!(
+            get_phasing(&genotype, &reads, &classification),
+            Some((true, phasing))
+        );
+
+        // Haplotypes that disagree with the allele assignment are not phased
+        let classification = vec![0, 1, 0, 1, 0, 1];
+        assert_eq!(get_phasing(&genotype, &reads, &classification), None);
+
+        // HP tags of a smaller phase set, where haplotype 1 may be another
+        // haplotype, are ignored
+        let reads = vec![
+            make_read(Some(1), Some(1000)),
+            make_read(Some(1), Some(1000)),
+            make_read(Some(1), Some(1000)),
+            make_read(Some(2), Some(2000)),
+            make_read(Some(2), Some(2000)),
+            make_read(Some(2), Some(1000)),
+            make_read(Some(2), Some(1000)),
+            make_read(Some(1), Some(2000)),
+            make_read(Some(1), Some(2000)),
+        ];
+        let classification = vec![0, 0, 0, 0, 0, 1, 1, 1, 1];
+        let phasing = Phasing {
+            phase_set: Some(1000),
+        };
+        assert_eq!(
+            get_phasing(&genotype, &reads, &classification),
+            Some((false, phasing))
+        );
+    }
+
+    #[test]
+    fn cluster_recruited_reads_with_uneven_flanks() {
+        // Pseudo-random flanks, long enough for reads to extend past the
+        // clipping radius
+        let mut state = 7u64;
+        let mut make_seq = |len: usize| {
+            (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    b"ACGT"[(state >> 33) as usize % 4]
+                })
+                .collect_vec()
+        };
+        let (left, right) = (make_seq(3000), make_seq(3000));
+        let locus = Locus {
+            id: "TR".to_string(),
+            left_flank: String::from_utf8(left[left.len() - 250..].to_vec()).unwrap(),
+            tr: "CAG".repeat(10),
+            right_flank: String::from_utf8(right[..250].to_vec()).unwrap(),
+            flank_offsets: (0, 0),
+            region: crate::utils::GenomicRegion::new("chrA:3000-3030").unwrap(),
+            motifs: vec!["CAG".to_string()],
+            struc: "(CAG)n".to_string(),
+            ploidies: vec![Ploidy::TWO],
+            genotyper: Genotyper::Cluster,
+        };
+        let params = Params {
+            search_flank_len: 100,
+            min_read_qual: 0.0,
+            use_base_quals: false,
+            max_depth: 250,
+            aln_scoring: crate::preset::Preset::Hifi.aln_scoring(),
+            min_flank_id_frac: 0.7,
+            len_errors: LenErrors::NONE,
+            annotate_reads: false,
+            cpg_profiles: false,
+            stitch_supplementary: false,
+        };
+
+        // Reads of both alleles have either short or long flanks; the last
+        // read ends too close to the repeat to be genotyped
+        let reads = (0..13)
+            .map(|index| {
+                let flank_len = if index % 2 == 0 { 520 } else { 2900 };
+                let tr = if index < 6 { 10 } else { 15 };
+                let mut bases = left[left.len() - flank_len..].to_vec();
+                bases.extend("CAG".repeat(tr).as_bytes());
+                bases.extend(&right[..if index < 12 { flank_len } else { 90 }]);
+                HiFiRead::from_seq(format!("read{}", index), bases, false)
+            })
+            .collect_vec();
+
+        let result = analyze_reads(&locus, &params, Ploidy::TWO, reads).unwrap();
+        assert_eq!(result.qc.num_short_flanks, 1);
+        let alleles = result.genotype.iter().map(|a| a.seq.as_str()).collect_vec();
+        assert_eq!(alleles, ["CAG".repeat(10), "CAG".repeat(15)]);
+        assert_eq!(result.classification, [0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1]);
+        assert!(result
+            .reads
+            .iter()
+            .zip(&result.tr_spans)
+            .all(|(read, span)| span.0 <= CLIP_RADIUS && read.bases.len() - span.1 <= CLIP_RADIUS));
+    }
+}
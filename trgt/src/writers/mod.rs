@@ -1,5 +1,7 @@
 mod write_bam;
+mod write_read_table;
 mod write_vcf;
 
-pub use write_bam::BamWriter;
-pub use write_vcf::VcfWriter;
+pub use write_bam::{index_bam, BamWriter};
+pub use write_read_table::ReadTableWriter;
+pub use write_vcf::{index_vcf, VcfWriter, VECTOR_END_FLOAT, VECTOR_END_INTEGER};
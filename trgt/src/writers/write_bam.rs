@@ -1,15 +1,44 @@
 use crate::cli;
 use crate::locus::Locus;
-use crate::reads::clip_bases;
-use crate::workflows::LocusResult;
+use crate::reads::{clip_bases, HiFiRead};
+use crate::workflows::{LocusResult, CLIP_RADIUS};
 use rust_htslib::bam::header::HeaderRecord;
 use rust_htslib::bam::record::{AuxArray, CigarString};
 use rust_htslib::{bam, bam::record::Aux};
+use std::collections::BTreeMap;
 use std::env;
 
+/// Checks if a spanning read has flanks of at least the output flank length
+/// and keeps some bases once they are clipped to it
+pub fn has_output_flanks(read: &HiFiRead, span: (usize, usize), output_flank_len: usize) -> bool {
+    span.0 >= output_flank_len
+        && read.bases.len() >= span.1 + output_flank_len
+        && span.1 - span.0 + 2 * output_flank_len > 0
+}
+
+/// Clips the flanks of a spanning read to the output flank length; reads with
+/// shorter flanks are left out of the outputs
+pub fn clip_to_output_flanks(
+    read: &HiFiRead,
+    span: (usize, usize),
+    output_flank_len: usize,
+) -> Option<HiFiRead> {
+    if !has_output_flanks(read, span, output_flank_len) {
+        return None;
+    }
+    let left_clip_len = span.0 - output_flank_len;
+    let right_clip_len = read.bases.len() - span.1 - output_flank_len;
+    clip_bases(read, left_clip_len, right_clip_len)
+}
+
+/// Records are keyed by contig id, position, and order of arrival
+type RecordKey = (i32, i64, usize);
+
 pub struct BamWriter {
     writer: bam::Writer,
     output_flank_len: usize,
+    pending: BTreeMap<RecordKey, bam::Record>,
+    num_records: usize,
 }
 
 impl BamWriter {
@@ -39,36 +68,62 @@ impl BamWriter {
         Ok(BamWriter {
             writer,
             output_flank_len,
+            pending: BTreeMap::new(),
+            num_records: 0,
         })
     }
 
-    pub fn write(&mut self, locus: &Locus, results: &LocusResult) {
+    /// Writes out all records that precede the given position
+    ///
+    /// Loci must be written in coordinate order. Reads are clipped to
+    /// CLIP_RADIUS bases around each repeat, so no record of a locus that
+    /// starts at or after the given position can be placed before
+    /// `start - CLIP_RADIUS`.
+    fn flush_before(&mut self, tid: i32, start: i64) {
+        let boundary = (tid, start - CLIP_RADIUS as i64, 0);
+        let remaining = self.pending.split_off(&boundary);
+        let ready = std::mem::replace(&mut self.pending, remaining);
+        for rec in ready.into_values() {
+            self.writer.write(&rec).unwrap();
+        }
+    }
+
+    /// Writes out all remaining records
+    pub fn finish(mut self) {
+        for rec in std::mem::take(&mut self.pending).into_values() {
+            self.writer.write(&rec).unwrap();
+        }
+    }
+
+    /// Adds a record recovered from the output of an interrupted run
+    pub fn insert(&mut self, rec: bam::Record) {
+        self.pending
+            .insert((rec.tid(), rec.pos(), self.num_records), rec);
+        self.num_records += 1;
+    }
+
+    /// Returns the number of records written for the locus
+    pub fn write(&mut self, locus: &Locus, results: &LocusResult) -> usize {
+        let contig = locus.region.contig.as_bytes();
+        let contig_id = self.writer.header().tid(contig).unwrap() as i32;
+        self.flush_before(contig_id, locus.region.start as i64);
+
+        let mut num_written = 0;
         let num_reads = results.reads.len();
         for index in 0..num_reads {
             let read = &results.reads[index];
             let classification = results.classification[index];
-            let span = &results.tr_spans[index];
-
-            if span.0 < self.output_flank_len || read.bases.len() < span.1 + self.output_flank_len {
-                log::error!("Read {} has unexpectedly short flanks", read.id);
-                continue;
-            }
+            let span = results.tr_spans[index];
 
-            let left_clip_len = span.0 - self.output_flank_len;
-            let right_clip_len = read.bases.len() - span.1 - self.output_flank_len;
-            let clipped_read = clip_bases(read, left_clip_len, right_clip_len);
-            if clipped_read.is_none() {
+            let Some(read) = clip_to_output_flanks(read, span, self.output_flank_len) else {
                 log::error!("Read {} has unexpectedly short flanks", read.id);
                 continue;
-            }
-            let read = clipped_read.unwrap();
+            };
 
-            let contig = locus.region.contig.as_bytes();
-            let contig_id = self.writer.header().tid(contig).unwrap();
             let quals = "(".repeat(read.bases.len());
 
             let mut rec = bam::Record::new();
-            rec.set_tid(contig_id as i32);
+            rec.set_tid(contig_id);
 
             if let Some(cigar) = read.cigar {
                 rec.set_pos(cigar.ref_pos);
@@ -104,15 +159,46 @@ impl BamWriter {
                 rec.push_aux(b"HP", hp_tag).unwrap();
             }
 
+            if let Some(ps) = read.ps_tag {
+                rec.push_aux(b"PS", Aux::I32(ps)).unwrap();
+            }
+
             rec.push_aux(b"SO", Aux::I32(read.start_offset)).unwrap();
             rec.push_aux(b"EO", Aux::I32(read.end_offset)).unwrap();
             rec.push_aux(b"AL", Aux::I32(classification)).unwrap();
 
+            if let Some(annotation) = results.read_annotations.get(index) {
+                let counts = annotation
+                    .motif_counts
+                    .iter()
+                    .map(|count| *count as u32)
+                    .collect::<Vec<_>>();
+                let mr_tag: AuxArray<u32> = (&counts).into();
+                rec.push_aux(b"MR", Aux::ArrayU32(mr_tag)).unwrap();
+                let spans = annotation.encode_spans();
+                rec.push_aux(b"MS", Aux::String(&spans)).unwrap();
+            }
+
             let dat: &Vec<u32> = &vec![self.output_flank_len as u32, self.output_flank_len as u32];
             let fl_tag: AuxArray<u32> = dat.into();
             rec.push_aux(b"FL", Aux::ArrayU32(fl_tag)).unwrap();
 
-            self.writer.write(&rec).unwrap();
+            self.pending
+                .insert((contig_id, rec.pos(), self.num_records), rec);
+            self.num_records += 1;
+            num_written += 1;
         }
+        num_written
     }
 }
+
+/// Builds a BAI index or a CSI index if the BAM has contigs too long for BAI
+pub fn index_bam(path: &str, use_csi: bool) -> Result<(), String> {
+    let idx_type = if use_csi {
+        bam::index::Type::Csi(14)
+    } else {
+        bam::index::Type::Bai
+    };
+    bam::index::build(path, None, idx_type, 1)
+        .map_err(|e| format!("Failed to index {}: {}", path, e))
+}
@@ -0,0 +1,165 @@
+use super::write_bam::has_output_flanks;
+use crate::locus::Locus;
+use crate::workflows::{get_tr_meth, LocusResult};
+use flate2::{write::GzEncoder, Compression};
+use itertools::Itertools;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const HEADER: &str =
+    "sample\ttrid\tread\tstrand\ttr_start\ttr_end\ttr_len\tallele\tmotif_counts\tmeth\tmapq\thp";
+
+/// Writes one line per spanning read to a gzipped TSV file
+pub struct ReadTableWriter {
+    writer: GzEncoder<BufWriter<File>>,
+    output_flank_len: usize,
+}
+
+impl ReadTableWriter {
+    pub fn new(output_path: &str, output_flank_len: usize) -> Result<ReadTableWriter, String> {
+        let file = File::create(output_path)
+            .map_err(|_| format!("Invalid read table output path: {}", output_path))?;
+        let mut writer = GzEncoder::new(BufWriter::new(file), Compression::default());
+        writeln!(writer, "{}", HEADER).map_err(|e| e.to_string())?;
+        Ok(ReadTableWriter {
+            writer,
+            output_flank_len,
+        })
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        self.writer
+            .finish()
+            .and_then(|mut writer| writer.flush())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Repeat spans are given in coordinates of the reads in the spanning BAM,
+    /// whose flanks are clipped to the output flank length; reads left out of
+    /// the spanning BAM are left out of the table too
+    pub fn write(
+        &mut self,
+        sample: &str,
+        locus: &Locus,
+        results: &LocusResult,
+    ) -> Result<(), String> {
+        for (index, read) in results.reads.iter().enumerate() {
+            let span = results.tr_spans[index];
+            if !has_output_flanks(read, span, self.output_flank_len) {
+                continue;
+            }
+            let tr_len = span.1 - span.0;
+            let tr_start = self.output_flank_len;
+
+            let classification = results.classification[index];
+            let allele = if (classification as usize) < results.genotype.len() {
+                classification.to_string()
+            } else {
+                ".".to_string()
+            };
+            let motif_counts = results
+                .read_annotations
+                .get(index)
+                .map_or(".".to_string(), |a| a.motif_counts.iter().join("_"));
+            let meth = get_tr_meth(read, &span).map_or(".".to_string(), |m| format!("{:.3}", m));
+            let hp = read.hp_tag.map_or(".".to_string(), |hp| hp.to_string());
+
+            writeln!(
+                self.writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                sample,
+                locus.id,
+                read.id,
+                if read.is_reverse { '-' } else { '+' },
+                tr_start,
+                tr_start + tr_len,
+                tr_len,
+                allele,
+                motif_counts,
+                meth,
+                read.mapq,
+                hp
+            )
+            .map_err(|e| format!("Failed to write read table: {}", e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genotype::Ploidy;
+    use crate::label::Annotation;
+    use crate::locus::Genotyper;
+    use crate::reads::HiFiRead;
+    use crate::utils::GenomicRegion;
+    use crate::workflows::Allele;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn write_rows_of_reads_in_spanning_bam() {
+        let locus = Locus {
+            id: "TR".to_string(),
+            left_flank: "AAAAA".to_string(),
+            tr: "CAGCAGCAG".to_string(),
+            right_flank: "TTTTT".to_string(),
+            flank_offsets: (0, 0),
+            region: GenomicRegion::new("chrA:1000-1009").unwrap(),
+            motifs: vec!["CAG".to_string()],
+            struc: "(CAG)n".to_string(),
+            ploidies: vec![Ploidy::TWO],
+            genotyper: Genotyper::Size,
+        };
+        let allele = Allele {
+            seq: locus.tr.clone(),
+            support: vec![1.0; locus.tr.len()],
+            annotation: Annotation {
+                labels: None,
+                motif_counts: vec![3],
+                purity: 1.0,
+            },
+            ci: (9, 9),
+            num_spanning: 2,
+            meth: None,
+            hmc: None,
+            m6a: None,
+            len_dist: None,
+            cpg_profile: Vec::new(),
+        };
+        let make_read = |id: &str, seq: &str, is_reverse| {
+            HiFiRead::from_seq(id.to_string(), seq.as_bytes().to_vec(), is_reverse)
+        };
+        // The second read has a left flank shorter than the output flanks
+        let results = LocusResult {
+            genotype: vec![allele],
+            reads: vec![
+                make_read("read1", "AAAAACAGCAGCAGTTTTT", false),
+                make_read("read2", "AACAGCAGCAGTTTTT", false),
+                make_read("read3", "AAAACAGCAGCAGCAGTTTT", true),
+            ],
+            tr_spans: vec![(5, 14), (2, 11), (4, 16)],
+            classification: vec![0, 0, -1],
+            ..LocusResult::empty()
+        };
+
+        let path = std::env::temp_dir().join(format!("trgt-{}-reads.tsv.gz", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut writer = ReadTableWriter::new(path, 3).unwrap();
+        writer.write("sample", &locus, &results).unwrap();
+        writer.finish().unwrap();
+
+        let mut table = String::new();
+        GzDecoder::new(File::open(path).unwrap())
+            .read_to_string(&mut table)
+            .unwrap();
+        std::fs::remove_file(path).unwrap();
+        let expected = [
+            HEADER,
+            "sample\tTR\tread1\t+\t3\t12\t9\t0\t.\t.\t0\t.",
+            "sample\tTR\tread3\t-\t3\t15\t12\t.\t.\t.\t0\t.",
+        ];
+        assert_eq!(table.lines().collect_vec(), expected);
+    }
+}
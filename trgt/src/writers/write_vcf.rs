@@ -1,38 +1,141 @@
-use crate::locus::Locus;
-use crate::workflows::{Genotype, LocusResult};
+use crate::consensus::{get_low_support_positions, MIN_BASE_SUPPORT};
+use crate::genotype::{self, Ploidy};
+use crate::locus::{Genotyper, Locus};
+use crate::workflows::{
+    encode_cpg_profile, Allele, Genotype, Likelihoods, LocusQc, LocusResult, MethDiff,
+};
 use itertools::Itertools;
+use lazy_static::lazy_static;
 use rust_htslib::bam::{self};
-use rust_htslib::bcf::record::GenotypeAllele;
+use rust_htslib::bcf::record::{GenotypeAllele, Numeric};
 use rust_htslib::bcf::{self, Format, Record};
+use rust_htslib::htslib;
 use std::env;
 
+/// Pads per-sample vectors of integers that are shorter than others
+pub const VECTOR_END_INTEGER: i32 = htslib::bcf_int32_vector_end;
+
+lazy_static! {
+    /// Pads per-sample vectors of floats that are shorter than others
+    pub static ref VECTOR_END_FLOAT: f32 = f32::from_bits(0x7F80_0002);
+}
+
 pub struct VcfWriter {
     writer: bcf::Writer,
 }
 
-const VCF_LINES: [&str; 12] = [
+/// Genotyped samples with fewer spanning reads are flagged as LowDepth
+const MIN_SPANNING_READS: usize = 5;
+
+/// Allele length ranges wider than both bounds are flagged as WideCI
+const MAX_CI_WIDTH: usize = 10;
+const MAX_CI_FRACTION: f64 = 0.2;
+
+/// Alleles with a larger fraction of low-support bases are flagged as
+/// LowSupport
+const MAX_LOW_SUPPORT_FRACTION: f64 = 0.1;
+
+/// Samples with a larger fraction of reads with discordant flanks are flagged
+/// as Discordant
+const MAX_DISCORDANT_FRACTION: f64 = 0.1;
+
+/// Samples with a larger fraction of reads removed by the read quality filter
+/// are flagged as QualityFiltered
+const MAX_QUALITY_FILTERED_FRACTION: f64 = 0.2;
+
+fn get_filter_lines() -> Vec<String> {
+    let percent = |fraction: f64| (fraction * 100.0).round();
+    [
+        (
+            "LowDepth",
+            format!("Fewer than {MIN_SPANNING_READS} spanning reads in a genotyped sample"),
+        ),
+        (
+            "WideCI",
+            format!(
+                "Allele length range wider than {}bp and {}% of the allele length",
+                MAX_CI_WIDTH,
+                percent(MAX_CI_FRACTION)
+            ),
+        ),
+        (
+            "Downsampled",
+            "Spanning reads were downsampled to the maximum depth".to_string(),
+        ),
+        (
+            "LowSupport",
+            format!(
+                "More than {}% of the bases of an allele supported by fewer than {}% of its reads",
+                percent(MAX_LOW_SUPPORT_FRACTION),
+                percent(MIN_BASE_SUPPORT)
+            ),
+        ),
+        (
+            "Discordant",
+            format!(
+                "More than {}% of the reads of a sample have discordant flanks",
+                percent(MAX_DISCORDANT_FRACTION)
+            ),
+        ),
+        (
+            "QualityFiltered",
+            format!(
+                "More than {}% of the reads of a sample were removed by the read quality filter",
+                percent(MAX_QUALITY_FILTERED_FRACTION)
+            ),
+        ),
+        ("NoSpanning", "No spanning reads in any sample".to_string()),
+    ]
+    .into_iter()
+    .map(|(id, description)| format!(r#"##FILTER=<ID={id},Description="{description}">"#))
+    .collect()
+}
+
+const VCF_LINES: [&str; 30] = [
     r#"##INFO=<ID=TRID,Number=1,Type=String,Description="Tandem repeat ID">"#,
     r#"##INFO=<ID=END,Number=1,Type=Integer,Description="End position of the variant described in this record">"#,
     r#"##INFO=<ID=MOTIFS,Number=1,Type=String,Description="Motifs that the tandem repeat is composed of">"#,
     r#"##INFO=<ID=STRUC,Number=1,Type=String,Description="Structure of the region">"#,
+    r#"##INFO=<ID=FLANKS,Number=4,Type=Integer,Description="Start and end positions of the left and right flanks used to locate the repeat">"#,
     r#"##FORMAT=<ID=GT,Number=1,Type=String,Description="Genotype">"#,
+    r#"##FORMAT=<ID=PS,Number=1,Type=Integer,Description="Phase set of the genotype">"#,
     r#"##FORMAT=<ID=AL,Number=.,Type=Integer,Description="Length of each allele">"#,
+    r#"##FORMAT=<ID=PL,Number=G,Type=Integer,Description="Phred-scaled genotype likelihoods from repeat lengths of spanning reads">"#,
+    r#"##FORMAT=<ID=GQ,Number=1,Type=Integer,Description="Genotype quality">"#,
     r#"##FORMAT=<ID=ALLR,Number=.,Type=String,Description="Length range per allele">"#,
     r#"##FORMAT=<ID=SD,Number=.,Type=Integer,Description="Number of spanning reads supporting per allele">"#,
     r#"##FORMAT=<ID=MC,Number=.,Type=String,Description="Motif counts per allele">"#,
     r#"##FORMAT=<ID=MS,Number=.,Type=String,Description="Motif spans per allele">"#,
     r#"##FORMAT=<ID=AP,Number=.,Type=Float,Description="Allele purity per allele">"#,
+    r#"##FORMAT=<ID=LS,Number=.,Type=String,Description="Positions of allele bases supported by fewer than half of the reads per allele">"#,
     r#"##FORMAT=<ID=AM,Number=.,Type=Float,Description="Mean methylation level per allele">"#,
+    r#"##FORMAT=<ID=AH,Number=.,Type=Float,Description="Mean 5hmC level per allele">"#,
+    r#"##FORMAT=<ID=AA,Number=.,Type=Float,Description="Mean 6mA level per allele">"#,
+    r#"##FORMAT=<ID=MP,Number=.,Type=String,Description="Methylation of CpGs per allele as position|level|depth, with positions relative to the allele start">"#,
+    r#"##FORMAT=<ID=MD,Number=1,Type=Float,Description="Mean methylation of the second allele minus that of the first allele">"#,
+    r#"##FORMAT=<ID=MDP,Number=1,Type=Float,Description="P-value of the difference in methylation between alleles">"#,
+    r#"##FORMAT=<ID=LQ,Number=.,Type=String,Description="5th, 25th, 50th, 75th, and 95th percentiles of repeat lengths in reads per allele">"#,
+    r#"##FORMAT=<ID=MI,Number=.,Type=Float,Description="Instability index of repeat lengths in reads per allele">"#,
+    r#"##FORMAT=<ID=NR,Number=1,Type=Integer,Description="Number of reads overlapping the repeat">"#,
+    r#"##FORMAT=<ID=LF,Number=1,Type=Integer,Description="Number of reads containing only the left flank">"#,
+    r#"##FORMAT=<ID=RF,Number=1,Type=Integer,Description="Number of reads containing only the right flank">"#,
+    r#"##FORMAT=<ID=DF,Number=1,Type=Integer,Description="Number of reads with discordant flanks">"#,
+    r#"##FORMAT=<ID=SF,Number=1,Type=Integer,Description="Number of spanning reads with flanks too short for genotyping">"#,
+    r#"##FORMAT=<ID=LB,Number=1,Type=Integer,Description="Lower bound on the longest allele length from flanking reads">"#,
 ];
 
 impl VcfWriter {
     pub fn new(
         output_path: &str,
-        sample_name: &str,
+        sample_names: &[&str],
+        karyotypes: &[&str],
         bam_header: &bam::Header,
     ) -> Result<VcfWriter, String> {
         let mut vcf_header = bcf::header::Header::new();
 
+        for line in get_filter_lines() {
+            vcf_header.push_record(line.as_bytes());
+        }
         for line in VCF_LINES.iter() {
             vcf_header.push_record(line.as_bytes());
         }
@@ -57,7 +160,14 @@ impl VcfWriter {
         let line = format!("##{}Command={}", env!("CARGO_PKG_NAME"), command_line);
         vcf_header.push_record(line.as_bytes());
 
-        vcf_header.push_sample(sample_name.as_bytes());
+        for (sample_name, karyotype) in sample_names.iter().zip(karyotypes) {
+            let line = format!("##SAMPLE=<ID={},Karyotype={}>", sample_name, karyotype);
+            vcf_header.push_record(line.as_bytes());
+        }
+
+        for sample_name in sample_names {
+            vcf_header.push_sample(sample_name.as_bytes());
+        }
 
         let writer = bcf::Writer::from_path(output_path, &vcf_header, false, Format::Vcf)
             .map_err(|_| format!("Invalid VCF output path: {}", output_path))?;
@@ -65,24 +175,61 @@ impl VcfWriter {
         Ok(VcfWriter { writer })
     }
 
-    pub fn write(&mut self, locus: &Locus, results: &LocusResult) {
+    /// Writes a record recovered from the output of an interrupted run
+    pub fn write_record(&mut self, record: &mut Record) -> Result<(), String> {
+        self.writer.translate(record);
+        self.writer.write(record).map_err(|e| e.to_string())
+    }
+
+    /// Writes a record with one entry per sample; all samples share one allele set
+    pub fn write(&mut self, locus: &Locus, results: &[LocusResult]) {
         let mut record = self.writer.empty_record();
         self.write_info_fields(locus, results, &mut record);
-        if !results.genotype.is_empty() {
-            self.write_genotype_fields(locus, results, &mut record);
+        self.write_genotype_fields(locus, results, &mut record);
+        self.write_qc_fields(locus, results, &mut record);
+        self.writer.write(&record).unwrap();
+    }
+
+    /// Sets QUAL to the lowest quality across genotyped samples and FILTER to
+    /// the QC checks failed by any sample
+    fn write_qc_fields(&mut self, locus: &Locus, results: &[LocusResult], record: &mut Record) {
+        let genotyped = results
+            .iter()
+            .filter(|r| !r.genotype.is_empty())
+            .collect_vec();
+        let qual = genotyped
+            .iter()
+            .map(|r| r.qc.quality as f32)
+            .reduce(f32::min)
+            .unwrap_or_else(f32::missing);
+        record.set_qual(qual);
+
+        // Filters describe samples with copies of the repeat and are left
+        // missing if no sample has any
+        let results = results
+            .iter()
+            .zip(&locus.ploidies)
+            .filter(|(_, ploidy)| **ploidy != Ploidy::ZERO)
+            .map(|(result, _)| result)
+            .collect_vec();
+        if results.is_empty() {
+            return;
+        }
+        let filters = get_filters(&results);
+        if filters.is_empty() {
+            record.set_filters(&[&b"PASS"[..]]).unwrap();
         } else {
-            self.write_missing_genotype_fields(locus, &mut record);
+            let ids = filters.iter().map(|f| f.as_bytes()).collect_vec();
+            record.set_filters(&ids).unwrap();
         }
-        self.writer.write(&record).unwrap();
     }
 
-    fn write_info_fields(&mut self, locus: &Locus, results: &LocusResult, record: &mut Record) {
+    fn write_info_fields(&mut self, locus: &Locus, results: &[LocusResult], record: &mut Record) {
         let contig = locus.region.contig.as_bytes();
         let rid = self.writer.header().name2rid(contig).unwrap();
         record.set_rid(Some(rid));
 
-        let no_empty_alleles = results.genotype.iter().all(|a| !a.seq.is_empty());
-        record.set_pos(if no_empty_alleles {
+        record.set_pos(if has_no_empty_alleles(results) {
             locus.region.start as i64
         } else {
             locus.region.start as i64 - 1
@@ -100,96 +247,213 @@ impl VcfWriter {
         record
             .push_info_string(b"STRUC", &[locus.struc.as_bytes()])
             .unwrap();
+        let (left, right) = locus.get_flank_bounds();
+        let flanks = [left.0 + 1, left.1, right.0 + 1, right.1].map(|pos| pos as i32);
+        record.push_info_integer(b"FLANKS", &flanks).unwrap();
     }
 
-    fn write_missing_genotype_fields(&mut self, locus: &Locus, record: &mut Record) {
-        record
-            .set_alleles(&[locus.tr.as_bytes()])
-            .expect("Failed to set alleles");
+    fn write_genotype_fields(
+        &mut self,
+        locus: &Locus,
+        results: &[LocusResult],
+        record: &mut Record,
+    ) {
+        let alleles = get_alleles(locus, results);
+        set_alleles(locus, &alleles, results, record);
+        set_gt(&alleles, results, record);
+        if matches!(locus.genotyper, Genotyper::Size) {
+            set_pl_and_gq(&alleles, results, record);
+        }
+
+        let data = results
+            .iter()
+            .map(|r| {
+                r.phasing
+                    .as_ref()
+                    .and_then(|p| p.phase_set)
+                    .unwrap_or_else(i32::missing)
+            })
+            .collect_vec();
+        record.push_format_integer(b"PS", &data).unwrap();
+
+        let data = encode_per_sample(results, encode_al);
+        record.push_format_string(b"AL", &data).unwrap();
+
+        let data = encode_per_sample(results, encode_allr);
+        record.push_format_string(b"ALLR", &data).unwrap();
+
+        let data = encode_per_sample(results, encode_hd);
+        record.push_format_string(b"SD", &data).unwrap();
+
+        let data = encode_per_sample(results, encode_mc);
+        record.push_format_string(b"MC", &data).unwrap();
+
+        let data = encode_per_sample(results, encode_ms);
+        record.push_format_string(b"MS", &data).unwrap();
+
+        let data = encode_allele_floats(results, |a| a.annotation.purity as f32);
+        record.push_format_float(b"AP", &data).unwrap();
+
+        let data = encode_per_sample(results, encode_ls);
+        record.push_format_string(b"LS", &data).unwrap();
+
+        let data = encode_per_sample(results, encode_am);
+        record.push_format_string(b"AM", &data).unwrap();
+
+        let data = encode_per_sample(results, |gt| encode_levels(gt.iter().map(|a| a.hmc)));
+        record.push_format_string(b"AH", &data).unwrap();
+
+        let data = encode_per_sample(results, |gt| encode_levels(gt.iter().map(|a| a.m6a)));
+        record.push_format_string(b"AA", &data).unwrap();
+
+        let data = encode_per_sample(results, |gt| {
+            gt.iter()
+                .map(|a| encode_cpg_profile(&a.cpg_profile))
+                .join(",")
+        });
+        record.push_format_string(b"MP", &data).unwrap();
+
+        let get_meth_diff = |get: fn(&MethDiff) -> f64| {
+            results
+                .iter()
+                .map(|r| {
+                    r.meth_diff
+                        .as_ref()
+                        .map_or_else(f32::missing, |d| get(d) as f32)
+                })
+                .collect_vec()
+        };
         record
-            .push_genotypes(&[GenotypeAllele::UnphasedMissing])
+            .push_format_float(b"MD", &get_meth_diff(|d| d.effect))
             .unwrap();
-
-        record.push_format_string(b"AL", &[".".as_bytes()]).unwrap();
         record
-            .push_format_string(b"ALLR", &[".".as_bytes()])
+            .push_format_float(b"MDP", &get_meth_diff(|d| d.p_value))
             .unwrap();
-        record.push_format_string(b"SD", &[".".as_bytes()]).unwrap();
-        record.push_format_string(b"MC", &[".".as_bytes()]).unwrap();
-        record.push_format_string(b"MS", &[".".as_bytes()]).unwrap();
-        record.push_format_string(b"AP", &[".".as_bytes()]).unwrap();
-        record.push_format_string(b"AM", &[".".as_bytes()]).unwrap();
-    }
 
-    fn write_genotype_fields(&mut self, locus: &Locus, results: &LocusResult, record: &mut Record) {
-        set_gt(locus, results, record);
+        let data = encode_per_sample(results, encode_lq);
+        record.push_format_string(b"LQ", &data).unwrap();
 
-        let data = encode_al(&results.genotype);
+        let data = encode_allele_floats(results, |a| {
+            a.len_dist
+                .as_ref()
+                .map_or_else(f32::missing, |d| d.instability as f32)
+        });
+        record.push_format_float(b"MI", &data).unwrap();
+
+        let get_counts = |count: fn(&LocusQc) -> usize| {
+            results.iter().map(|r| count(&r.qc) as i32).collect_vec()
+        };
         record
-            .push_format_string(b"AL", &[data.as_bytes()])
+            .push_format_integer(b"NR", &get_counts(|qc| qc.num_reads))
             .unwrap();
-
-        let data = encode_allr(&results.genotype);
         record
-            .push_format_string(b"ALLR", &[data.as_bytes()])
+            .push_format_integer(b"LF", &get_counts(|qc| qc.num_left_flanking))
             .unwrap();
-
-        let data = encode_hd(&results.genotype);
         record
-            .push_format_string(b"SD", &[data.as_bytes()])
+            .push_format_integer(b"RF", &get_counts(|qc| qc.num_right_flanking))
             .unwrap();
-
-        let data = encode_mc(&results.genotype);
         record
-            .push_format_string(b"MC", &[data.as_bytes()])
+            .push_format_integer(b"DF", &get_counts(|qc| qc.num_discordant))
             .unwrap();
-
-        let data = encode_ms(&results.genotype);
         record
-            .push_format_string(b"MS", &[data.as_bytes()])
+            .push_format_integer(b"SF", &get_counts(|qc| qc.num_short_flanks))
             .unwrap();
+        let data = results
+            .iter()
+            .map(|r| {
+                r.qc.min_allele_len
+                    .map_or_else(i32::missing, |len| len as i32)
+            })
+            .collect_vec();
+        record.push_format_integer(b"LB", &data).unwrap();
+    }
+}
 
-        let data = encode_ap(&results.genotype);
-        record.push_format_float(b"AP", &data).unwrap();
+fn get_filters(results: &[&LocusResult]) -> Vec<&'static str> {
+    if results.iter().all(|r| r.genotype.is_empty()) {
+        return vec!["NoSpanning"];
+    }
 
-        let data = encode_am(&results.genotype);
-        record
-            .push_format_string(b"AM", &[data.as_bytes()])
-            .unwrap();
+    let mut filters = Vec::new();
+    let is_low_depth = results.iter().any(|r| {
+        !r.genotype.is_empty()
+            && r.genotype.iter().map(|a| a.num_spanning).sum::<usize>() < MIN_SPANNING_READS
+    });
+    if is_low_depth {
+        filters.push("LowDepth");
+    }
+    let alleles = results.iter().flat_map(|r| r.genotype.iter());
+    if alleles.clone().any(has_wide_ci) {
+        filters.push("WideCI");
+    }
+    if alleles.clone().any(has_low_support) {
+        filters.push("LowSupport");
+    }
+    if results.iter().any(|r| r.qc.is_downsampled) {
+        filters.push("Downsampled");
     }
+    let exceeds =
+        |count: usize, total: usize, max_fraction: f64| count as f64 > max_fraction * total as f64;
+    if results
+        .iter()
+        .any(|r| exceeds(r.qc.num_discordant, r.qc.num_reads, MAX_DISCORDANT_FRACTION))
+    {
+        filters.push("Discordant");
+    }
+    if results.iter().any(|r| {
+        let total = r.qc.num_reads + r.qc.num_quality_filtered;
+        exceeds(
+            r.qc.num_quality_filtered,
+            total,
+            MAX_QUALITY_FILTERED_FRACTION,
+        )
+    }) {
+        filters.push("QualityFiltered");
+    }
+    filters
 }
 
-fn set_gt(locus: &Locus, results: &LocusResult, record: &mut Record) {
-    let mut seqs = vec![locus.tr.as_bytes()];
-    let mut indexes = Vec::new();
-    let gt = &results.genotype;
-    for allele in gt {
-        if allele.seq == locus.tr {
-            indexes.push(GenotypeAllele::Unphased(0));
-            continue;
-        }
+fn has_wide_ci(allele: &Allele) -> bool {
+    let width = allele.ci.1 - allele.ci.0;
+    width > MAX_CI_WIDTH && width as f64 > MAX_CI_FRACTION * allele.seq.len() as f64
+}
 
-        if seqs.len() == 1 {
-            indexes.push(GenotypeAllele::Unphased(1));
-            seqs.push(allele.seq.as_bytes());
-        } else if gt[0].seq == gt[1].seq {
-            indexes.push(GenotypeAllele::Unphased(1));
-        } else {
-            indexes.push(GenotypeAllele::Unphased(2));
-            seqs.push(allele.seq.as_bytes());
+fn has_low_support(allele: &Allele) -> bool {
+    let num_low_support = get_low_support_positions(&allele.support).len();
+    num_low_support as f64 > MAX_LOW_SUPPORT_FRACTION * allele.support.len() as f64
+}
+
+fn has_no_empty_alleles(results: &[LocusResult]) -> bool {
+    results
+        .iter()
+        .all(|r| r.genotype.iter().all(|a| !a.seq.is_empty()))
+}
+
+/// Collects the reference allele followed by all distinct allele sequences
+/// in the order in which they appear across samples
+fn get_alleles<'a>(locus: &'a Locus, results: &'a [LocusResult]) -> Vec<&'a str> {
+    let mut alleles = vec![locus.tr.as_str()];
+    for allele in results.iter().flat_map(|r| r.genotype.iter()) {
+        if !alleles.contains(&allele.seq.as_str()) {
+            alleles.push(&allele.seq);
         }
     }
+    alleles
+}
 
-    let no_empty_alleles = results.genotype.iter().all(|a| !a.seq.is_empty());
-    if no_empty_alleles {
-        record.set_alleles(&seqs).expect("Failed to set alleles");
+fn set_alleles(locus: &Locus, alleles: &[&str], results: &[LocusResult], record: &mut Record) {
+    if has_no_empty_alleles(results) {
+        let encoding = alleles.iter().map(|a| a.as_bytes()).collect_vec();
+        record
+            .set_alleles(&encoding)
+            .expect("Failed to set alleles");
     } else {
         let pad_base = *locus.left_flank.as_bytes().last().unwrap();
-        let padded_seqs = seqs
+        let padded_seqs = alleles
             .iter()
             .map(|s| {
                 let mut padded_seq = vec![pad_base];
-                padded_seq.extend(s.iter());
+                padded_seq.extend(s.as_bytes());
                 padded_seq
             })
             .collect_vec();
@@ -198,8 +462,117 @@ fn set_gt(locus: &Locus, results: &LocusResult, record: &mut Record) {
             .set_alleles(&encoding)
             .expect("Failed to set alleles");
     }
+}
+
+fn set_gt(alleles: &[&str], results: &[LocusResult], record: &mut Record) {
+    let width = get_max_ploidy(results);
+    let mut encoding = Vec::with_capacity(width * results.len());
+    for result in results {
+        let mut indexes = result
+            .genotype
+            .iter()
+            .map(|allele| {
+                let index = alleles.iter().position(|a| *a == allele.seq).unwrap() as i32;
+                match result.phasing {
+                    Some(_) => i32::from(GenotypeAllele::Phased(index)),
+                    None => i32::from(GenotypeAllele::Unphased(index)),
+                }
+            })
+            .collect_vec();
+        if indexes.is_empty() {
+            indexes.push(i32::from(GenotypeAllele::UnphasedMissing));
+        }
+        indexes.resize(width, VECTOR_END_INTEGER);
+        encoding.extend(indexes);
+    }
+
+    record.push_format_integer(b"GT", &encoding).unwrap();
+}
+
+/// Sets genotype likelihoods over all alleles of the record by looking up the
+/// likelihoods of the genotypes with the same allele lengths; genotypes with
+/// lengths that were not evaluated for a sample get missing values
+fn set_pl_and_gq(alleles: &[&str], results: &[LocusResult], record: &mut Record) {
+    let mut pls = Vec::new();
+    let mut gqs = Vec::new();
+    for result in results {
+        match &result.likelihoods {
+            Some(likelihoods) => {
+                pls.push(remap_pl(alleles, result.genotype.len(), likelihoods));
+                gqs.push(likelihoods.gq);
+            }
+            None => {
+                pls.push(Vec::new());
+                gqs.push(i32::missing());
+            }
+        }
+    }
+
+    let width = pls.iter().map(|pl| pl.len()).max().unwrap_or(0).max(1);
+    let mut encoding = Vec::with_capacity(width * pls.len());
+    for mut pl in pls {
+        if pl.is_empty() {
+            pl.push(i32::missing());
+        }
+        pl.resize(width, VECTOR_END_INTEGER);
+        encoding.extend(pl);
+    }
+    record.push_format_integer(b"PL", &encoding).unwrap();
+    record.push_format_integer(b"GQ", &gqs).unwrap();
+}
 
-    record.push_genotypes(&indexes).unwrap();
+fn remap_pl(alleles: &[&str], ploidy: usize, likelihoods: &Likelihoods) -> Vec<i32> {
+    if genotype::count_genotypes(alleles.len(), ploidy) > genotype::MAX_PL_GENOTYPES {
+        return Vec::new();
+    }
+    let len_genotypes = genotype::get_genotypes(likelihoods.allele_lens.len(), ploidy);
+    let len_indexes = alleles
+        .iter()
+        .map(|allele| {
+            likelihoods
+                .allele_lens
+                .iter()
+                .position(|len| *len == allele.len())
+        })
+        .collect_vec();
+    genotype::get_genotypes(alleles.len(), ploidy)
+        .into_iter()
+        .map(|gt| {
+            let len_gt = gt
+                .iter()
+                .map(|index| len_indexes[*index])
+                .collect::<Option<Vec<_>>>();
+            let Some(mut len_gt) = len_gt else {
+                return i32::missing();
+            };
+            len_gt.sort();
+            let position = len_genotypes.iter().position(|g| *g == len_gt).unwrap();
+            likelihoods.pl[position]
+        })
+        .collect()
+}
+
+/// Number of values per sample needed to hold the largest genotype
+fn get_max_ploidy(results: &[LocusResult]) -> usize {
+    results
+        .iter()
+        .map(|r| r.genotype.len())
+        .max()
+        .unwrap_or(0)
+        .max(1)
+}
+
+fn encode_per_sample(results: &[LocusResult], encode: fn(&Genotype) -> String) -> Vec<Vec<u8>> {
+    results
+        .iter()
+        .map(|r| {
+            if r.genotype.is_empty() {
+                b".".to_vec()
+            } else {
+                encode(&r.genotype).into_bytes()
+            }
+        })
+        .collect_vec()
 }
 
 fn encode_al(diplotype: &Genotype) -> String {
@@ -232,30 +605,44 @@ fn encode_mc(genotype: &Genotype) -> String {
 }
 
 fn encode_ms(diplotype: &Genotype) -> String {
-    let mut encoding = String::new();
-    for hap in diplotype {
-        let sizes = match &hap.annotation.labels {
-            None => vec![".".to_string()],
-            Some(value) => value
-                .iter()
-                .map(|s| format!("{}({}-{})", s.motif_index, s.start, s.end))
-                .collect_vec(),
-        };
+    diplotype
+        .iter()
+        .map(|hap| hap.annotation.encode_spans())
+        .join(",")
+}
 
-        if !encoding.is_empty() {
-            encoding += ",";
+/// Encodes a value per allele of each sample; samples without alleles have a
+/// missing value and vectors are padded to the maximum ploidy
+fn encode_allele_floats<F>(results: &[LocusResult], get_value: F) -> Vec<f32>
+where
+    F: Fn(&Allele) -> f32,
+{
+    let width = get_max_ploidy(results);
+    let mut encoding = Vec::with_capacity(width * results.len());
+    for result in results {
+        let mut values = result.genotype.iter().map(&get_value).collect_vec();
+        if values.is_empty() {
+            values.push(f32::missing());
         }
-        encoding += &sizes.join("_");
+        values.resize(width, *VECTOR_END_FLOAT);
+        encoding.extend(values);
     }
-
     encoding
 }
 
-fn encode_ap(genotype: &Genotype) -> Vec<f32> {
+/// Lists 0-based positions of low-support bases of each allele
+fn encode_ls(genotype: &Genotype) -> String {
     genotype
         .iter()
-        .map(|a| a.annotation.purity as f32)
-        .collect_vec()
+        .map(|allele| {
+            let positions = get_low_support_positions(&allele.support);
+            if positions.is_empty() {
+                ".".to_string()
+            } else {
+                positions.iter().join("_")
+            }
+        })
+        .join(",")
 }
 
 fn encode_allr(diplotype: &Genotype) -> String {
@@ -279,16 +666,152 @@ fn encode_hd(diplotype: &Genotype) -> String {
 }
 
 fn encode_am(diplotype: &Genotype) -> String {
-    let mut encoding = String::new();
-    for hap in diplotype {
-        if !encoding.is_empty() {
-            encoding += ",";
-        }
-        encoding += &match hap.meth {
+    encode_levels(diplotype.iter().map(|hap| hap.meth))
+}
+
+fn encode_levels(levels: impl Iterator<Item = Option<f64>>) -> String {
+    levels
+        .map(|level| match level {
             Some(value) => format!("{:.2}", value),
             None => ".".to_string(),
+        })
+        .join(",")
+}
+
+fn encode_lq(genotype: &Genotype) -> String {
+    genotype
+        .iter()
+        .map(|allele| match &allele.len_dist {
+            Some(dist) => dist.quantiles.iter().join("_"),
+            None => ".".to_string(),
+        })
+        .join(",")
+}
+
+/// Builds a tabix index or a CSI index if the VCF has contigs too long for tabix
+pub fn index_vcf(path: &str, use_csi: bool) -> Result<(), String> {
+    let idx_type = if use_csi {
+        bcf::index::Type::Csi(14)
+    } else {
+        bcf::index::Type::Tbx
+    };
+    bcf::index::build(path, None, 1, idx_type)
+        .map_err(|e| format!("Failed to index {}: {}", path, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::label::Annotation;
+    use crate::workflows::CpgSite;
+
+    #[test]
+    fn remap_likelihoods_to_record_alleles() {
+        // Reference allele of length 3 and a called allele of length 6
+        let likelihoods = Likelihoods {
+            allele_lens: vec![3, 6],
+            pl: vec![40, 0, 30],
+            gq: 30,
         };
+        assert_eq!(
+            remap_pl(&["CAG", "CAGCAG"], 2, &likelihoods),
+            vec![40, 0, 30]
+        );
+        // Another sample's allele of the same length shares its likelihoods,
+        // while genotypes with an allele of an unseen length are missing
+        let pl = remap_pl(&["CAG", "CTGCAG", "CAGCAG", "CA"], 2, &likelihoods);
+        assert_eq!(&pl[..6], &[40, 0, 30, 0, 30, 30]);
+        assert!(pl[6..].iter().all(|v| v.is_missing()));
     }
 
-    encoding
+    #[test]
+    fn describe_filters_with_thresholds() {
+        let lines = get_filter_lines();
+        assert!(lines[0].contains("Fewer than 5 spanning reads"));
+        assert!(lines[1].contains("wider than 10bp and 20% of the allele length"));
+        assert!(lines[3].contains("More than 10% of the bases"));
+        assert!(lines.iter().all(|l| l.starts_with("##FILTER=<ID=")));
+    }
+
+    #[test]
+    fn flag_discordant_and_quality_filtered_reads() {
+        let allele = Allele {
+            seq: "CAGCAG".to_string(),
+            support: vec![1.0; 6],
+            annotation: Annotation {
+                labels: None,
+                motif_counts: vec![2],
+                purity: 1.0,
+            },
+            ci: (6, 6),
+            num_spanning: 10,
+            meth: None,
+            hmc: None,
+            m6a: None,
+            len_dist: None,
+            cpg_profile: Vec::new(),
+        };
+        let mut result = LocusResult::empty();
+        result.genotype.push(allele);
+        result.qc.num_reads = 10;
+        assert!(get_filters(&[&result]).is_empty());
+
+        result.qc.num_discordant = 2;
+        result.qc.num_quality_filtered = 3;
+        assert_eq!(
+            get_filters(&[&result]),
+            vec!["Discordant", "QualityFiltered"]
+        );
+
+        // Thresholds are exclusive
+        result.qc.num_discordant = 1;
+        assert_eq!(get_filters(&[&result]), vec!["QualityFiltered"]);
+        result.qc.num_reads = 12;
+        assert!(get_filters(&[&result]).is_empty());
+    }
+
+    #[test]
+    fn write_and_read_cpg_profile() {
+        let path = std::env::temp_dir().join(format!("trgt-{}-mp.vcf", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut bam_header = bam::Header::new();
+        let mut contig = bam::header::HeaderRecord::new(b"SQ");
+        contig.push_tag(b"SN", "chr1");
+        contig.push_tag(b"LN", 100000);
+        bam_header.push_record(&contig);
+
+        let profile = [
+            CpgSite {
+                pos: -3,
+                level: 0.5,
+                depth: 2,
+            },
+            CpgSite {
+                pos: 0,
+                level: 1.0,
+                depth: 1,
+            },
+        ];
+        let mp = format!("{},.", encode_cpg_profile(&profile));
+        {
+            let mut vcf_writer = VcfWriter::new(path, &["sample"], &["XX"], &bam_header).unwrap();
+            let writer = &mut vcf_writer.writer;
+            let mut record = writer.empty_record();
+            record.set_rid(Some(0));
+            record.set_pos(1000);
+            record.set_alleles(&[b"CAG", b"CAGCAG"]).unwrap();
+            let gt = [GenotypeAllele::Unphased(0), GenotypeAllele::Unphased(1)];
+            record.push_genotypes(&gt).unwrap();
+            record.push_format_string(b"MP", &[mp.as_bytes()]).unwrap();
+            record.push_format_integer(b"NR", &[4]).unwrap();
+            writer.write(&record).unwrap();
+        }
+
+        let mut reader = bcf::Reader::from_path(path).unwrap();
+        let record = bcf::Read::records(&mut reader).next().unwrap().unwrap();
+        let values = record.format(b"MP").string().unwrap();
+        assert_eq!(values[0], mp.as_bytes());
+        assert_eq!(record.format(b"NR").integer().unwrap()[0], &[4]);
+        std::fs::remove_file(path).unwrap();
+    }
 }
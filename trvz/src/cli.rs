@@ -50,7 +50,7 @@ pub struct CliParams {
 
     #[clap(required = true)]
     #[clap(long = "spanning-reads")]
-    #[clap(help = "BAM file with spanning reads generated by TRGT")]
+    #[clap(help = "BAM or CRAM file with spanning reads generated by TRGT")]
     #[clap(value_name = "SPANNING_READS")]
     #[arg(value_parser = check_file_exists)]
     pub reads_path: PathBuf,
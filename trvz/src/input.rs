@@ -80,8 +80,16 @@ pub fn get_locus(
     return Err(format!("Unable to find locus {}", tr_id));
 }
 
-pub fn get_reads(bam_path: &PathBuf, locus: &Locus) -> Result<Vec<Read>, String> {
+pub fn get_reads(
+    bam_path: &PathBuf,
+    genome_path: &PathBuf,
+    locus: &Locus,
+) -> Result<Vec<Read>, String> {
     let mut reads = bam::IndexedReader::from_path(bam_path).unwrap();
+    // CRAM records are decoded against the reference genome
+    reads
+        .set_reference(genome_path)
+        .map_err(|e| format!("Failed to set reference for {}: {}", bam_path.display(), e))?;
     // This assumes that TRGT outputs flanks shorter than 1Kbps in length. We may want
     // to implement a more flexible mechanism for handling flank lengths here and elsewhere.
     let search_radius = 1000;
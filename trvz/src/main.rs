@@ -132,7 +132,7 @@ fn main() -> Result<()> {
     )
     .unwrap_or_else(|err| handle_error_and_exit(err.into()));
 
-    let reads = input::get_reads(&cli_params.reads_path, &locus)
+    let reads = input::get_reads(&cli_params.reads_path, &cli_params.genome_path, &locus)
         .unwrap_or_else(|err| handle_error_and_exit(err.into()));
     let pipe_plot = if cli_params.plot_type == "allele" {
         let genotype = input::get_genotype(&cli_params.bcf_path, &locus)